@@ -0,0 +1,79 @@
+//! `check` subcommand
+
+use crate::{Config, util::Database};
+use anyhow::{Context, Result};
+use argh::FromArgs;
+use futures_util::stream::StreamExt;
+use semantic_search::ApiClient;
+
+/// check config, API connectivity and database
+#[derive(FromArgs, PartialEq, Eq, Debug)]
+#[argh(subcommand, name = "check", help_triggers("-h", "--help"))]
+pub struct Check {}
+
+impl Check {
+    /// Run all checks, printing the outcome of each. Returns whether all checks passed.
+    #[allow(clippy::future_not_send, reason = "Main function")]
+    pub async fn execute(&self, config: Config) -> Result<bool> {
+        // The config has been parsed if we get here
+        let mut passed = report("Config", Ok(format!("using model {}", config.api.model)));
+
+        let api = config.api.client();
+        passed &= report(
+            "API key",
+            api.as_ref()
+                .map(|_| "well-formed".to_string())
+                .map_err(|e| anyhow::anyhow!("{e}")),
+        );
+        if let Ok(api) = api {
+            passed &= report("API request", check_api(&api).await);
+        } else {
+            println!("⏭️ API request: skipped");
+        }
+
+        passed &= report("Database", check_database(&config).await);
+
+        Ok(passed)
+    }
+}
+
+/// Make a tiny embedding request, returning the embedding dimension, which the client checked to be that of the model.
+async fn check_api(api: &ApiClient) -> Result<String> {
+    api.embed("Hello, world!").await?;
+    Ok(format!("got {}-dimensional embedding", api.dimension()))
+}
+
+/// Check that the database exists and is readable, returning the number of records and their root.
+#[allow(clippy::future_not_send, reason = "Main function")]
+async fn check_database(config: &Config) -> Result<String> {
+    let path = &config.database.path;
+    if !path.exists() {
+        anyhow::bail!("{} does not exist, consider indexing first", path.display());
+    }
+    let db = Database::open(path, true, config.database.wal)
+        .await
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    let count = db.iter().count().await;
+    let root = db.root().map_or_else(
+        || "the working directory".to_owned(),
+        |root| root.display().to_string(),
+    );
+    Ok(format!(
+        "{} is readable, with {count} record(s) relative to {root}",
+        path.display()
+    ))
+}
+
+/// Print the outcome of a check, returning whether it passed.
+fn report(name: &str, result: Result<String>) -> bool {
+    match result {
+        Ok(detail) => {
+            println!("✅ {name}: {detail}");
+            true
+        }
+        Err(error) => {
+            println!("❌ {name}: {error:#}");
+            false
+        }
+    }
+}
@@ -0,0 +1,91 @@
+//! `clean` subcommand
+
+use super::index::{index_roots, walk_options};
+use crate::{Config, util::Database};
+use anyhow::{Context, Result};
+use argh::FromArgs;
+use futures_util::TryStreamExt;
+use std::{collections::HashSet, path::Path};
+
+/// remove records of files that no longer exist from the index
+#[derive(FromArgs, PartialEq, Eq, Debug)]
+#[argh(subcommand, name = "clean", help_triggers("-h", "--help"))]
+pub struct Clean {
+    /// also remove records of files that exist but would not be indexed now: hidden files, unless included, the database and configuration file, and files filtered out by --max-size, --ext or --no-ext
+    #[argh(switch)]
+    pub orphans: bool,
+    /// with --orphans, keep records of hidden files and directories, overriding `index.include_hidden` in the config
+    #[argh(switch)]
+    pub include_hidden: bool,
+    /// with --orphans, remove records of files larger than this many bytes
+    #[argh(option)]
+    pub max_size: Option<u64>,
+    /// with --orphans, remove records of files without these extensions, comma-separated like `jpg,png`, can be repeated
+    #[argh(option)]
+    pub ext: Vec<String>,
+    /// with --orphans, remove records of files with these extensions, comma-separated like `mp4,mkv`, can be repeated
+    #[argh(option)]
+    pub no_ext: Vec<String>,
+    /// report what would be removed without changing anything
+    #[argh(switch)]
+    pub dry_run: bool,
+}
+
+/// Summary of the clean operation.
+#[derive(Debug, Default)]
+pub struct CleanSummary {
+    /// Number of records of missing files removed, or that would be in a dry run
+    pub missing: usize,
+    /// Number of records of files that would not be indexed now removed, or that would be in a dry run
+    pub orphans: usize,
+}
+
+impl Clean {
+    /// Remove records of files missing under the root of the index and `index.roots` in the config, and with `--orphans`, of files the current rules would not index.
+    ///
+    /// Stickers added from Telegram are always kept, and records with absolute paths are only removed if missing.
+    #[allow(clippy::future_not_send, reason = "Main function")]
+    pub async fn execute(&self, config: Config) -> Result<CleanSummary> {
+        let mut db = Database::open(&config.database.path, self.dry_run, config.database.wal)
+            .await
+            .with_context(|| "Failed to open database, consider indexing first.")?;
+        let root = match db.root() {
+            Some(root) => root.to_path_buf(),
+            None => std::env::current_dir()?.canonicalize()?,
+        };
+        let mut options = walk_options(&config, self.include_hidden);
+        options.max_size = self.max_size;
+        options.ext.clone_from(&self.ext);
+        options.no_ext.clone_from(&self.no_ext);
+
+        let missing: HashSet<_> = db
+            .stale(&index_roots(&root, &config))
+            .await
+            .into_iter()
+            .collect();
+        let paths: Vec<String> = db.iter().try_collect().await?;
+        let mut summary = CleanSummary::default();
+        db.begin().await?;
+        for path in paths {
+            if path.starts_with("tg-sticker://") {
+                continue;
+            }
+            let relative = !Path::new(&path).is_absolute();
+            if missing.contains(&path) {
+                println!("{path} (missing)");
+                summary.missing += 1;
+            } else if self.orphans && relative && options.ignores(&root, &path) {
+                println!("{path} (ignored)");
+                summary.orphans += 1;
+            } else {
+                continue;
+            }
+            if !self.dry_run {
+                db.delete(&path).await?;
+            }
+        }
+        db.commit().await?;
+
+        Ok(summary)
+    }
+}
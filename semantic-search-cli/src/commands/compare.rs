@@ -0,0 +1,41 @@
+//! `compare` subcommand
+
+use crate::Config;
+use anyhow::{Context, Result};
+use argh::FromArgs;
+use semantic_search::Embedding;
+
+/// compute the cosine similarity between two texts, without touching the index
+#[derive(FromArgs, PartialEq, Eq, Debug)]
+#[argh(subcommand, name = "compare", help_triggers("-h", "--help"))]
+pub struct Compare {
+    /// first text, or path to a text file with `--files`
+    #[argh(positional)]
+    pub a: String,
+    /// second text, or path to a text file with `--files`
+    #[argh(positional)]
+    pub b: String,
+    /// treat arguments as paths to text files and compare their contents
+    #[argh(switch, short = 'f')]
+    pub files: bool,
+}
+
+impl Compare {
+    /// Embed both texts, returning their cosine similarity.
+    pub async fn execute(&self, config: Config) -> Result<f32> {
+        let api = config.api.client()?;
+        let a: Embedding = api.embed(&self.text(&self.a)?).await?.into();
+        let b: Embedding = api.embed(&self.text(&self.b)?).await?.into();
+
+        Ok(a.cosine_similarity(&b))
+    }
+
+    /// The text to embed for an argument, reading the file if `--files` is set.
+    fn text(&self, arg: &str) -> Result<String> {
+        if self.files {
+            std::fs::read_to_string(arg).with_context(|| format!("Failed to read {arg}"))
+        } else {
+            Ok(arg.to_owned())
+        }
+    }
+}
@@ -0,0 +1,40 @@
+//! `embed` subcommand
+
+use crate::{Config, OutputFormat};
+use anyhow::Result;
+use argh::FromArgs;
+use semantic_search::Embedding;
+use serde_json::{Value, json};
+
+/// print the embedding of arbitrary text
+#[derive(FromArgs, PartialEq, Eq, Debug)]
+#[argh(subcommand, name = "embed", help_triggers("-h", "--help"))]
+pub struct Embed {
+    /// text to embed
+    #[argh(positional)]
+    pub text: String,
+    /// print base64-encoded bytes (little-endian f32) instead of a JSON array
+    #[argh(switch)]
+    pub base64: bool,
+}
+
+impl Embed {
+    /// Embed the text, returning the embedding formatted for printing. With `json` or `ndjson` output, it is wrapped in an object like `{"text": "cat", "embedding": [0.1, ...]}`.
+    pub async fn execute(&self, config: Config, format: Option<OutputFormat>) -> Result<String> {
+        let api = config.api.client()?;
+        let embedding: Embedding = api.embed(&self.text).await?.into();
+        let embedding = if self.base64 {
+            Value::String(embedding.to_base64())
+        } else {
+            json!(embedding.as_slice())
+        };
+
+        match (format, embedding) {
+            (Some(OutputFormat::Json | OutputFormat::Ndjson), embedding) => {
+                Ok(json!({ "text": self.text, "embedding": embedding }).to_string())
+            }
+            (_, Value::String(base64)) => Ok(base64),
+            (_, embedding) => Ok(embedding.to_string()),
+        }
+    }
+}
@@ -0,0 +1,205 @@
+//! `eval` subcommand
+
+use super::search::open;
+use crate::{Config, util::PathFilter};
+use anyhow::{Context, Result};
+use argh::FromArgs;
+use semantic_search::Embedding;
+use std::{collections::HashMap, fs, path::PathBuf};
+
+/// evaluate search relevance against a file of expected results
+#[derive(FromArgs, PartialEq, Eq, Debug)]
+#[argh(subcommand, name = "eval", help_triggers("-h", "--help"))]
+pub struct Eval {
+    /// TSV file with a `query<TAB>expected_path` pair per line, where blank lines and lines starting with `#` are skipped
+    #[argh(positional)]
+    pub file: PathBuf,
+    /// number of results per query to consider (the k in recall@k), default is `search.num_results` in the config
+    #[argh(option, short = 'k')]
+    pub k: Option<usize>,
+    /// also print the rank and similarity of the expected file for each pair
+    #[argh(switch)]
+    pub verbose: bool,
+}
+
+/// Result of evaluating a single pair.
+#[derive(Debug)]
+pub struct PairResult {
+    /// Query string
+    pub query: String,
+    /// Path of the file expected to be found
+    pub expected: String,
+    /// 1-based rank of the expected file, if within the top k
+    pub rank: Option<usize>,
+    /// Cosine similarity between the query and the expected file
+    pub similarity: f32,
+}
+
+/// Summary of an evaluation.
+#[derive(Debug, Default)]
+pub struct EvalSummary {
+    /// Number of results considered per query
+    pub k: usize,
+    /// Result of each pair, in the order of the file
+    pub pairs: Vec<PairResult>,
+}
+
+impl EvalSummary {
+    /// Fraction of pairs whose expected file is within the top k.
+    #[must_use]
+    pub fn recall(&self) -> f64 {
+        let hits = self.pairs.iter().filter(|pair| pair.rank.is_some()).count();
+        self.mean(hits as f64)
+    }
+
+    /// Mean reciprocal rank of the expected files, counting those beyond the top k as `0`.
+    #[must_use]
+    pub fn mrr(&self) -> f64 {
+        let sum = self
+            .pairs
+            .iter()
+            .filter_map(|pair| pair.rank)
+            .map(|rank| 1.0 / rank as f64)
+            .sum();
+        self.mean(sum)
+    }
+
+    /// Mean cosine similarity between queries and their expected files.
+    #[must_use]
+    pub fn mean_similarity(&self) -> f64 {
+        let sum = self
+            .pairs
+            .iter()
+            .map(|pair| f64::from(pair.similarity))
+            .sum();
+        self.mean(sum)
+    }
+
+    /// Divide a sum over pairs by the number of pairs, or `0` if there are none.
+    fn mean(&self, sum: f64) -> f64 {
+        if self.pairs.is_empty() {
+            0.0
+        } else {
+            sum / self.pairs.len() as f64
+        }
+    }
+}
+
+impl Eval {
+    /// Run the query of each pair against the index, finding the rank of the expected file. Each distinct query is embedded once.
+    #[allow(clippy::future_not_send, reason = "Main function")]
+    pub async fn execute(&self, config: Config) -> Result<EvalSummary> {
+        let content = fs::read_to_string(&self.file)
+            .with_context(|| format!("Failed to read {}", self.file.display()))?;
+        let pairs = parse_pairs(&content)?;
+        let (db, api) = open(&config).await?;
+        let k = self.k.unwrap_or(config.search.num_results);
+
+        // Check that every expected file is indexed before embedding any query
+        let expected: Vec<_> = pairs.iter().map(|(_, _, expected)| *expected).collect();
+        let records: HashMap<_, _> = db
+            .get_many(&expected)
+            .await?
+            .into_iter()
+            .map(|record| (record.file_path.clone(), record))
+            .collect();
+
+        let mut embeddings: HashMap<&str, Embedding> = HashMap::new();
+        let mut summary = EvalSummary {
+            k,
+            pairs: Vec::with_capacity(pairs.len()),
+        };
+        for (line, query, expected) in pairs {
+            let Some(record) = records.get(expected) else {
+                anyhow::bail!("{expected} on line {line} is not indexed");
+            };
+            if !embeddings.contains_key(query) {
+                embeddings.insert(query, api.embed(query).await?.into());
+            }
+            let embedding = &embeddings[query];
+            let results = db.search(k, embedding, &PathFilter::default()).await?;
+            let rank = results
+                .iter()
+                .position(|(path, _)| path == expected)
+                .map(|index| index + 1);
+            summary.pairs.push(PairResult {
+                query: query.to_owned(),
+                expected: expected.to_owned(),
+                rank,
+                similarity: embedding.cosine_similarity(&record.embedding),
+            });
+        }
+
+        Ok(summary)
+    }
+}
+
+/// Parse `query<TAB>expected_path` pairs, returning them with their 1-based line numbers.
+fn parse_pairs(content: &str) -> Result<Vec<(usize, &str, &str)>> {
+    let mut pairs = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((query, expected)) = line.split_once('\t') else {
+            anyhow::bail!("Line {} should be `query<TAB>expected_path`", index + 1);
+        };
+        let (query, expected) = (query.trim(), expected.trim());
+        if query.is_empty() || expected.is_empty() {
+            anyhow::bail!("Line {} has an empty query or path", index + 1);
+        }
+        pairs.push((index + 1, query, expected));
+    }
+
+    Ok(pairs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Summary of pairs whose expected files are at `ranks`, with the given `similarities`.
+    fn summary(ranks: &[Option<usize>], similarities: &[f32]) -> EvalSummary {
+        let pairs = ranks
+            .iter()
+            .zip(similarities)
+            .map(|(&rank, &similarity)| PairResult {
+                query: "query".to_owned(),
+                expected: "expected".to_owned(),
+                rank,
+                similarity,
+            })
+            .collect();
+        EvalSummary { k: 3, pairs }
+    }
+
+    #[test]
+    fn metrics() {
+        let summary = summary(&[Some(1), Some(2), None], &[0.9, 0.6, 0.3]);
+        assert!((summary.recall() - 2.0 / 3.0).abs() < 1e-9);
+        // (1 + 1/2 + 0) / 3
+        assert!((summary.mrr() - 0.5).abs() < 1e-9);
+        assert!((summary.mean_similarity() - 0.6).abs() < 1e-6);
+    }
+
+    #[test]
+    fn metrics_empty() {
+        let summary = EvalSummary::default();
+        assert!(summary.recall().abs() < f64::EPSILON);
+        assert!(summary.mrr().abs() < f64::EPSILON);
+        assert!(summary.mean_similarity().abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn parse() {
+        let content = "# query\texpected\n\ncute cat\tcat.jpg\n  \n sad dog \t dog.png\n";
+        assert_eq!(
+            parse_pairs(content).unwrap(),
+            [(3, "cute cat", "cat.jpg"), (5, "sad dog", "dog.png")]
+        );
+
+        let error = parse_pairs("cute cat\tcat.jpg\ncute cat cat.jpg\n").unwrap_err();
+        assert!(error.to_string().starts_with("Line 2 "), "{error}");
+        assert!(parse_pairs("cute cat\t \n").is_err());
+    }
+}
@@ -0,0 +1,150 @@
+//! `export` subcommand
+
+use crate::{
+    Config, OutputFormat,
+    util::{Database, Record},
+};
+use anyhow::{Context, Result};
+use argh::FromArgs;
+use futures_util::stream::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    str::FromStr,
+};
+
+/// export the index as JSONL, JSON or CSV
+#[derive(FromArgs, PartialEq, Eq, Debug)]
+#[argh(subcommand, name = "export", help_triggers("-h", "--help"))]
+pub struct Export {
+    /// output format, either `jsonl`, `json` or `csv`, overriding the global `--format`, defaulting to `jsonl`
+    #[argh(option, short = 'f')]
+    pub format: Option<ExportFormat>,
+    /// file to write to, defaults to stdout
+    #[argh(option, short = 'o')]
+    pub output: Option<String>,
+    /// include base64-encoded embeddings (little-endian f32), which are large
+    #[argh(switch, short = 'e')]
+    pub with_embeddings: bool,
+}
+
+/// Supported export formats.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ExportFormat {
+    /// One JSON object per line.
+    Jsonl,
+    /// A JSON array of objects.
+    Json,
+    /// Comma-separated values with a header row.
+    Csv,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "jsonl" => Ok(Self::Jsonl),
+            "json" => Ok(Self::Json),
+            "csv" => Ok(Self::Csv),
+            _ => Err(format!(
+                "Unknown format `{s}`, expected `jsonl`, `json` or `csv`"
+            )),
+        }
+    }
+}
+
+/// A record as it appears in the export.
+#[derive(Serialize, Deserialize)]
+pub struct ExportRecord {
+    /// Path to the file
+    pub file_path: String,
+    /// SHA-256 hash of the file
+    pub file_hash: String,
+    /// File id used in Telegram
+    pub file_id: Option<String>,
+    /// Label of the file
+    pub label: String,
+    /// Emojis of the sticker, separated by spaces
+    #[serde(default)]
+    pub emojis: Option<String>,
+    /// Base64-encoded embedding, if exported
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub embedding: Option<String>,
+}
+
+impl ExportRecord {
+    /// Convert a database record, optionally keeping its embedding.
+    fn new(record: Record, with_embedding: bool) -> Self {
+        let embedding = with_embedding.then(|| record.embedding.to_base64());
+        Self {
+            file_path: record.file_path,
+            file_hash: record.file_hash,
+            file_id: record.file_id,
+            label: record.label,
+            emojis: record.emojis,
+            embedding,
+        }
+    }
+}
+
+impl Export {
+    /// Export all records, returning the number of records exported. Without `--format`, the global `json` format exports a JSON array, and any other exports JSON lines.
+    #[allow(clippy::future_not_send, reason = "Main function")]
+    pub async fn execute(&self, config: Config, global: Option<OutputFormat>) -> Result<usize> {
+        let db = Database::open(&config.database.path, true, config.database.wal)
+            .await
+            .with_context(|| "Failed to open database, consider indexing first.")?;
+        let writer: Box<dyn Write> = match &self.output {
+            Some(path) => {
+                Box::new(File::create(path).with_context(|| format!("Failed to create {path}"))?)
+            }
+            None => Box::new(io::stdout().lock()),
+        };
+        let mut writer = BufWriter::new(writer);
+        let mut records = db.iter_records();
+        let mut count = 0;
+
+        let format = self.format.unwrap_or(match global {
+            Some(OutputFormat::Json) => ExportFormat::Json,
+            _ => ExportFormat::Jsonl,
+        });
+        match format {
+            ExportFormat::Jsonl => {
+                while let Some(record) = records.next().await {
+                    let record = ExportRecord::new(record?, self.with_embeddings);
+                    serde_json::to_writer(&mut writer, &record)?;
+                    writeln!(writer)?;
+                    count += 1;
+                }
+                writer.flush()?;
+            }
+            ExportFormat::Json => {
+                // Streamed, so that the whole index is never held in memory
+                write!(writer, "[")?;
+                while let Some(record) = records.next().await {
+                    if count > 0 {
+                        write!(writer, ",")?;
+                    }
+                    let record = ExportRecord::new(record?, self.with_embeddings);
+                    serde_json::to_writer(&mut writer, &record)?;
+                    count += 1;
+                }
+                writeln!(writer, "]")?;
+                writer.flush()?;
+            }
+            ExportFormat::Csv => {
+                let mut writer = csv::Writer::from_writer(writer);
+                while let Some(record) = records.next().await {
+                    let record = ExportRecord::new(record?, self.with_embeddings);
+                    writer.serialize(record)?;
+                    count += 1;
+                }
+                writer.flush()?;
+            }
+        }
+
+        Ok(count)
+    }
+}
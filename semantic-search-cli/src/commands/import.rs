@@ -0,0 +1,106 @@
+//! `import` subcommand
+
+use super::export::ExportRecord;
+use crate::{
+    Config,
+    config::ApiConfig,
+    util::{Database, Record},
+};
+use anyhow::{Context, Result};
+use argh::FromArgs;
+use log::{debug, info};
+use semantic_search::{ApiClient, Embedding};
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+};
+
+/// import records from a JSONL export, rebuilding the index
+#[derive(FromArgs, PartialEq, Eq, Debug)]
+#[argh(subcommand, name = "import", help_triggers("-h", "--help"))]
+pub struct Import {
+    /// JSONL file produced by `export`
+    #[argh(positional)]
+    pub file: String,
+}
+
+impl Import {
+    /// Import all records in a single transaction, returning the number of records imported. The API is only needed for records without an embedding.
+    #[allow(clippy::future_not_send, reason = "Main function")]
+    pub async fn execute(&self, config: Config) -> Result<usize> {
+        let file =
+            File::open(&self.file).with_context(|| format!("Failed to open {}", self.file))?;
+        let mut db = Database::open(&config.database.path, false, config.database.wal)
+            .await
+            .with_context(|| "Failed to open database")?;
+
+        db.begin().await?;
+        match self
+            .import(BufReader::new(file), &mut db, &config.api)
+            .await
+        {
+            Ok(count) => {
+                db.commit().await?;
+                Ok(count)
+            }
+            Err(e) => {
+                db.rollback().await?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Insert records read from `reader` into the database, creating an API client from `api_config` on the first record without an embedding.
+    async fn import<R: BufRead>(
+        &self,
+        reader: R,
+        db: &mut Database,
+        api_config: &ApiConfig,
+    ) -> Result<usize> {
+        let mut api: Option<ApiClient> = None;
+        let mut count = 0;
+        for (index, line) in reader.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let ExportRecord {
+                file_path,
+                file_hash,
+                file_id,
+                label,
+                emojis,
+                embedding,
+            } = serde_json::from_str(&line)
+                .with_context(|| format!("Malformed record at {}:{line_number}", self.file))?;
+
+            let embedding: Embedding = if let Some(embedding) = embedding {
+                Embedding::from_base64(&embedding)
+                    .with_context(|| format!("Invalid embedding at {}:{line_number}", self.file))?
+            } else {
+                if api.is_none() {
+                    api = Some(api_config.client()?);
+                }
+                let api = api.as_ref().expect("Client was just created");
+                info!("Embedding label of {file_path}");
+                api.embed(&label).await?.into()
+            };
+            debug!("[IMPORT] {file_path}: {file_hash}");
+
+            db.insert(Record {
+                file_path,
+                file_hash,
+                file_id,
+                label,
+                embedding,
+                mtime: None,
+                emojis,
+            })
+            .await?;
+            count += 1;
+        }
+
+        Ok(count)
+    }
+}
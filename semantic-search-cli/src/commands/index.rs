@@ -2,15 +2,30 @@
 
 use crate::{
     Config,
-    util::{Database, Record, hash_file, iter_files, prompt},
+    util::{Database, Record, WalkOptions, hash_files, iter_files, modified_time, prompt},
 };
 use anyhow::{Context, Result};
 use argh::FromArgs;
-use log::{debug, info, warn};
-use semantic_search::ApiClient;
+use futures_util::{StreamExt, TryStreamExt, stream};
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{debug, error, info, warn};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use semantic_search::{ApiClient, Embedding, SenseError};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    io::IsTerminal,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// Number of files written per transaction.
+const BATCH_SIZE: usize = 500;
+/// Time between progress log lines when no progress bar is drawn.
+const PROGRESS_LOG_INTERVAL: Duration = Duration::from_secs(5);
 
 /// generate index of the files
-#[derive(FromArgs, PartialEq, Eq, Debug)]
+#[derive(FromArgs, PartialEq, Debug)]
 #[argh(subcommand, name = "index", help_triggers("-h", "--help"))]
 pub struct Index {
     /// skip prompting for labels and use filename or existing label
@@ -19,10 +34,209 @@ pub struct Index {
     /// re-embedding files that hash has changed, useful when you edited the labels externally and conveyed the changes by changing the hash
     #[argh(switch, short = 'r')]
     pub re_embed: bool,
+    /// report pairs of likely duplicate files after indexing
+    #[argh(switch, short = 'd')]
+    pub detect_duplicates: bool,
+    /// minimum similarity for two files to be considered duplicates, default is 0.98
+    #[argh(option, default = "0.98")]
+    pub duplicate_threshold: f32,
+    /// report what would change without calling the API or writing to the database
+    #[argh(switch)]
+    pub dry_run: bool,
+    /// number of files to hash in parallel, default is the number of CPUs
+    #[argh(option, short = 'j', default = "default_jobs()")]
+    pub jobs: usize,
+    /// maximum number of embedding requests in flight at once, default is 4
+    #[argh(option, default = "4")]
+    pub concurrency: usize,
+    /// index at most this many new or modified files, then stop, e.g. to try out on a subset
+    #[argh(option)]
+    pub max_files: Option<usize>,
+    /// keep running after indexing, watching for created, modified and deleted files and indexing them, requires -y
+    #[argh(switch, short = 'w')]
+    pub watch: bool,
+    /// also index hidden files and directories, whose names start with `.`, overriding `index.include_hidden` in the config
+    #[argh(switch)]
+    pub include_hidden: bool,
+    /// skip files larger than this many bytes
+    #[argh(option)]
+    pub max_size: Option<u64>,
+    /// only index files with these extensions, comma-separated like `jpg,png`, can be repeated
+    #[argh(option)]
+    pub ext: Vec<String>,
+    /// skip files with these extensions, comma-separated like `mp4,mkv`, can be repeated
+    #[argh(option)]
+    pub no_ext: Vec<String>,
+    /// only estimate the changes since the last index from modification times, without hashing files or calling the API
+    #[argh(switch)]
+    pub estimate: bool,
+    /// print the summary as a JSON object to stdout, same as `--format json`
+    #[argh(switch)]
+    pub json: bool,
+    /// seconds without file system events before indexing the changes with --watch, default is 2, so that files still being written are indexed once complete
+    #[argh(option, default = "2")]
+    pub watch_interval: u64,
+}
+
+/// Log the changes indexed while watching, if any.
+fn log_changes(summary: &IndexSummary) {
+    if summary.changed + summary.new + summary.deleted + summary.renamed > 0 {
+        info!(
+            "Indexed {} new, {} changed, {} renamed and {} deleted file(s). 📝",
+            summary.new, summary.changed, summary.renamed, summary.deleted
+        );
+    }
+}
+
+/// Record the paths changed by a file system event. Accesses are ignored, such as those made by indexing itself.
+fn record_event(changed: &mut HashSet<PathBuf>, event: notify::Result<Event>) {
+    match event {
+        Ok(event) if matches!(event.kind, EventKind::Access(_)) => {}
+        Ok(event) => changed.extend(event.paths),
+        Err(e) => warn!("Error while watching for changes: {e}"),
+    }
+}
+
+/// Files to index among the `changed` paths under the first of `roots`, walking directories, and stale records at or under the paths that vanished, whose files exist under none of `roots`.
+#[allow(clippy::future_not_send, reason = "Main function")]
+async fn changed_files(
+    db: &Database,
+    roots: &[PathBuf],
+    options: &WalkOptions,
+    changed: &HashSet<PathBuf>,
+) -> Result<(Vec<(PathBuf, String)>, Vec<String>)> {
+    let root = &roots[0];
+    let mut files = Vec::new();
+    let mut vanished = Vec::new();
+    for path in changed {
+        let Ok(relative) = path.strip_prefix(root) else {
+            continue;
+        };
+        let relative = relative.to_string_lossy().to_string();
+        if relative.is_empty() || options.skips_relative(root, &relative) {
+            continue;
+        }
+        if path.is_dir() {
+            files.extend(iter_files(path, root, options));
+        } else if path.is_file() {
+            files.push((path.clone(), relative));
+        } else {
+            vanished.push(relative);
+        }
+    }
+    // A path may be reported by several events, or be under a changed directory
+    files.sort_unstable();
+    files.dedup();
+
+    let stale = if vanished.is_empty() {
+        Vec::new()
+    } else {
+        let paths: Vec<String> = db.iter().try_collect().await?;
+        paths
+            .into_iter()
+            .filter(|path| {
+                vanished
+                    .iter()
+                    .any(|gone| Path::new(path).starts_with(gone))
+                    && !roots.iter().any(|root| root.join(path).exists())
+            })
+            .collect()
+    };
+    Ok((files, stale))
+}
+
+/// Roots under which files of the index are looked up, `root` first and then `index.roots` in the config, so that records are only stale if their file exists under none of them.
+pub(super) fn index_roots(root: &Path, config: &Config) -> Vec<PathBuf> {
+    std::iter::once(root.to_path_buf())
+        .chain(config.index.roots.iter().cloned())
+        .collect()
+}
+
+/// Options for walking files, never yielding the database or the configuration file, which may hold secrets. Hidden files are included if `include_hidden` or `index.include_hidden` in the config is set.
+pub(super) fn walk_options(config: &Config, include_hidden: bool) -> WalkOptions {
+    let options = WalkOptions::new(include_hidden || config.index.include_hidden)
+        .exclude_database(&config.database.path);
+    match &config.path {
+        Some(path) => options.exclude(path),
+        None => options,
+    }
+}
+
+/// Progress over all walked files, drawn as a progress bar if stderr is a terminal, or logged every [`PROGRESS_LOG_INTERVAL`] otherwise. The bar is cleared when dropped.
+struct Progress {
+    /// Number of files walked
+    total: usize,
+    /// Number of files done, or being processed
+    done: usize,
+    /// Progress bar, if drawn
+    bar: Option<ProgressBar>,
+    /// When progress was last logged
+    logged: Instant,
+}
+
+impl Progress {
+    /// Track progress over `total` files.
+    fn new(total: usize) -> Self {
+        let bar = std::io::stderr().is_terminal().then(|| {
+            let style = ProgressStyle::with_template(
+                "{spinner} [{elapsed_precise}] {wide_bar} {pos}/{len} ({eta}) {msg}",
+            )
+            .unwrap_or_else(|_| ProgressStyle::default_bar());
+            ProgressBar::new(total as u64).with_style(style)
+        });
+        Self {
+            total,
+            done: 0,
+            bar,
+            logged: Instant::now(),
+        }
+    }
+
+    /// Move on to the file at `relative`.
+    fn inc(&mut self, relative: &str) {
+        self.done += 1;
+        if let Some(bar) = &self.bar {
+            bar.set_message(relative.to_string());
+            bar.inc(1);
+        } else if self.logged.elapsed() >= PROGRESS_LOG_INTERVAL {
+            self.logged = Instant::now();
+            info!(
+                "{} {}% done, at {relative}",
+                self.prefix(),
+                self.done * 100 / self.total.max(1)
+            );
+        }
+    }
+
+    /// Prefix of log lines about the current file, like `[12/345]`.
+    fn prefix(&self) -> String {
+        format!("[{}/{}]", self.done, self.total)
+    }
+
+    /// Run `f`, e.g. logging or prompting, with the progress bar hidden, so that they don't garble each other.
+    fn suspend<R>(&self, f: impl FnOnce() -> R) -> R {
+        match &self.bar {
+            Some(bar) => bar.suspend(f),
+            None => f(),
+        }
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        if let Some(bar) = &self.bar {
+            bar.finish_and_clear();
+        }
+    }
+}
+
+/// Default number of hashing jobs, which is the available parallelism.
+fn default_jobs() -> usize {
+    std::thread::available_parallelism().map_or(1, std::num::NonZeroUsize::get)
 }
 
 /// Summary of the index operation.
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Serialize)]
 pub struct IndexSummary {
     /// Number of changed files
     pub changed: usize,
@@ -30,100 +244,546 @@ pub struct IndexSummary {
     pub new: usize,
     /// Number of deleted files
     pub deleted: usize,
+    /// Number of files moved or renamed, detected by their hash and keeping their label and embedding
+    pub renamed: usize,
+    /// Number of files skipped by `--max-size`, `--ext` or `--no-ext`
+    pub skipped: usize,
+    /// Number of embedding API calls made, or that would be made in a dry run
+    pub embed_calls: usize,
+    /// Number of tokens used by embedding API calls, as reported by the API
+    pub tokens: u64,
+    /// Pairs of likely duplicate files and their similarity, if requested
+    pub duplicates: Vec<(String, String, f32)>,
+    /// Whether indexing stopped early because of `--max-files`
+    pub stopped_early: bool,
 }
 
 impl Index {
-    /// Index files.
+    /// Index files. In a dry run, the summary is computed but nothing is embedded or written.
     #[allow(clippy::future_not_send, reason = "Main function")]
     pub async fn execute(&self, config: Config) -> Result<IndexSummary> {
         // The option `yes` and `re_embed` should not be used together
         if self.yes && self.re_embed {
             anyhow::bail!("Options -y and -r should not be used together");
         }
-        let mut db = Database::open(".sense/index.db3", false)
+        if self.watch && !self.yes {
+            anyhow::bail!("Option -w requires -y, as watching runs non-interactively");
+        }
+        if self.watch && self.dry_run {
+            anyhow::bail!("Options -w and --dry-run should not be used together");
+        }
+        let path = &config.database.path;
+        let mut db = if !self.dry_run {
+            Database::open(path, false, config.database.wal).await
+        } else if path.exists() {
+            Database::open(path, true, config.database.wal).await
+        } else {
+            // Nothing indexed yet, so every file is new
+            Database::memory().await
+        }
+        .with_context(|| "Failed to open database")?;
+        let mut summary = IndexSummary::default();
+        // A dry run makes no API calls, so it needs no key
+        let api = if self.dry_run {
+            None
+        } else {
+            Some(config.api.client()?)
+        };
+        // Paths are relative to the recorded root, or the working directory on the first run
+        let root = match db.root() {
+            Some(root) => root.to_path_buf(),
+            None => {
+                let cwd = std::env::current_dir()?.canonicalize()?;
+                if !self.dry_run {
+                    info!("Recording {} as the root of the index", cwd.display());
+                    db.set_root(&cwd).await?;
+                }
+                cwd
+            }
+        };
+
+        let options = self.walk_options(&config);
+
+        let files = iter_files(&root, &root, &options).collect();
+        let roots = index_roots(&root, &config);
+        let stale = db.stale(&roots).await;
+        // Batches committed before a failure are kept, the current one is rolled back
+        db.begin().await?;
+        match self
+            .index_files(&mut db, api.as_ref(), files, &stale, &options, &mut summary)
             .await
-            .with_context(|| "Failed to open database")?;
+        {
+            Ok(()) => db.commit().await?,
+            Err(e) => {
+                db.rollback().await?;
+                return Err(e);
+            }
+        }
+
+        if self.detect_duplicates {
+            info!("Detecting duplicates...");
+            summary.duplicates = db.find_duplicates(self.duplicate_threshold).await?;
+        }
+        if self.watch {
+            log_changes(&summary);
+            self.watch(&mut db, api.as_ref(), &roots, &options).await?;
+        }
+
+        Ok(summary)
+    }
+
+    /// Options for walking files, filtering out those skipped by `--max-size`, `--ext` or `--no-ext`. Records of skipped files that are already indexed are kept.
+    fn walk_options(&self, config: &Config) -> WalkOptions {
+        let mut options = walk_options(config, self.include_hidden);
+        options.max_size = self.max_size;
+        options.ext.clone_from(&self.ext);
+        options.no_ext.clone_from(&self.no_ext);
+        options
+    }
+
+    /// Estimate changes since the last index from stored modification times, without hashing files, calling the API or writing to the database.
+    ///
+    /// Files whose modification time differs from the stored one, or isn't known, are counted as changed, although their content may be the same. Moved files are counted as new and deleted, as they are only detected by hashing.
+    #[allow(clippy::future_not_send, reason = "Main function")]
+    pub async fn estimate(&self, config: Config) -> Result<IndexSummary> {
+        if self.watch {
+            anyhow::bail!("Options -w and --estimate should not be used together");
+        }
+        let path = &config.database.path;
+        let db = if path.exists() {
+            Database::open(path, true, config.database.wal).await
+        } else {
+            // Nothing indexed yet, so every file is new
+            Database::memory().await
+        }
+        .with_context(|| "Failed to open database")?;
+        let root = match db.root() {
+            Some(root) => root.to_path_buf(),
+            None => std::env::current_dir()?.canonicalize()?,
+        };
+
+        let mtimes = db.mtimes().await?;
         let mut summary = IndexSummary::default();
-        let api = ApiClient::new(&config.api.key, config.api.model)?;
-        let cwd = std::env::current_dir()?.canonicalize()?;
-        summary.deleted = db.clean(&cwd).await?;
-        let files = iter_files(&cwd, &cwd);
+        let options = self.walk_options(&config);
+        for (path, relative) in iter_files(&root, &root, &options) {
+            if options.filters(&path) {
+                summary.skipped += 1;
+                continue;
+            }
+            match mtimes.get(&relative) {
+                None => summary.new += 1,
+                Some(Some(mtime)) if modified_time(&path) == Some(*mtime) => {}
+                Some(_) => summary.changed += 1,
+            }
+        }
+        summary.deleted = db.stale(&index_roots(&root, &config)).await.len();
+        // Same as a dry run, changed files are only embedded again with `-r`
+        summary.embed_calls = summary.new + if self.re_embed { summary.changed } else { 0 };
 
-        // For all files, calculate hash and write to database
+        Ok(summary)
+    }
+
+    /// Watch the first of `roots`, the root of the index, for changes and index them, keeping the database open. Runs until the process is interrupted or writing fails.
+    ///
+    /// Events are debounced, so changes are indexed once none has arrived for `--watch-interval` seconds, e.g. after a file has been written completely. Only the changed paths are indexed, walking directories created or moved in, and a failed batch, e.g. on a network error, is retried after the next interval. Editors saving by renaming a temporary file over the original are handled like any other modification.
+    #[allow(clippy::future_not_send, reason = "Main function")]
+    async fn watch(
+        &self,
+        db: &mut Database,
+        api: Option<&ApiClient>,
+        roots: &[PathBuf],
+        options: &WalkOptions,
+    ) -> Result<()> {
+        let root = &roots[0];
+        let interval = Duration::from_secs(self.watch_interval.max(1));
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event| {
+            // Only fails once the receiver is dropped, when watching stops
+            let _ = sender.send(event);
+        })
+        .with_context(|| "Failed to watch for changes")?;
+        watcher
+            .watch(root, RecursiveMode::Recursive)
+            .with_context(|| format!("Failed to watch {}", root.display()))?;
+        info!("Watching for changes, press Ctrl-C to stop...");
+
+        // Changed paths not indexed yet, kept on failure to retry them
+        let mut changed = HashSet::new();
+        loop {
+            if changed.is_empty() {
+                let event = receiver.recv().await;
+                record_event(&mut changed, event.context("Stopped watching for changes")?);
+            }
+            while let Ok(event) = tokio::time::timeout(interval, receiver.recv()).await {
+                record_event(&mut changed, event.context("Stopped watching for changes")?);
+            }
+
+            let (files, stale) = changed_files(db, roots, options, &changed).await?;
+            let mut summary = IndexSummary::default();
+            db.begin().await?;
+            match self
+                .index_files(db, api, files, &stale, options, &mut summary)
+                .await
+            {
+                Ok(()) => db.commit().await?,
+                Err(e) => {
+                    db.rollback().await?;
+                    error!("Failed to index changes, retrying: {e:#}");
+                    continue;
+                }
+            }
+            changed.clear();
+            log_changes(&summary);
+        }
+    }
+
+    /// Index `files`, as walked with `options`, within the current transaction, committing every [`BATCH_SIZE`] files. Records of `stale` paths, whose files are missing, are deleted at the end.
+    ///
+    /// Files are hashed in parallel first, then labeled and embedded one by one. A new file with the same hash as a missing one is taken as moved, keeping the label and embedding of the missing one, which is only deleted otherwise.
+    ///
+    /// Files are walked up front by the caller, so that progress is reported over all of them, see [`Progress`].
+    #[allow(clippy::future_not_send, reason = "Main function")]
+    async fn index_files(
+        &self,
+        db: &mut Database,
+        api: Option<&ApiClient>,
+        files: Vec<(PathBuf, String)>,
+        stale: &[String],
+        options: &WalkOptions,
+        summary: &mut IndexSummary,
+    ) -> Result<()> {
+        // Missing files by hash, until moved or deleted at the end
+        let stale: Vec<_> = stale.iter().map(String::as_str).collect();
+        let mut missing: HashMap<_, Vec<_>> = HashMap::new();
+        for record in db.get_many(&stale).await? {
+            missing
+                .entry(record.file_hash.clone())
+                .or_default()
+                .push(record);
+        }
+        let mut progress = Progress::new(files.len());
+
+        // Find files that need hashing
+        let mut candidates = Vec::new();
         for (path, relative) in files {
-            let hash = hash_file(&path)?;
-            let relative = relative.to_string();
+            if options.filters(&path) {
+                debug!("[SKIP] {relative}: filtered out by size or extension");
+                summary.skipped += 1;
+                progress.inc(&relative);
+                continue;
+            }
+            let mtime = modified_time(&path);
             let existing = db.get(&relative).await?;
 
-            // Get updated record
-            let record = if let Some(mut record) = existing {
+            // Assume unchanged if the modification time is known and matches, skipping hashing
+            if let Some(record) = &existing
+                && mtime.is_some()
+                && record.mtime == mtime
+            {
+                debug!("[SAME] {relative}: unmodified");
+                progress.inc(&relative);
+                continue;
+            }
+            if self.max_files.is_some_and(|max| candidates.len() >= max) {
+                progress.suspend(|| {
+                    info!(
+                        "Reached the limit of {} file(s), stopping early",
+                        candidates.len()
+                    );
+                });
+                summary.stopped_early = true;
+                break;
+            }
+            candidates.push((path, relative, mtime, existing));
+        }
+
+        // Hash in parallel, then process files in order so that prompts and summary are deterministic
+        let paths = candidates.iter().map(|(path, ..)| path.clone()).collect();
+        debug!(
+            "Hashing {} file(s) with {} job(s)",
+            candidates.len(),
+            self.jobs
+        );
+        let hashes = hash_files(paths, self.jobs).await?;
+
+        let mut pending = 0;
+        // Records waiting to be embedded, with the text to embed
+        let mut queue = Vec::new();
+        if !candidates.is_empty() {
+            progress.suspend(|| {
+                info!("Processing {} new or modified file(s)...", candidates.len());
+            });
+        }
+        for ((path, relative, mtime, existing), hash) in candidates.into_iter().zip(hashes) {
+            progress.inc(&relative);
+            let prefix = progress.prefix();
+            // Get updated record, and the text to embed if it needs embedding
+            let (record, text) = if let Some(mut record) = existing {
                 let hash_changed = record.file_hash != hash;
                 // Warn if the hash has changed
                 if hash_changed {
                     summary.changed += 1;
                     debug!("[CHANGED] {relative}: {} -> {hash}", record.file_hash);
-                    warn!("Hash of {relative} has changed, consider relabeling");
+                    progress.suspend(|| {
+                        warn!("{prefix} Hash of {relative} has changed, consider relabeling");
+                    });
                     record.file_hash = hash;
                     record.file_id = None; // Reset file_id
+                    record.mtime = mtime;
 
-                    if self.re_embed {
+                    if self.dry_run {
+                        // Only re-embedding is known in advance, relabeling depends on the prompt
+                        summary.embed_calls += usize::from(self.re_embed);
+                        continue;
+                    } else if self.re_embed {
                         // Re-embed existing label
-                        info!("Re-embedding {relative}");
-                        record.embedding = api.embed(&record.label).await?.into();
+                        progress.suspend(|| info!("{prefix} Re-embedding {relative}"));
+                        summary.embed_calls += 1;
+                        let text = record.label.clone();
+                        (record, Some(text))
                     } else if !self.yes {
                         // Prompt for label
-                        println!("Existing label: {}", record.label);
-                        let label = prompt(&format!("Label for {relative} (empty to keep): "))?;
+                        let label = progress.suspend(|| {
+                            eprintln!("Existing label: {}", record.label);
+                            prompt(&format!("Label for {relative} (empty to keep): "))
+                        })?;
                         if label.is_empty() {
-                            println!("Label kept as: {}", record.label);
+                            progress.suspend(|| eprintln!("Label kept as: {}", record.label));
+                            (record, None)
                         } else {
                             record.label = label;
-                            println!("Label updated to: {}", record.label);
-                            record.embedding = api.embed(&relative).await?.into();
+                            progress.suspend(|| eprintln!("Label updated to: {}", record.label));
+                            summary.embed_calls += 1;
+                            (record, Some(relative))
                         }
                     } else {
                         // Do nothing if `yes` is set - keep the existing label and embedding
-                        info!("Skipping {relative}");
+                        progress.suspend(|| info!("{prefix} Skipping {relative}"));
+                        (record, None)
                     }
-                } else {
+                } else if record.mtime == mtime {
                     // Nothing changed
                     debug!("[SAME] {relative}: {hash}");
                     continue; // Skip to next file - this should improve performance
+                } else if self.dry_run {
+                    continue;
+                } else {
+                    // Content unchanged, only record the modification time
+                    debug!("[TOUCHED] {relative}: {hash}");
+                    record.mtime = mtime;
+                    (record, None)
                 }
-                // Reuse the record
-                record
+            } else if let Some(mut record) = missing.get_mut(&hash).and_then(Vec::pop) {
+                summary.renamed += 1;
+                debug!("[RENAMED] {}: {} -> {relative}", hash, record.file_path);
+                progress.suspend(|| {
+                    info!(
+                        "{prefix} {} was moved to {relative}, keeping its label",
+                        record.file_path
+                    );
+                });
+                if self.dry_run {
+                    continue;
+                }
+                db.rename(&record.file_path, &relative).await?;
+                record.file_path = relative;
+                record.mtime = mtime;
+                (record, None)
             } else {
                 summary.new += 1;
                 debug!("[NEW] {hash}: {relative}");
-                warn!("New file: {relative}, consider labeling");
+                progress.suspend(|| warn!("{prefix} New file: {relative}, consider labeling"));
+                // New files are always embedded once
+                summary.embed_calls += 1;
+                if self.dry_run {
+                    continue;
+                }
 
-                let (label, embedding) = if self.yes {
+                let label = if self.yes {
+                    String::new()
+                } else {
+                    progress.suspend(|| {
+                        prompt(&format!("Label for {relative} (empty to use filename): "))
+                    })?
+                };
+                let label = if label.is_empty() {
                     // Use filename as label
-                    let label = path.file_stem().unwrap().to_string_lossy();
-                    (label.to_string(), api.embed(&relative).await?.into())
+                    path.file_stem().unwrap().to_string_lossy().to_string()
                 } else {
-                    let label = prompt(&format!("Label for {relative} (empty to use filename): "))?;
-                    if label.is_empty() {
-                        // Use filename as label
-                        let label = path.file_stem().unwrap().to_string_lossy();
-                        (label.to_string(), api.embed(&relative).await?.into())
-                    } else {
-                        let embedding = api.embed(&relative).await?;
-                        (label, embedding.into())
-                    }
+                    label
                 };
-                Record {
-                    file_path: relative,
+                let record = Record {
+                    file_path: relative.clone(),
                     file_hash: hash,
                     file_id: None,
                     label,
-                    embedding,
-                }
+                    // Filled in once embedded
+                    embedding: Embedding::default(),
+                    mtime,
+                    emojis: None,
+                };
+                (record, Some(relative))
             };
 
+            if let Some(text) = text {
+                queue.push((record, text));
+                if queue.len() == BATCH_SIZE {
+                    pending += self.embed_queued(db, api, &mut queue, summary).await?;
+                }
+            } else {
+                db.insert(record).await?;
+                pending += 1;
+            }
+            if pending >= BATCH_SIZE {
+                debug!("Committing {pending} file(s)");
+                db.commit().await?;
+                db.begin().await?;
+                pending = 0;
+            }
+        }
+        self.embed_queued(db, api, &mut queue, summary).await?;
+
+        // Files not reached may be where missing ones were moved to, so keep those until a full run
+        if summary.stopped_early {
+            debug!("Stopped early, keeping records of missing files");
+            return Ok(());
+        }
+        let missing: Vec<_> = missing.into_values().flatten().collect();
+        summary.deleted = missing.len();
+        if !self.dry_run {
+            for record in missing {
+                db.delete(&record.file_path).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Embed queued records with at most `concurrency` requests in flight, inserting each one as soon as it is embedded. Returns the number of inserted records.
+    async fn embed_queued(
+        &self,
+        db: &mut Database,
+        api: Option<&ApiClient>,
+        queue: &mut Vec<(Record, String)>,
+        summary: &mut IndexSummary,
+    ) -> Result<usize> {
+        if queue.is_empty() {
+            return Ok(0);
+        }
+        // Nothing is queued in a dry run, the only case without a client
+        let api = api.context("No API client to embed with")?;
+        let mut embedded = stream::iter(queue.drain(..))
+            .map(|(mut record, text)| async move {
+                let (embedding, meta) = api.embed_with_meta(&text).await?;
+                record.embedding = embedding;
+                Ok::<_, SenseError>((record, meta))
+            })
+            .buffer_unordered(self.concurrency.max(1));
+
+        let mut count = 0;
+        while let Some(result) = embedded.next().await {
+            let (record, meta) = result?;
+            debug!(
+                "Embedded {} with {} token(s) by {}, request id: {}",
+                record.file_path,
+                meta.total_tokens,
+                meta.model,
+                meta.id.as_deref().unwrap_or("unknown")
+            );
+            summary.tokens += u64::from(meta.total_tokens);
             db.insert(record).await?;
+            count += 1;
         }
 
-        Ok(summary)
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::hash_file;
+
+    /// Record of a file that was indexed with `label`, before it went missing or changed.
+    fn indexed(path: &str, hash: &str, label: &str) -> Record {
+        Record {
+            file_path: path.to_owned(),
+            file_hash: hash.to_owned(),
+            file_id: None,
+            label: label.to_owned(),
+            embedding: Embedding::from([0.5; 1024]),
+            mtime: None,
+            emojis: None,
+        }
+    }
+
+    /// Index the files under `dir` with `args`, without an API client, taking the `stale` paths as missing.
+    async fn index(db: &mut Database, dir: &Path, args: &[&str], stale: &[&str]) -> IndexSummary {
+        let index = Index::from_args(&["index"], args).unwrap();
+        let options = WalkOptions::new(false);
+        let mut files: Vec<_> = iter_files(dir, dir, &options).collect();
+        files.sort();
+        let stale: Vec<_> = stale.iter().map(ToString::to_string).collect();
+        let mut summary = IndexSummary::default();
+        db.begin().await.unwrap();
+        index
+            .index_files(db, None, files, &stale, &options, &mut summary)
+            .await
+            .unwrap();
+        db.commit().await.unwrap();
+        summary
+    }
+
+    /// Temporary directory with `files` and their contents.
+    fn temp_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("sense-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (file, content) in files {
+            std::fs::write(dir.join(file), content).unwrap();
+        }
+        dir.canonicalize().unwrap()
+    }
+
+    #[tokio::test]
+    async fn rename_keeps_label() {
+        let dir = temp_dir("rename", &[("new.txt", "cat")]);
+        let hash = hash_file(dir.join("new.txt")).unwrap();
+        let mut db = Database::memory().await.unwrap();
+        db.insert(indexed("old.txt", &hash, "a cat")).await.unwrap();
+
+        let summary = index(&mut db, &dir, &["-y"], &["old.txt"]).await;
+        assert_eq!(summary.renamed, 1);
+        assert_eq!(
+            (summary.new, summary.deleted, summary.embed_calls),
+            (0, 0, 0)
+        );
+        assert!(db.get("old.txt").await.unwrap().is_none());
+        let record = db.get("new.txt").await.unwrap().unwrap();
+        assert_eq!(record.label, "a cat");
+        assert_eq!(record.embedding, Embedding::from([0.5; 1024]));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn stopped_early_keeps_missing() {
+        // `b.txt` was moved from `old.txt`, but isn't reached after the changed `a.txt`
+        let dir = temp_dir("stopped-early", &[("a.txt", "a"), ("b.txt", "cat")]);
+        let hash = hash_file(dir.join("b.txt")).unwrap();
+        let mut db = Database::memory().await.unwrap();
+        db.insert(indexed("a.txt", "outdated", "a")).await.unwrap();
+        db.insert(indexed("old.txt", &hash, "a cat")).await.unwrap();
+
+        let summary = index(&mut db, &dir, &["-y", "--max-files", "1"], &["old.txt"]).await;
+        assert!(summary.stopped_early);
+        assert_eq!((summary.changed, summary.deleted), (1, 0));
+        assert!(db.get("old.txt").await.unwrap().is_some());
+
+        // A full run then finds the move
+        let summary = index(&mut db, &dir, &["-y"], &["old.txt"]).await;
+        assert_eq!((summary.renamed, summary.deleted), (1, 0));
+        assert_eq!(db.get("b.txt").await.unwrap().unwrap().label, "a cat");
+
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
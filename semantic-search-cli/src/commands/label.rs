@@ -0,0 +1,44 @@
+//! `label` subcommand
+
+use crate::{Config, util::Database};
+use anyhow::{Context, Result};
+use argh::FromArgs;
+use log::info;
+
+/// change the label of an indexed file without re-indexing
+#[derive(FromArgs, PartialEq, Eq, Debug)]
+#[argh(subcommand, name = "label", help_triggers("-h", "--help"))]
+pub struct Label {
+    /// path of the file, as stored in the index
+    #[argh(positional)]
+    pub path: String,
+    /// new label
+    #[argh(positional)]
+    pub label: String,
+    /// only change the label text, keeping the existing embedding
+    #[argh(switch, short = 'n')]
+    pub no_embed: bool,
+}
+
+impl Label {
+    /// Update the label, re-embedding it unless `--no-embed` is set.
+    #[allow(clippy::future_not_send, reason = "Main function")]
+    pub async fn execute(&self, config: Config) -> Result<()> {
+        let mut db = Database::open_existing(&config.database.path, config.database.wal)
+            .await
+            .with_context(|| "Failed to open database, consider indexing first.")?;
+        let Some(mut record) = db.get(&self.path).await? else {
+            anyhow::bail!("{} is not indexed", self.path);
+        };
+
+        info!("Label of {}: {} -> {}", self.path, record.label, self.label);
+        record.label.clone_from(&self.label);
+        if !self.no_embed {
+            let api = config.api.client()?;
+            record.embedding = api.embed(&record.label).await?.into();
+        }
+        db.insert(record).await?;
+
+        Ok(())
+    }
+}
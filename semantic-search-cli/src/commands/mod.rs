@@ -1,21 +1,65 @@
 //! Subcommands for the Semantic Search CLI.
 
+mod check;
+mod clean;
+mod compare;
+mod embed;
+mod eval;
+mod export;
+mod import;
 mod index;
+mod label;
+mod prune;
+mod rename;
 mod search;
 mod serve;
+mod similar;
 mod telegram;
 
 use argh::FromArgs;
 pub use index::Index;
+pub use search::{ScoreFormat, SearchResult};
+
+/// Short aliases of commands, and the commands they stand for.
+pub const ALIASES: [(&str, &str); 6] = [
+    ("s", "search"),
+    ("q", "search"),
+    ("i", "index"),
+    ("idx", "index"),
+    ("sim", "similar"),
+    ("mv", "rename"),
+];
 
 /// Possible commands.
-#[derive(FromArgs, PartialEq, Eq, Debug)]
+#[derive(FromArgs, PartialEq, Debug)]
 #[argh(subcommand)]
 pub enum Command {
     /// An index command.
     Index(index::Index),
     /// A search command.
     Search(search::Search),
+    /// A check command.
+    Check(check::Check),
+    /// A similar command.
+    Similar(similar::Similar),
+    /// An embed command.
+    Embed(embed::Embed),
+    /// A compare command.
+    Compare(compare::Compare),
+    /// An eval command.
+    Eval(eval::Eval),
+    /// A label command.
+    Label(label::Label),
+    /// An export command.
+    Export(export::Export),
+    /// An import command.
+    Import(import::Import),
+    /// A prune command.
+    Prune(prune::Prune),
+    /// A clean command.
+    Clean(clean::Clean),
+    /// A rename command.
+    Rename(rename::Rename),
     /// A Telegram bot command.
     Telegram(telegram::Telegram),
     /// A serve command.
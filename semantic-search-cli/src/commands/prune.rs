@@ -0,0 +1,76 @@
+//! `prune` subcommand
+
+use crate::{
+    Config,
+    util::{Database, group_duplicates},
+};
+use anyhow::{Context, Result};
+use argh::FromArgs;
+use log::{info, warn};
+use std::fs;
+
+/// remove near-duplicate files from the index, keeping one per group
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "prune", help_triggers("-h", "--help"))]
+pub struct Prune {
+    /// minimum similarity for two files to be considered duplicates, default is 0.98
+    #[argh(option, short = 's', default = "0.98")]
+    pub similarity: f32,
+    /// also delete the removed files from disk
+    #[argh(switch)]
+    pub delete_files: bool,
+    /// report what would be removed without changing anything
+    #[argh(switch)]
+    pub dry_run: bool,
+}
+
+/// Summary of the prune operation.
+#[derive(Debug, Default)]
+pub struct PruneSummary {
+    /// Number of groups of near-duplicate files
+    pub groups: usize,
+    /// Number of files removed from the index, or that would be in a dry run
+    pub removed: usize,
+}
+
+impl Prune {
+    /// Find groups of near-duplicate files, keeping the first path of each group and removing the rest.
+    ///
+    /// Grouping is transitive, see [`group_duplicates`].
+    #[allow(clippy::future_not_send, reason = "Main function")]
+    pub async fn execute(&self, config: Config) -> Result<PruneSummary> {
+        let mut db = Database::open(&config.database.path, self.dry_run, config.database.wal)
+            .await
+            .with_context(|| "Failed to open database, consider indexing first.")?;
+        info!("Detecting duplicates...");
+        let pairs = db.find_duplicates(self.similarity).await?;
+        let groups = group_duplicates(&pairs);
+        let mut summary = PruneSummary {
+            groups: groups.len(),
+            removed: 0,
+        };
+
+        db.begin().await?;
+        for group in groups {
+            let (keep, duplicates) = group.split_first().expect("Groups have at least two files");
+            for path in duplicates {
+                println!("{path} (duplicate of {keep})");
+                summary.removed += 1;
+                if self.dry_run {
+                    continue;
+                }
+                db.delete(path).await?;
+                // Stickers added via the Telegram bot have no file
+                if self.delete_files
+                    && !path.starts_with("tg-sticker://")
+                    && let Err(e) = fs::remove_file(db.resolve(path))
+                {
+                    warn!("Failed to delete {path}: {e}");
+                }
+            }
+        }
+        db.commit().await?;
+
+        Ok(summary)
+    }
+}
@@ -0,0 +1,43 @@
+//! `rename` subcommand
+
+use crate::{Config, util::Database};
+use anyhow::{Context, Result};
+use argh::FromArgs;
+use log::{info, warn};
+
+/// move an indexed file to another path without re-embedding it
+#[derive(FromArgs, PartialEq, Eq, Debug)]
+#[argh(subcommand, name = "rename", help_triggers("-h", "--help"))]
+pub struct Rename {
+    /// current path of the file, as stored in the index
+    #[argh(positional)]
+    pub old: String,
+    /// new path of the file, relative to the root of the index
+    #[argh(positional)]
+    pub new: String,
+}
+
+impl Rename {
+    /// Move the record, keeping its hash, label and embedding.
+    #[allow(clippy::future_not_send, reason = "Main function")]
+    pub async fn execute(&self, config: Config) -> Result<()> {
+        let mut db = Database::open_existing(&config.database.path, config.database.wal)
+            .await
+            .with_context(|| "Failed to open database, consider indexing first.")?;
+        if db.get(&self.new).await?.is_some() {
+            anyhow::bail!("{} is already indexed", self.new);
+        }
+        if !db.rename(&self.old, &self.new).await? {
+            anyhow::bail!("{} is not indexed", self.old);
+        }
+        info!("Renamed {} -> {}", self.old, self.new);
+        if !db.resolve(&self.new).exists() {
+            warn!(
+                "{} does not exist, so the next index will delete it",
+                self.new
+            );
+        }
+
+        Ok(())
+    }
+}
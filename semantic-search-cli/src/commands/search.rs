@@ -1,31 +1,496 @@
 //! `search` subcommand
 
-use crate::{Config, util::Database};
+use crate::{
+    Config, OutputFormat,
+    util::{Database, Metadata, PathFilter, descending, prompt},
+};
 use anyhow::{Context, Result};
 use argh::FromArgs;
+use glob::Pattern;
 use semantic_search::{ApiClient, Embedding};
+use serde::Serialize;
+use std::{
+    fs::File,
+    io::{self, BufRead, BufWriter, IsTerminal, Read, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
 /// search for files based on labels
 #[derive(FromArgs, PartialEq, Eq, Debug)]
 #[argh(subcommand, name = "search", help_triggers("-h", "--help"))]
 pub struct Search {
-    /// query string
+    /// query terms, each embedded separately and combined as given by `--combine`. If omitted or `-`, the query is read from stdin, or prompted for in a terminal, unless `--batch` is given
     #[argh(positional)]
-    pub query: String,
-    /// number of results to show
-    #[argh(option, short = 'n', default = "8")]
-    pub num_results: usize,
+    pub query: Vec<String>,
+    /// number of results to show, default is `search.num_results` in the config
+    #[argh(option, short = 'n')]
+    pub num_results: Option<usize>,
+    /// only search files under this directory
+    #[argh(option, short = 'u')]
+    pub under: Option<PathBuf>,
+    /// only search files whose path matches this glob pattern, e.g. `*.jpg`
+    #[argh(option, short = 'g')]
+    pub glob: Option<Pattern>,
+    /// exclude files whose path matches this glob pattern, can be repeated
+    #[argh(option, short = 'x')]
+    pub exclude: Vec<Pattern>,
+    /// how to show similarities: `percent` (default, cosine similarity as a percentage, from -100% to 100%), `cosine` (raw, from -1 to 1) or `normalized` (mapped to 0% to 100%)
+    #[argh(option, default = "ScoreFormat::Percent")]
+    pub score_format: ScoreFormat,
+    /// how to combine multiple query terms: `mean` (default, search once with the mean of their embeddings) or `max` (search each term, ranking files by their highest similarity)
+    #[argh(option, short = 'c', default = "Combine::Mean")]
+    pub combine: Combine,
+    /// read one query per line from stdin instead, printing results as JSON lines
+    #[argh(switch, short = 'b')]
+    pub batch: bool,
+    /// show the first this many characters of each file, or its label if the file is missing or binary
+    #[argh(option, short = 'p')]
+    pub preview: Option<usize>,
+    /// print results as a JSON array, same as `--format json`
+    #[argh(switch)]
+    pub json: bool,
+    /// output format, either `text`, `json` or `ndjson`, overriding the global `--format`, defaulting to the extension of `--output` (`.json`, `.jsonl` or `.ndjson`) and `text` otherwise
+    #[argh(option, short = 'f')]
+    pub format: Option<OutputFormat>,
+    /// file to write results to, defaults to stdout
+    #[argh(option, short = 'o')]
+    pub output: Option<PathBuf>,
+}
+
+/// A search result, as printed by the command.
+#[derive(Debug, Serialize)]
+pub struct SearchResult {
+    /// Path of the file
+    pub file: String,
+    /// Cosine similarity to the query
+    pub similarity: f32,
+    /// Beginning of the file or its label, if `--preview` is given
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preview: Option<String>,
+}
+
+/// Display formats of similarity scores.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum ScoreFormat {
+    /// Cosine similarity as is, in `[-1, 1]`.
+    Cosine,
+    /// Cosine similarity as a percentage, in `[-100%, 100%]`.
+    #[default]
+    Percent,
+    /// Cosine similarity mapped linearly to `[0%, 100%]`, so that `-1` is `0%` and `1` is `100%`.
+    Normalized,
+}
+
+impl ScoreFormat {
+    /// Format a cosine similarity.
+    #[must_use]
+    pub fn format(self, similarity: f32) -> String {
+        match self {
+            Self::Cosine => format!("{similarity:.4}"),
+            Self::Percent => format!("{:.2}%", similarity * 100.0),
+            Self::Normalized => format!("{:.2}%", (similarity + 1.0) / 2.0 * 100.0),
+        }
+    }
+}
+
+impl FromStr for ScoreFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "cosine" => Ok(Self::Cosine),
+            "percent" => Ok(Self::Percent),
+            "normalized" => Ok(Self::Normalized),
+            _ => Err(format!(
+                "Unknown score format `{s}`, expected `cosine`, `percent` or `normalized`"
+            )),
+        }
+    }
+}
+
+/// Strategies to combine multiple query terms.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum Combine {
+    /// Search once with the mean of the embeddings of all terms.
+    #[default]
+    Mean,
+    /// Search each term separately, ranking files by their highest similarity to any term.
+    Max,
+}
+
+impl FromStr for Combine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "mean" => Ok(Self::Mean),
+            "max" => Ok(Self::Max),
+            _ => Err(format!(
+                "Unknown combine strategy `{s}`, expected `mean` or `max`"
+            )),
+        }
+    }
 }
 
 impl Search {
-    pub async fn execute(&self, config: Config) -> Result<Vec<(String, f32)>> {
-        let mut db = Database::open(".sense/index.db3", true)
-            .await
-            .with_context(|| "Failed to open database, consider indexing first.")?;
-        let api = ApiClient::new(&config.api.key, config.api.model)?;
-        let embedding: Embedding = api.embed(&self.query).await?.into();
-        let results = db.search(self.num_results, &embedding).await?;
+    pub async fn execute(&self, config: Config) -> Result<Vec<SearchResult>> {
+        let (db, embeddings, filter, num_results) = self.prepare(&config).await?;
+        let mut searches = Vec::with_capacity(embeddings.len());
+        for embedding in &embeddings {
+            searches.push(self.results(&db, embedding, &filter, num_results).await?);
+        }
+
+        Ok(merge(searches, num_results, |result| {
+            (&result.file, result.similarity)
+        }))
+    }
+
+    /// Like [`execute`](Self::execute), but on an open database and API client, returning the stored metadata of each result, e.g. its label and hash.
+    pub async fn execute_with_metadata(
+        &self,
+        db: &Database,
+        api: &ApiClient,
+        config: &Config,
+    ) -> Result<Vec<(Metadata, f32)>> {
+        let embeddings = self.embed(api, &self.query).await?;
+        let filter = self.filter();
+        let num_results = self.num_results.unwrap_or(config.search.num_results);
+        let mut searches = Vec::with_capacity(embeddings.len());
+        for embedding in &embeddings {
+            searches.push(
+                db.search_with_metadata(num_results, embedding, &filter)
+                    .await?,
+            );
+        }
+
+        Ok(merge(searches, num_results, |(metadata, similarity)| {
+            (&metadata.file_path, *similarity)
+        }))
+    }
+
+    /// Run one search per line of stdin, printing the results of each query as a JSON line like `{"query": "cat", "results": [{"file": "cat.jpg", "similarity": 0.8}]}`, with previews if `--preview` is given. The database and API client are shared by all queries. Returns the number of queries.
+    #[allow(clippy::future_not_send, reason = "Main function")]
+    pub async fn execute_batch(&self, config: Config) -> Result<usize> {
+        if !self.query.is_empty() {
+            anyhow::bail!("A query should not be given with --batch");
+        }
+        let (db, api) = open(&config).await?;
+        let filter = self.filter();
+        let num_results = self.num_results.unwrap_or(config.search.num_results);
+
+        #[derive(Serialize)]
+        struct BatchResults<'a> {
+            query: &'a str,
+            results: Vec<SearchResult>,
+        }
+
+        let mut writer = self.writer()?;
+        let mut count = 0;
+        for line in io::stdin().lock().lines() {
+            let line = line?;
+            let query = line.trim();
+            if query.is_empty() {
+                continue;
+            }
+            let embedding: Embedding = api.embed(query).await?.into();
+            let results = self.results(&db, &embedding, &filter, num_results).await?;
+            serde_json::to_writer(&mut writer, &BatchResults { query, results })?;
+            writeln!(writer)?;
+            count += 1;
+        }
+        writer.flush()?;
+
+        Ok(count)
+    }
+
+    /// Search the database, reading previews if `--preview` is given.
+    #[allow(clippy::future_not_send, reason = "Main function")]
+    async fn results(
+        &self,
+        db: &Database,
+        embedding: &Embedding,
+        filter: &PathFilter,
+        num_results: usize,
+    ) -> Result<Vec<SearchResult>> {
+        let Some(chars) = self.preview else {
+            let results = db.search(num_results, embedding, filter).await?;
+            return Ok(results
+                .into_iter()
+                .map(|(file, similarity)| SearchResult {
+                    file,
+                    similarity,
+                    preview: None,
+                })
+                .collect());
+        };
+        let results = db
+            .search_with_metadata(num_results, embedding, filter)
+            .await?;
+        let results = results
+            .into_iter()
+            .map(|(metadata, similarity)| {
+                let preview =
+                    read_preview(&db.resolve(&metadata.file_path), chars).unwrap_or(metadata.label);
+                SearchResult {
+                    file: metadata.file_path,
+                    similarity,
+                    preview: Some(preview),
+                }
+            })
+            .collect();
 
         Ok(results)
     }
+
+    /// Open the database and embed the query terms, returning them with the filter and number of results.
+    async fn prepare(
+        &self,
+        config: &Config,
+    ) -> Result<(Database, Vec<Embedding>, PathFilter, usize)> {
+        let terms = self.terms()?;
+        if is_blank(&terms) {
+            anyhow::bail!("No query given, pass one or use --batch to read queries from stdin");
+        }
+        let (db, api) = open(config).await?;
+        let embeddings = self.embed(&api, &terms).await?;
+        let num_results = self.num_results.unwrap_or(config.search.num_results);
+
+        Ok((db, embeddings, self.filter(), num_results))
+    }
+
+    /// Query terms given, or the query read from stdin if none is given or the only one is `-`, like `echo "cat" | sense search`. All of stdin is a single query, use `--batch` for one per line. In a terminal, the query is prompted for instead.
+    fn terms(&self) -> Result<Vec<String>> {
+        if !self.query.is_empty() && self.query != ["-"] {
+            return Ok(self.query.clone());
+        }
+        let stdin = io::stdin();
+        let query = if stdin.is_terminal() {
+            prompt("Query: ")?
+        } else {
+            let mut query = String::new();
+            stdin.lock().read_to_string(&mut query)?;
+            query.trim().to_owned()
+        };
+
+        Ok(vec![query])
+    }
+
+    /// Embed the query terms, combined into a single embedding with `--combine mean`, or kept separate with `--combine max`.
+    async fn embed(&self, api: &ApiClient, terms: &[String]) -> Result<Vec<Embedding>> {
+        if is_blank(terms) {
+            anyhow::bail!("No query given");
+        }
+        let mut embeddings = Vec::with_capacity(terms.len());
+        for term in terms {
+            embeddings.push(api.embed(term).await?.into());
+        }
+        if self.combine == Combine::Mean && embeddings.len() > 1 {
+            embeddings = Embedding::mean(&embeddings).into_iter().collect();
+        }
+
+        Ok(embeddings)
+    }
+
+    /// Format of the output: given by `--format` or `--json`, then the global `--format`, or guessed from the extension of `--output`.
+    #[must_use]
+    pub fn output_format(&self, global: Option<OutputFormat>) -> OutputFormat {
+        if let Some(format) = self
+            .format
+            .or_else(|| self.json.then_some(OutputFormat::Json))
+            .or(global)
+        {
+            return format;
+        }
+        let ext = self
+            .output
+            .as_ref()
+            .and_then(|path| path.extension())
+            .map(|ext| ext.to_string_lossy().to_lowercase());
+        match ext.as_deref() {
+            Some("json") => OutputFormat::Json,
+            Some("jsonl" | "ndjson") => OutputFormat::Ndjson,
+            _ => OutputFormat::Text,
+        }
+    }
+
+    /// Buffered writer to the file given by `--output`, or stdout.
+    pub fn writer(&self) -> Result<BufWriter<Box<dyn Write>>> {
+        let writer: Box<dyn Write> = match &self.output {
+            Some(path) => Box::new(
+                File::create(path)
+                    .with_context(|| format!("Failed to create {}", path.display()))?,
+            ),
+            None => Box::new(io::stdout().lock()),
+        };
+
+        Ok(BufWriter::new(writer))
+    }
+
+    /// Filter on paths given by `--under`, `--glob` and `--exclude`.
+    fn filter(&self) -> PathFilter {
+        PathFilter {
+            under: self.under.clone(),
+            glob: self.glob.clone(),
+            exclude: self.exclude.clone(),
+        }
+    }
+}
+
+/// Whether no query term is given, or all of them are blank.
+fn is_blank(terms: &[String]) -> bool {
+    terms.iter().all(|term| term.trim().is_empty())
+}
+
+/// Merge the results of several searches, keeping the highest similarity of each file, and return the `n` most similar, most similar first. `key` gives the path and similarity of a result.
+///
+/// As each search returns its own top `n`, the merged top `n` is exact.
+fn merge<T, F>(searches: Vec<Vec<T>>, n: usize, key: F) -> Vec<T>
+where
+    F: Fn(&T) -> (&String, f32),
+{
+    let mut searches = searches.into_iter();
+    let Some(first) = searches.next() else {
+        return Vec::new();
+    };
+    // Paths are unique within a search
+    let mut merged = first;
+    for result in searches.flatten() {
+        let (file, similarity) = key(&result);
+        match merged.iter().position(|other| key(other).0 == file) {
+            Some(i) if key(&merged[i]).1 < similarity => merged[i] = result,
+            Some(_) => {}
+            None => merged.push(result),
+        }
+    }
+    merged.sort_by(|a, b| descending(key(a).1, key(b).1));
+    merged.truncate(n);
+    merged
+}
+
+/// Read the first `chars` characters of a file, with whitespace collapsed into single spaces. Returns `None` if the file can't be read, is binary or is blank.
+fn read_preview(path: &Path, chars: usize) -> Option<String> {
+    // Characters are at most 4 bytes in UTF-8
+    let limit = u64::try_from(chars.saturating_mul(4)).unwrap_or(u64::MAX);
+    let mut bytes = Vec::new();
+    File::open(path)
+        .ok()?
+        .take(limit)
+        .read_to_end(&mut bytes)
+        .ok()?;
+    if bytes.contains(&0) {
+        return None;
+    }
+    let text = match str::from_utf8(&bytes) {
+        Ok(text) => text,
+        // Cut in the middle of a character by the limit
+        Err(e) if e.error_len().is_none() => str::from_utf8(&bytes[..e.valid_up_to()]).ok()?,
+        Err(_) => return None,
+    };
+
+    let mut words = text.split_whitespace();
+    let mut preview = words.next()?.to_owned();
+    for word in words {
+        preview.push(' ');
+        preview.push_str(word);
+    }
+    if preview.chars().count() > chars {
+        preview = preview.chars().take(chars).collect();
+        preview.push('…');
+    }
+    Some(preview)
+}
+
+/// Open the database read-only and create an API client.
+pub(super) async fn open(config: &Config) -> Result<(Database, ApiClient)> {
+    let db = Database::open(&config.database.path, true, config.database.wal)
+        .await
+        .with_context(|| "Failed to open database, consider indexing first.")?;
+    let api = config.api.client()?;
+
+    Ok((db, api))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Search parsed from command line arguments.
+    fn search(args: &[&str]) -> Search {
+        Search::from_args(&["search"], args).unwrap()
+    }
+
+    #[test]
+    fn merge_max() {
+        let result = |file: &str, similarity| (file.to_owned(), similarity);
+        let searches = vec![
+            vec![result("a", 0.5), result("b", 0.4)],
+            vec![result("b", 0.9), result("c", 0.3), result("a", 0.1)],
+        ];
+        let merged = merge(searches, 2, |(file, similarity)| (file, *similarity));
+        assert_eq!(merged, [result("b", 0.9), result("a", 0.5)]);
+
+        let merged: Vec<(String, f32)> =
+            merge(Vec::new(), 2, |(file, similarity)| (file, *similarity));
+        assert!(merged.is_empty());
+    }
+
+    #[test]
+    fn score_format() {
+        let formats =
+            |format: ScoreFormat| [-1.0, 0.0, 1.0].map(|similarity| format.format(similarity));
+        assert_eq!(
+            formats(ScoreFormat::Cosine),
+            ["-1.0000", "0.0000", "1.0000"]
+        );
+        assert_eq!(
+            formats(ScoreFormat::Percent),
+            ["-100.00%", "0.00%", "100.00%"]
+        );
+        assert_eq!(
+            formats(ScoreFormat::Normalized),
+            ["0.00%", "50.00%", "100.00%"]
+        );
+    }
+
+    #[test]
+    fn preview() {
+        let dir = std::env::temp_dir().join(format!("sense-preview-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("notes.txt"), "cute\n\n  cat  pictures").unwrap();
+        std::fs::write(dir.join("cat.jpg"), b"\xff\xd8\xff\x00JFIF").unwrap();
+
+        assert_eq!(
+            read_preview(&dir.join("notes.txt"), 100).as_deref(),
+            Some("cute cat pictures")
+        );
+        assert_eq!(
+            read_preview(&dir.join("notes.txt"), 8).as_deref(),
+            Some("cute cat…")
+        );
+        // Binary and missing files fall back to their labels
+        assert_eq!(read_preview(&dir.join("cat.jpg"), 100), None);
+        assert_eq!(read_preview(&dir.join("missing.txt"), 100), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn output_format() {
+        let format = |args: &[&str]| search(args).output_format(None);
+        assert_eq!(format(&["-o", "results.json"]), OutputFormat::Json);
+        assert_eq!(format(&["-o", "results.JSONL"]), OutputFormat::Ndjson);
+        assert_eq!(format(&["-o", "results.ndjson"]), OutputFormat::Ndjson);
+        assert_eq!(format(&["-o", "results.txt"]), OutputFormat::Text);
+        assert_eq!(format(&[]), OutputFormat::Text);
+        // Explicit formats take precedence over the extension
+        assert_eq!(
+            format(&["-o", "results.json", "-f", "ndjson"]),
+            OutputFormat::Ndjson
+        );
+        assert_eq!(format(&["-o", "results.txt", "--json"]), OutputFormat::Json);
+        assert_eq!(
+            search(&["-o", "results.json"]).output_format(Some(OutputFormat::Text)),
+            OutputFormat::Text
+        );
+    }
 }
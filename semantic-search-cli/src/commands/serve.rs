@@ -1,22 +1,601 @@
 //! `serve` subcommand
+//!
+//! ## Concurrency
+//!
+//! Requests are handled concurrently, sharing a read-only database opened at startup, whose pool of connections lets searches run in parallel. Reindexing opens the database separately for writing, and its changes are visible to searches once committed.
+//!
+//! ## TLS
+//!
+//! If both `server.tls_cert` and `server.tls_key` are configured, connections are served over HTTPS with rustls, and plain HTTP otherwise. Failed handshakes are logged, without affecting other connections.
+//!
+//! ## Metrics
+//!
+//! Every search is logged with its query, number of results and latency. If built with the `metrics` feature and `server.metrics` is enabled, counters of searches and failed searches, as well as a histogram of their latency, are exposed at `GET /metrics` in the Prometheus text format.
+//!
+//! ## In-Memory Search
+//!
+//! With `--in-memory`, all embeddings are loaded at startup, so that searches compare against them without reading the database, taking about 4 KiB of memory per file. After a reindex over HTTP, the database is reopened and embeddings loaded again, while searches already running finish with the previous ones.
 
-#![allow(unused_imports, unused_variables, reason = "Not implemented yet.")]
-
-use crate::Config;
+use super::{
+    Index, ScoreFormat,
+    search::{Combine, Search},
+};
+use crate::{
+    Config,
+    config::BindAddress,
+    util::{BusyGuard, Database, Metadata},
+};
 use anyhow::{Context, Result};
 use argh::FromArgs;
+use futures_util::stream;
+use glob::Pattern;
+use http_body_util::{BodyExt, Full, StreamBody, combinators::BoxBody};
+use hyper::{
+    Method, Request, Response, StatusCode,
+    body::{Bytes, Frame, Incoming},
+    header::{AUTHORIZATION, CACHE_CONTROL, CONTENT_TYPE, HeaderMap},
+    server::conn::http1,
+    service::service_fn,
+};
+use hyper_util::rt::TokioIo;
+use log::{debug, error, info, warn};
+#[cfg(feature = "metrics")]
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use semantic_search::ApiClient;
+use serde::{Deserialize, Serialize};
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, RwLock, atomic::AtomicBool},
+    time::Instant,
+};
+use subtle::ConstantTimeEq;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+};
+use tokio_rustls::{
+    TlsAcceptor,
+    rustls::{
+        self,
+        pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    },
+};
+
+/// Body of all responses.
+type Body = BoxBody<Bytes, Infallible>;
+
+/// Whether a reindex requested over HTTP is in progress.
+static REINDEXING: AtomicBool = AtomicBool::new(false);
+
+/// Number of embeddings above which loading them into memory warns, taking about 1 GiB.
+const LARGE_IN_MEMORY: usize = 1 << 18;
+
+/// Counter of searches, including failed ones.
+#[cfg(feature = "metrics")]
+const SEARCHES: &str = "sense_searches_total";
+
+/// Counter of failed searches.
+#[cfg(feature = "metrics")]
+const SEARCH_ERRORS: &str = "sense_search_errors_total";
+
+/// Histogram of search latency, in seconds.
+#[cfg(feature = "metrics")]
+const SEARCH_LATENCY: &str = "sense_search_duration_seconds";
+
+/// Buckets of [`SEARCH_LATENCY`], in seconds.
+#[cfg(feature = "metrics")]
+const LATENCY_BUCKETS: [f64; 10] = [0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
 
 /// start a server to search for files
 #[derive(FromArgs, PartialEq, Eq, Debug)]
 #[argh(subcommand, name = "serve", help_triggers("-h", "--help"))]
 pub struct Serve {
-    // ...
+    /// address to listen on, like `0.0.0.0` or `0.0.0.0:9000`, overriding `server.bind` in the config
+    #[argh(option)]
+    pub bind: Option<BindAddress>,
+    /// port to listen on, overriding `server.port` and the port of the bind address
+    #[argh(option, short = 'p')]
+    pub port: Option<u16>,
+    /// load all embeddings into memory at startup for faster searches
+    #[argh(switch)]
+    pub in_memory: bool,
 }
 
 impl Serve {
-    #[allow(clippy::unused_async, reason = "Not implemented yet")]
+    /// Start the server.
+    ///
+    /// # Memory Leak
+    ///
+    /// Like the Telegram bot, this function leaks the state shared by request handlers, including `config` and `api`, as it runs indefinitely and is called only once.
     pub async fn execute(&self, config: Config) -> Result<()> {
-        // ...
-        Ok(())
+        let address = config.server.address(self.bind, self.port);
+        if config.server.token.as_deref().is_none_or(str::is_empty) {
+            warn!("No `server.token` configured, reindex requests will be rejected");
+        }
+        #[cfg(not(feature = "metrics"))]
+        if config.server.metrics {
+            warn!(
+                "`server.metrics` is enabled, but metrics are not available without the `metrics` feature"
+            );
+        }
+        #[cfg(feature = "metrics")]
+        let metrics = if config.server.metrics {
+            Some(install_recorder()?)
+        } else {
+            None
+        };
+        let db = open(&config, self.in_memory)
+            .await
+            .with_context(|| "Failed to open database, consider indexing first.")?;
+        let api = config.api.client()?;
+        let tls = config
+            .server
+            .tls()?
+            .map(|(cert, key)| tls_acceptor(cert, key))
+            .transpose()?;
+        let listener = TcpListener::bind(address)
+            .await
+            .with_context(|| format!("Failed to listen on {address}"))?;
+        let scheme = if tls.is_some() { "https" } else { "http" };
+        info!("Listening on {scheme}://{}", listener.local_addr()?);
+
+        // Leaking the state here
+        let state: &State = Box::leak(Box::new(State {
+            db: RwLock::new(Arc::new(db)),
+            api,
+            config,
+            in_memory: self.in_memory,
+            #[cfg(feature = "metrics")]
+            metrics,
+        }));
+        loop {
+            let (stream, address) = listener.accept().await?;
+            debug!("Accepted connection from {address}");
+            let tls = tls.clone();
+            tokio::spawn(async move {
+                match tls {
+                    Some(acceptor) => match acceptor.accept(stream).await {
+                        Ok(stream) => serve_connection(stream, address, state).await,
+                        Err(e) => warn!("TLS handshake with {address} failed: {e}"),
+                    },
+                    None => serve_connection(stream, address, state).await,
+                }
+            });
+        }
+    }
+}
+
+/// Serve requests over a connection, plain or encrypted.
+async fn serve_connection<I>(io: I, address: SocketAddr, state: &State)
+where
+    I: AsyncRead + AsyncWrite + Unpin,
+{
+    let service = service_fn(|request| handle(request, state));
+    if let Err(e) = http1::Builder::new()
+        .serve_connection(TokioIo::new(io), service)
+        .await
+    {
+        error!("Failed to serve connection from {address}: {e}");
+    }
+}
+
+/// Build a TLS acceptor from PEM files of the certificate chain and private key.
+fn tls_acceptor(cert: &Path, key: &Path) -> Result<TlsAcceptor> {
+    let certs: Vec<_> = CertificateDer::pem_file_iter(cert)
+        .and_then(Iterator::collect)
+        .with_context(|| format!("Failed to read TLS certificate {}", cert.display()))?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificate found in {}", cert.display());
+    }
+    let key = PrivateKeyDer::from_pem_file(key)
+        .with_context(|| format!("Failed to read TLS private key {}", key.display()))?;
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Invalid TLS certificate or private key")?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Install the Prometheus recorder, describing the metrics of searches.
+#[cfg(feature = "metrics")]
+fn install_recorder() -> Result<PrometheusHandle> {
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Full(SEARCH_LATENCY.to_owned()), &LATENCY_BUCKETS)?
+        .install_recorder()?;
+    metrics::describe_counter!(SEARCHES, "Number of searches, including failed ones");
+    metrics::describe_counter!(SEARCH_ERRORS, "Number of failed searches");
+    metrics::describe_histogram!(
+        SEARCH_LATENCY,
+        metrics::Unit::Seconds,
+        "Latency of searches"
+    );
+
+    Ok(handle)
+}
+
+/// Open the database read-only, loading all embeddings into memory if requested.
+async fn open(config: &Config, in_memory: bool) -> Result<Database> {
+    let mut db = Database::open(&config.database.path, true, config.database.wal).await?;
+    if in_memory {
+        let count = db.load_embeddings().await?;
+        info!("Loaded {count} embeddings into memory");
+        if count > LARGE_IN_MEMORY {
+            warn!(
+                "Keeping {count} embeddings in memory takes about {} MiB, consider searching the database instead",
+                count / 256
+            );
+        }
+    }
+
+    Ok(db)
+}
+
+/// State shared by all request handlers.
+struct State {
+    /// Read-only database, replaced after a reindex if embeddings are kept in memory
+    db: RwLock<Arc<Database>>,
+    /// Embedding API client
+    api: ApiClient,
+    /// Configuration
+    config: Config,
+    /// Whether embeddings are kept in memory
+    in_memory: bool,
+    /// Handle to render metrics, if enabled
+    #[cfg(feature = "metrics")]
+    metrics: Option<PrometheusHandle>,
+}
+
+impl State {
+    /// The current database.
+    fn db(&self) -> Arc<Database> {
+        self.db.read().expect("Lock not poisoned").clone()
+    }
+}
+
+/// Route a request to its handler.
+async fn handle(request: Request<Incoming>, state: &State) -> Result<Response<Body>, Infallible> {
+    info!("{} {}", request.method(), request.uri().path());
+    let response = match (request.method(), request.uri().path()) {
+        (&Method::POST, "/search") => search(request, state).await,
+        (&Method::GET, "/search/stream") => search_stream(&request, state).await,
+        (&Method::POST, "/reindex") => reindex(request.headers(), state).await,
+        #[cfg(feature = "metrics")]
+        (&Method::GET, "/metrics") if state.metrics.is_some() => metrics(state),
+        (_, "/search" | "/search/stream" | "/reindex") => {
+            error(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed")
+        }
+        _ => error(StatusCode::NOT_FOUND, "Not found"),
+    };
+
+    Ok(response)
+}
+
+/// Parameters of a search, as a JSON body or query string.
+#[derive(Deserialize)]
+struct SearchParams {
+    /// Query string
+    #[serde(alias = "q")]
+    query: String,
+    /// Number of results, default is `search.num_results` in the config
+    limit: Option<usize>,
+    /// Only search files with this extension, e.g. `jpg`
+    ext: Option<String>,
+    /// Include the stored label and hash of each result
+    #[serde(default)]
+    with_metadata: bool,
+}
+
+/// Options given in the query string of `POST /search`.
+#[derive(Deserialize, Default)]
+struct SearchOptions {
+    /// Include the stored label and hash of each result
+    #[serde(default)]
+    with_metadata: bool,
+}
+
+/// A search result, with its label and hash if requested.
+#[derive(Serialize)]
+struct SearchResult<'a> {
+    /// Path of the file
+    file: &'a str,
+    /// Cosine similarity to the query
+    similarity: f32,
+    /// Stored label of the file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<&'a str>,
+    /// SHA-256 hash of the file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file_hash: Option<&'a str>,
+}
+
+impl<'a> SearchResult<'a> {
+    /// Build a search result, dropping the metadata unless requested.
+    fn new(metadata: &'a Metadata, similarity: f32, with_metadata: bool) -> Self {
+        Self {
+            file: &metadata.file_path,
+            similarity,
+            label: with_metadata.then_some(metadata.label.as_str()),
+            file_hash: with_metadata.then_some(metadata.file_hash.as_str()),
+        }
+    }
+}
+
+/// Search with the parameters in the JSON body, responding with the matching files, most similar first.
+///
+/// With `with_metadata` set in the body or query string (`POST /search?with_metadata=true`), the similarity, label and hash of each file are also included as `results`.
+async fn search(request: Request<Incoming>, state: &State) -> Response<Body> {
+    let query = request.uri().query().unwrap_or_default();
+    let options: SearchOptions = match serde_urlencoded::from_str(query) {
+        Ok(options) => options,
+        Err(e) => return error(StatusCode::BAD_REQUEST, &e.to_string()),
+    };
+    let body = match request.into_body().collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(e) => return error(StatusCode::BAD_REQUEST, &e.to_string()),
+    };
+    let mut params: SearchParams = match serde_json::from_slice(&body) {
+        Ok(params) => params,
+        Err(e) => return error(StatusCode::BAD_REQUEST, &e.to_string()),
+    };
+    params.with_metadata |= options.with_metadata;
+    let with_metadata = params.with_metadata;
+    let results = match search_results(params, state).await {
+        Ok(results) => results,
+        Err(response) => return response,
+    };
+
+    #[derive(Serialize)]
+    struct SearchBody<'a> {
+        files: Vec<&'a str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        results: Option<Vec<SearchResult<'a>>>,
+    }
+    let files = results
+        .iter()
+        .map(|(metadata, _)| metadata.file_path.as_str())
+        .collect();
+    let results = with_metadata.then(|| {
+        results
+            .iter()
+            .map(|(metadata, similarity)| SearchResult::new(metadata, *similarity, true))
+            .collect()
+    });
+    json(StatusCode::OK, &SearchBody { files, results })
+}
+
+/// Search with the parameters in the query string, streaming results as Server-Sent Events, most similar first.
+///
+/// Each result is sent as a `data` event like `{"file": "cat.jpg", "similarity": 0.8}`, followed by a final `done` event. With `with_metadata=true`, the `label` and `file_hash` of each file are included too.
+async fn search_stream(request: &Request<Incoming>, state: &State) -> Response<Body> {
+    let query = request.uri().query().unwrap_or_default();
+    let params: SearchParams = match serde_urlencoded::from_str(query) {
+        Ok(params) => params,
+        Err(e) => return error(StatusCode::BAD_REQUEST, &e.to_string()),
+    };
+    let with_metadata = params.with_metadata;
+    let results = match search_results(params, state).await {
+        Ok(results) => results,
+        Err(response) => return response,
+    };
+
+    let events = results
+        .iter()
+        .map(|(metadata, similarity)| {
+            let result = SearchResult::new(metadata, *similarity, with_metadata);
+            let data = serde_json::to_string(&result).expect("Serializable result");
+            format!("data: {data}\n\n")
+        })
+        .chain(std::iter::once("event: done\ndata:\n\n".to_owned()))
+        .map(|event| Ok(Frame::data(Bytes::from(event))))
+        .collect::<Vec<_>>();
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "text/event-stream")
+        .header(CACHE_CONTROL, "no-cache")
+        .body(StreamBody::new(stream::iter(events)).boxed())
+        .expect("Valid response")
+}
+
+/// Run a search, or build the error response if it fails, logging and recording its latency.
+async fn search_results(
+    params: SearchParams,
+    state: &State,
+) -> Result<Vec<(Metadata, f32)>, Response<Body>> {
+    let query = params.query.clone();
+    let start = Instant::now();
+    let results = run_search(params, state).await;
+    let latency = start.elapsed();
+    match &results {
+        Ok(results) => info!(
+            "Searched {query:?} in {} ms, {} results",
+            latency.as_millis(),
+            results.len()
+        ),
+        Err(_) => info!("Failed to search {query:?} in {} ms", latency.as_millis()),
+    }
+    #[cfg(feature = "metrics")]
+    record_search(results.is_err(), latency);
+
+    results
+}
+
+/// Record a search in the metrics, which does nothing unless they are enabled.
+#[cfg(feature = "metrics")]
+fn record_search(failed: bool, latency: std::time::Duration) {
+    metrics::counter!(SEARCHES).increment(1);
+    if failed {
+        metrics::counter!(SEARCH_ERRORS).increment(1);
+    }
+    metrics::histogram!(SEARCH_LATENCY).record(latency.as_secs_f64());
+}
+
+/// Run a search, or build the error response if it fails.
+async fn run_search(
+    params: SearchParams,
+    state: &State,
+) -> Result<Vec<(Metadata, f32)>, Response<Body>> {
+    let glob = match params
+        .ext
+        .as_deref()
+        .map(|ext| Pattern::new(&format!("*.{ext}")))
+    {
+        Some(Ok(pattern)) => Some(pattern),
+        Some(Err(e)) => return Err(error(StatusCode::BAD_REQUEST, &e.to_string())),
+        None => None,
+    };
+    let search = Search {
+        query: vec![params.query],
+        num_results: params.limit,
+        under: None,
+        glob,
+        exclude: Vec::new(),
+        score_format: ScoreFormat::default(),
+        combine: Combine::default(),
+        batch: false,
+        preview: None,
+        json: false,
+        format: None,
+        output: None,
+    };
+    search
+        .execute_with_metadata(&state.db(), &state.api, &state.config)
+        .await
+        .map_err(|e| {
+            error!("Failed to search: {e:?}");
+            error(StatusCode::INTERNAL_SERVER_ERROR, &format!("{e:#}"))
+        })
+}
+
+/// Index the working directory, responding with the summary. Requires the configured bearer token.
+///
+/// If embeddings are kept in memory, they are loaded again from a newly opened database, which replaces the current one.
+async fn reindex(headers: &HeaderMap, state: &State) -> Response<Body> {
+    let config = &state.config;
+    if !authorized(headers, config.server.token.as_deref()) {
+        return error(StatusCode::UNAUTHORIZED, "Missing or invalid bearer token");
+    }
+    // Only one reindex at a time, until the guard is dropped
+    let Some(_busy) = BusyGuard::acquire(&REINDEXING) else {
+        return error(StatusCode::CONFLICT, "Already reindexing");
+    };
+    let index = Index::from_args(&["index"], &["--yes"]).expect("Valid arguments");
+    let result = index.execute(config.clone()).await;
+    if result.is_ok() && state.in_memory {
+        match open(config, true).await {
+            Ok(db) => *state.db.write().expect("Lock not poisoned") = Arc::new(db),
+            Err(e) => error!("Failed to reload embeddings, searching previous ones: {e:?}"),
+        }
+    }
+
+    match result {
+        Ok(summary) => json(StatusCode::OK, &summary),
+        Err(e) => {
+            error!("Failed to reindex: {e:?}");
+            error(StatusCode::INTERNAL_SERVER_ERROR, &format!("{e:#}"))
+        }
+    }
+}
+
+/// Check the `Authorization: Bearer <token>` header, comparing in constant time so that the token can't be guessed by timing. Always fails if no token is configured, or it is empty.
+fn authorized(headers: &HeaderMap, token: Option<&str>) -> bool {
+    let Some(token) = token.filter(|token| !token.is_empty()) else {
+        return false;
+    };
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|provided| provided.as_bytes().ct_eq(token.as_bytes()).into())
+}
+
+/// Render the metrics in the Prometheus text format.
+#[cfg(feature = "metrics")]
+fn metrics(state: &State) -> Response<Body> {
+    let body = state
+        .metrics
+        .as_ref()
+        .map(PrometheusHandle::render)
+        .unwrap_or_default();
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(Full::new(Bytes::from(body)).boxed())
+        .expect("Valid response")
+}
+
+/// A JSON response.
+fn json<T: Serialize>(status: StatusCode, body: &T) -> Response<Body> {
+    let body = serde_json::to_vec(body).expect("Serializable body");
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from(body)).boxed())
+        .expect("Valid response")
+}
+
+/// A JSON error response, like `{"error": "Not found"}`.
+fn error(status: StatusCode, message: &str) -> Response<Body> {
+    #[derive(Serialize)]
+    struct ErrorBody<'a> {
+        error: &'a str,
+    }
+
+    json(status, &ErrorBody { error: message })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+    use semantic_search::Model;
+
+    /// Headers with the given `Authorization` value.
+    fn headers(authorization: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_str(authorization).unwrap());
+        headers
+    }
+
+    #[test]
+    fn authorized_token() {
+        assert!(!authorized(&HeaderMap::new(), Some("secret")));
+        assert!(!authorized(&headers("Basic secret"), Some("secret")));
+        assert!(!authorized(&headers("Bearer wrong"), Some("secret")));
+        assert!(!authorized(&headers("Bearer secre"), Some("secret")));
+        assert!(!authorized(&headers("Bearer secret"), None));
+        assert!(authorized(&headers("Bearer secret"), Some("secret")));
+
+        // An empty token never authorizes, even with an empty bearer token
+        assert!(!authorized(&headers("Bearer "), Some("")));
+        assert!(!authorized(&headers("Bearer"), Some("")));
+    }
+
+    #[tokio::test]
+    async fn reindex_conflict() {
+        let mut config = Config::default();
+        config.server.token = Some("secret".to_owned());
+        let key = "sk-1234567890abcdef1234567890abcdef1234567890abcdef";
+        let state = State {
+            db: RwLock::new(Arc::new(Database::memory().await.unwrap())),
+            api: ApiClient::new(key, &Model::default()).unwrap(),
+            config,
+            in_memory: false,
+            #[cfg(feature = "metrics")]
+            metrics: None,
+        };
+
+        let response = reindex(&headers("Bearer wrong"), &state).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // A reindex already running, without starting one here
+        let busy = BusyGuard::acquire(&REINDEXING).unwrap();
+        let response = reindex(&headers("Bearer secret"), &state).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        drop(busy);
     }
 }
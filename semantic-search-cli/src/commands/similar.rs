@@ -0,0 +1,43 @@
+//! `similar` subcommand
+
+use crate::{
+    Config,
+    util::{Database, PathFilter},
+};
+use anyhow::{Context, Result};
+use argh::FromArgs;
+
+/// find indexed files similar to an indexed file
+#[derive(FromArgs, PartialEq, Eq, Debug)]
+#[argh(subcommand, name = "similar", help_triggers("-h", "--help"))]
+pub struct Similar {
+    /// path of the file, as stored in the index
+    #[argh(positional)]
+    pub path: String,
+    /// number of results to show, default is `search.num_results` in the config
+    #[argh(option, short = 'n')]
+    pub num_results: Option<usize>,
+}
+
+impl Similar {
+    /// Find the files most similar to the given one, excluding itself. Uses the stored embedding, so no API call is made.
+    #[allow(clippy::future_not_send, reason = "Main function")]
+    pub async fn execute(&self, config: Config) -> Result<Vec<(String, f32)>> {
+        let db = Database::open(&config.database.path, true, config.database.wal)
+            .await
+            .with_context(|| "Failed to open database, consider indexing first.")?;
+        let Some(record) = db.get(&self.path).await? else {
+            anyhow::bail!("{} is not indexed", self.path);
+        };
+
+        // One extra result to make up for the file itself
+        let num_results = self.num_results.unwrap_or(config.search.num_results);
+        let mut results = db
+            .search(num_results + 1, &record.embedding, &PathFilter::default())
+            .await?;
+        results.retain(|(path, _)| path != &self.path);
+        results.truncate(num_results);
+
+        Ok(results)
+    }
+}
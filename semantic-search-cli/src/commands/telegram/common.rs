@@ -9,7 +9,7 @@
 // Sending stickers:
 // SendSticker or InlineQueryResultCachedSticker
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use frankenstein::{
     AsyncTelegramApi, Error,
@@ -24,34 +24,94 @@ use frankenstein::{
 };
 use image::{
     GenericImageView, ImageError, ImageFormat, ImageResult,
-    error::{ImageFormatHint, UnsupportedError, UnsupportedErrorKind},
+    error::{
+        ImageFormatHint, ParameterError, ParameterErrorKind, UnsupportedError, UnsupportedErrorKind,
+    },
     imageops::FilterType,
 };
 use log::{debug, error, info, warn};
 
 use super::{BotConfig, BotResult};
 use crate::util::Database;
+use tokio::sync::Mutex;
 
-/// Number of images per batch. Must be lower than sticker set limit (120).
+/// Number of images per batch. Must be lower than sticker set limit.
 const BATCH_SIZE: usize = 20;
+/// Maximum number of stickers in a sticker set.
+const STICKER_SET_LIMIT: usize = 120;
+/// Emoji for stickers without any emojis.
+const DEFAULT_EMOJI: &str = "😼";
+/// Maximum number of emojis per sticker.
+const MAX_EMOJIS: usize = 20;
+/// Length of the longer side of static stickers, in pixels.
+const STICKER_SIDE: u32 = 512;
+/// Extensions of files that can become stickers: images, converted if necessary, and video and animated stickers.
+const STICKER_EXTENSIONS: [&str; 6] = ["jpeg", "jpg", "png", "webp", "webm", "tgs"];
+
+/// Held while uploading stickers, so that uploads run one at a time, e.g. for two inline queries, and never empty or fill the same sticker set at once. Separate from the database lock, which is only held briefly.
+static UPLOADING: Mutex<()> = Mutex::const_new(());
 
-/// Initialize stickers.
+/// Initialize stickers, uploading all stickers without a file id.
+///
+/// Like other uploads, the database is only locked while it is read or written, not during requests to Telegram, so that searches are answered meanwhile.
 pub async fn init_stickers(
     bot: &Bot,
     me: &User,
-    db: &mut Database,
+    db: &Mutex<Database>,
     config: &BotConfig,
 ) -> anyhow::Result<()> {
+    let paths = db.lock().await.paths_without_file_ids().await;
+    upload_stickers(bot, me, db, config, paths).await
+}
+
+/// Whether the file at a path can become a sticker, by its extension. Others, like text documents, are never uploaded.
+fn is_sticker(path: &str) -> bool {
+    STICKER_EXTENSIONS.contains(&extension(Path::new(path)).as_str())
+}
+
+/// Upload stickers at given paths via the sticker set, saving their file ids to the database. Paths that cannot become stickers are skipped, and files that fail to upload are logged and skipped.
+///
+/// Uploads run one at a time, see [`UPLOADING`], and paths uploaded by another call meanwhile are skipped.
+async fn upload_stickers(
+    bot: &Bot,
+    me: &User,
+    db: &Mutex<Database>,
+    config: &BotConfig,
+    paths: Vec<String>,
+) -> anyhow::Result<()> {
+    let _uploading = UPLOADING.lock().await;
+    let paths: Vec<_> = {
+        let db = db.lock().await;
+        let mut missing = Vec::with_capacity(paths.len());
+        for path in paths.into_iter().filter(|path| is_sticker(path)) {
+            let uploaded = db
+                .get(&path)
+                .await
+                .ok()
+                .flatten()
+                .is_some_and(|r| r.file_id.is_some());
+            if !uploaded {
+                missing.push(path);
+            }
+        }
+        missing
+    };
+    if paths.is_empty() {
+        debug!("No stickers to upload");
+        return Ok(());
+    }
     let Some(bot_name) = &me.username else {
         anyhow::bail!("Cannot initialize stickers without a bot username.");
     };
+    if config.keep_stickers {
+        return upload_to_kept_sets(bot, me, db, config, bot_name, paths).await;
+    }
     let sticker_set_name = format!("{}_by_{}", config.sticker_set, bot_name);
     let get_params = GetStickerSetParams::builder()
         .name(&sticker_set_name)
         .build();
 
     // Check if the sticker set exists
-    let paths = db.paths_without_file_ids().await;
     let mut paths = paths.into_iter();
     let sticker_set = get_sticker_set(bot, &get_params).await;
     let mut success_paths = Vec::new();
@@ -61,13 +121,25 @@ pub async fn init_stickers(
         debug!("Sticker set found: {sticker_set_name}, emptying...");
         empty_sticker_set(bot, sticker_set).await?;
     } else {
-        // If the sticker set does not exist, create it with one sticker
+        // If the sticker set does not exist, create it with the first sticker uploaded
         debug!("Sticker set not found: {sticker_set_name}, creating...");
-        let Some(path) = paths.next() else {
-            anyhow::bail!("No stickers found in the database.");
+        let (path, file_id) = loop {
+            let Some(path) = paths.next() else {
+                warn!("None of the stickers could be uploaded");
+                return Ok(());
+            };
+            if let Some(file_id) = try_upload_sticker_file(bot, db, &path, me.id).await {
+                break (path, file_id);
+            }
         };
-        let file_id = upload_sticker_file(bot, &path, me.id).await?;
-        create_sticker_set(bot, &sticker_set_name, me.id, &vec![&file_id]).await?;
+        let (emojis, label) = emojis_and_label(db, &path).await;
+        create_sticker_set(
+            bot,
+            &sticker_set_name,
+            me.id,
+            vec![sticker(&file_id, &path, emojis.as_deref(), &label, config)],
+        )
+        .await?;
         success_paths.push(path);
     }
 
@@ -75,11 +147,14 @@ pub async fn init_stickers(
     info!("Uploading stickers...");
     for path in paths {
         // NOTE: This shouldn't be done in parallel, as the stickers must be uploaded in order
-        let file_id = upload_sticker_file(bot, &path, me.id).await?;
+        let Some(file_id) = try_upload_sticker_file(bot, db, &path, me.id).await else {
+            continue;
+        };
+        let (emojis, label) = emojis_and_label(db, &path).await;
         let add_params = AddStickerToSetParams::builder()
             .user_id(me.id)
             .name(&sticker_set_name)
-            .sticker(sticker(&file_id))
+            .sticker(sticker(&file_id, &path, emojis.as_deref(), &label, config))
             .build();
         let result = bot.add_sticker_to_set(&add_params).await;
         if let Err(error) = result {
@@ -109,6 +184,125 @@ pub async fn init_stickers(
     Ok(())
 }
 
+/// Upload stickers into numbered sticker sets that are kept, i.e. `<prefix>_1_by_<bot>`, `<prefix>_2_by_<bot>` and so on, creating the next set when one is full.
+async fn upload_to_kept_sets(
+    bot: &Bot,
+    me: &User,
+    db: &Mutex<Database>,
+    config: &BotConfig,
+    bot_name: &str,
+    paths: Vec<String>,
+) -> anyhow::Result<()> {
+    let set_name = |index: usize| format!("{}_{index}_by_{bot_name}", config.sticker_set);
+
+    // Skip sets that are already full
+    let mut index = 1;
+    let mut count = loop {
+        let get_params = GetStickerSetParams::builder()
+            .name(set_name(index))
+            .build();
+        match get_sticker_set(bot, &get_params).await {
+            Some(sticker_set) if sticker_set.stickers.len() >= STICKER_SET_LIMIT => index += 1,
+            Some(sticker_set) => break sticker_set.stickers.len(),
+            None => break 0,
+        }
+    };
+
+    info!("Uploading stickers...");
+    for path in paths {
+        let name = set_name(index);
+        let Some(file_id) = try_upload_sticker_file(bot, db, &path, me.id).await else {
+            continue;
+        };
+        let (emojis, label) = emojis_and_label(db, &path).await;
+        let input_sticker = sticker(&file_id, &path, emojis.as_deref(), &label, config);
+        let result = if count == 0 {
+            debug!("Creating sticker set {name}...");
+            create_sticker_set(bot, &name, me.id, vec![input_sticker]).await
+        } else {
+            let add_params = AddStickerToSetParams::builder()
+                .user_id(me.id)
+                .name(&name)
+                .sticker(input_sticker)
+                .build();
+            bot.add_sticker_to_set(&add_params).await.map(|_| ())
+        };
+        if let Err(error) = result {
+            error!("[{name}] ! {path}: {error}");
+            continue;
+        }
+
+        // The new sticker is the last one in the set
+        let get_params = GetStickerSetParams::builder().name(&name).build();
+        let Some(added) = get_sticker_set(bot, &get_params)
+            .await
+            .and_then(|sticker_set| sticker_set.stickers.into_iter().last())
+        else {
+            warn!("[{name}] Cannot find the sticker just added for {path}");
+            continue;
+        };
+        {
+            let db = db.lock().await;
+            db.set_file_id(&path, &added.file_id).await?;
+            db.set_sticker_set(&path, &name).await?;
+        }
+        info!("[{name}] + {path}");
+
+        count += 1;
+        if count == STICKER_SET_LIMIT {
+            index += 1;
+            count = 0;
+        }
+    }
+
+    Ok(())
+}
+
+/// Fill in file ids of search results. Missing ones are uploaded on demand if `lazy_upload` is enabled, and are left empty if still missing, e.g. for non-image files, which are never uploaded.
+///
+/// The database must not be locked by the caller, as it is locked only briefly here, not while uploading.
+pub async fn resolve_file_ids(
+    bot: &Bot,
+    me: &User,
+    db: &Mutex<Database>,
+    config: &BotConfig,
+    results: Vec<(String, f32, Option<String>)>,
+) -> Vec<(String, f32, Option<String>)> {
+    let missing: Vec<_> = results
+        .iter()
+        .filter(|(path, _, file_id)| file_id.is_none() && is_sticker(path))
+        .map(|(path, _, _)| path.clone())
+        .collect();
+    if config.lazy_upload && !missing.is_empty() {
+        info!("Uploading {} sticker(s) on demand...", missing.len());
+        if let Err(e) = upload_stickers(bot, me, db, config, missing).await {
+            error!("Failed to upload stickers on demand: {e}");
+        }
+    }
+
+    let db = db.lock().await;
+    let mut resolved = Vec::with_capacity(results.len());
+    for (path, similarity, file_id) in results {
+        let file_id = match file_id {
+            Some(file_id) => Some(file_id),
+            // Uploaded just now, or not at all
+            None => db.get(&path).await.ok().flatten().and_then(|r| r.file_id),
+        };
+        if file_id.is_none() {
+            debug!("No file id for {path}");
+        }
+        resolved.push((path, similarity, file_id));
+    }
+    resolved
+}
+
+/// Count a search query made by the user, for `/stats`.
+pub async fn count_query(db: &Mutex<Database>, user_id: u64) {
+    if let Err(e) = db.lock().await.count_query(user_id).await {
+        error!("Failed to count query of user {user_id}: {e}");
+    }
+}
+
 /// Check if the sticker set exists, returning the sticker set if found.
 async fn get_sticker_set(bot: &Bot, get_params: &GetStickerSetParams) -> Option<StickerSet> {
     match bot.get_sticker_set(get_params).await {
@@ -130,11 +324,17 @@ async fn get_sticker_set(bot: &Bot, get_params: &GetStickerSetParams) -> Option<
     }
 }
 
-/// Upload a sticker file.
-async fn upload_sticker_file(bot: &Bot, path: &str, user_id: u64) -> Result<String, anyhow::Error> {
-    // Image conversion
-    let (image, is_temp) = match convert_if_necessary(path) {
-        Ok((image, is_temp)) => (image, is_temp),
+/// Upload a sticker file, given its path as stored in the database.
+async fn upload_sticker_file(
+    bot: &Bot,
+    db: &Mutex<Database>,
+    path: &str,
+    user_id: u64,
+) -> Result<String, anyhow::Error> {
+    let file = db.lock().await.resolve(path);
+    // Image conversion, keeping the temporary file until the upload is done
+    let (image, _temp) = match convert_if_necessary(&file) {
+        Ok((image, temp)) => (image, temp),
         Err(e) => {
             anyhow::bail!("Failed to convert image: {e} for {path}");
         }
@@ -142,14 +342,11 @@ async fn upload_sticker_file(bot: &Bot, path: &str, user_id: u64) -> Result<Stri
 
     // Upload the sticker
     let sticker_params = UploadStickerFileParams::builder()
-        .sticker_format(StickerFormat::Static)
+        .sticker_format(sticker_format(Path::new(path)))
         .user_id(user_id)
         .sticker(image.clone())
         .build();
     let uploaded = bot.upload_sticker_file(&sticker_params).await;
-    if is_temp {
-        std::fs::remove_file(&image).unwrap();
-    }
 
     match uploaded {
         Ok(uploaded) => {
@@ -163,18 +360,39 @@ async fn upload_sticker_file(bot: &Bot, path: &str, user_id: u64) -> Result<Stri
     }
 }
 
+/// Upload a sticker file like [`upload_sticker_file`], logging the error if it fails, e.g. as the image cannot be converted, so that other files can still be uploaded.
+async fn try_upload_sticker_file(
+    bot: &Bot,
+    db: &Mutex<Database>,
+    path: &str,
+    user_id: u64,
+) -> Option<String> {
+    upload_sticker_file(bot, db, path, user_id)
+        .await
+        .inspect_err(|e| error!("{e}"))
+        .ok()
+}
+
 /// Commit the changes to database and empty the sticker set.
 async fn commit_changes(
     bot: &Bot,
-    db: &mut Database,
+    db: &Mutex<Database>,
     get_params: &GetStickerSetParams,
     success_paths: &[String],
 ) -> anyhow::Result<()> {
     if let Some(sticker_set) = get_sticker_set(bot, get_params).await {
         info!("Updating database...");
         // Take the last `success_paths.len()` stickers
-        let start = sticker_set.stickers.len() - success_paths.len();
+        let Some(start) = sticker_set.stickers.len().checked_sub(success_paths.len()) else {
+            warn!(
+                "Sticker set has {} sticker(s), fewer than the {} just added, not updating the database",
+                sticker_set.stickers.len(),
+                success_paths.len()
+            );
+            return Ok(());
+        };
         let stickers = &sticker_set.stickers[start..];
+        let db = db.lock().await;
         for (path, sticker) in success_paths.iter().zip(stickers) {
             match db.set_file_id(path, &sticker.file_id).await {
                 Ok(true) => debug!("Updated database with file id for {path}"),
@@ -184,6 +402,7 @@ async fn commit_changes(
                 Err(e) => warn!("Failed to update database: {e} for {path}"),
             }
         }
+        drop(db);
         info!("Emptying sticker set...");
         empty_sticker_set(bot, sticker_set).await?;
     } else {
@@ -227,9 +446,8 @@ async fn create_sticker_set(
     bot: &Bot,
     name: &str,
     owner: u64,
-    file_ids: &Vec<&String>,
+    stickers: Vec<InputSticker>,
 ) -> BotResult<()> {
-    let stickers: Vec<_> = file_ids.iter().map(|id| sticker(id)).collect();
     let create_params = CreateNewStickerSetParams::builder()
         .user_id(owner)
         .name(name)
@@ -241,29 +459,94 @@ async fn create_sticker_set(
     result.map(|_| ())
 }
 
-/// Create a sticker from file id.
-fn sticker(file_id: &str) -> InputSticker {
+/// Get the emojis stored for a path, if any, and its label.
+async fn emojis_and_label(db: &Mutex<Database>, path: &str) -> (Option<String>, String) {
+    db.lock()
+        .await
+        .get(path)
+        .await
+        .ok()
+        .flatten()
+        .map(|record| (record.emojis, record.label))
+        .unwrap_or_default()
+}
+
+/// Create a sticker from file id and the path it was uploaded from, with space-separated emojis, or those mapped from keywords in its label by `emoji_map`, or the default one.
+fn sticker(
+    file_id: &str,
+    path: &str,
+    emojis: Option<&str>,
+    label: &str,
+    config: &BotConfig,
+) -> InputSticker {
+    let mut emoji_list: Vec<_> = emojis.unwrap_or_default().split_whitespace().collect();
+    if emoji_list.is_empty() {
+        emoji_list = config.emojis_for(label);
+    }
+    if emoji_list.is_empty() {
+        emoji_list.push(DEFAULT_EMOJI);
+    }
+    let emoji_list: Vec<_> = emoji_list
+        .into_iter()
+        .take(MAX_EMOJIS)
+        .map(str::to_string)
+        .collect();
     InputSticker::builder()
         .sticker(FileUpload::String(file_id.to_string()))
-        .format(StickerFormat::Static)
-        .emoji_list(vec!["😼".to_string()])
+        .format(sticker_format(Path::new(path)))
+        .emoji_list(emoji_list)
         .build()
 }
 
-/// Convert the image if necessary, returning the new path and whether a temporary file was created.
-fn convert_if_necessary(path: &str) -> ImageResult<(PathBuf, bool)> {
-    // Requirements:
+/// Lowercase extension of a path, or an empty string if it has none.
+fn extension(path: &Path) -> String {
+    path.extension()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_lowercase()
+}
+
+/// Sticker format of a file by its extension: `.webm` is a video sticker, `.tgs` an animated one, and anything else a static one.
+fn sticker_format(path: &Path) -> StickerFormat {
+    match extension(path).as_str() {
+        "webm" => StickerFormat::Video,
+        "tgs" => StickerFormat::Animated,
+        _ => StickerFormat::Static,
+    }
+}
+
+/// A temporary file, removed when dropped.
+struct TempFile(PathBuf);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        match std::fs::remove_file(&self.0) {
+            Ok(()) => debug!("Removed temporary file {}", self.0.display()),
+            // Never created, e.g. if the conversion failed
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => warn!("Failed to remove temporary file {}: {e}", self.0.display()),
+        }
+    }
+}
+
+/// Convert the image if necessary, returning the new path and the temporary file created, if any. Video and animated stickers are used as is.
+fn convert_if_necessary(path: &Path) -> ImageResult<(PathBuf, Option<TempFile>)> {
+    // Requirements for static stickers:
     // 1. .PNG or .WEBP format
     // 2. One side must be 512px, the other side equal or less than 512px
+    // Video (.WEBM) and animated (.TGS) stickers must be prepared beforehand, as Telegram requires.
 
-    // We only accept JPEG, PNG, and WEBP formats.
+    // We only accept JPEG, PNG, and WEBP formats for static stickers.
     const ACCEPTED_EXTENSIONS: [&str; 4] = ["jpeg", "jpg", "png", "webp"];
-    let path = PathBuf::from(path);
-    let ext = path
-        .extension()
-        .unwrap_or_default()
-        .to_string_lossy()
-        .to_lowercase();
+    let path = path.to_path_buf();
+    if !matches!(sticker_format(&path), StickerFormat::Static) {
+        debug!(
+            "Uploading video or animated sticker as is: {}",
+            path.display()
+        );
+        return Ok((path, None));
+    }
+    let ext = extension(&path);
     if !ACCEPTED_EXTENSIONS.contains(&ext.as_str()) {
         let format = ImageFormat::from_extension(&ext)
             .map_or(ImageFormatHint::Name(ext), |format| {
@@ -280,22 +563,110 @@ fn convert_if_necessary(path: &str) -> ImageResult<(PathBuf, bool)> {
 
     // Use the original image if it meets the requirements
     let ext_acceptable = ext == "png" || ext == "webp";
-    let one_side_512 = width == 512 || height == 512;
-    let both_leq_512 = width <= 512 && height <= 512;
-    if ext_acceptable && one_side_512 && both_leq_512 {
+    if ext_acceptable && meets_requirements(width, height) {
         debug!("Image already meets requirements: {}", path.display());
-        return Ok((path, false));
+        return Ok((path, None));
     }
 
-    // Resize the image if it doesn't meet the requirements
+    // Resize the image if it doesn't meet the requirements, upscaling small ones
+    let (new_width, new_height) = sticker_dimensions(width, height);
+    let resized = image.resize_exact(new_width, new_height, FilterType::Lanczos3);
+    let (width, height) = resized.dimensions();
+    if !meets_requirements(width, height) {
+        return Err(ImageError::Parameter(ParameterError::from_kind(
+            ParameterErrorKind::DimensionMismatch,
+        )));
+    }
     let new_path = path.with_extension("tmp.webp");
-    let resized = image.resize(512, 512, FilterType::Lanczos3);
+    let temp = TempFile(new_path.clone());
     debug!(
-        "Resized image: {} to {}",
+        "Resized image: {} to {} ({width}x{height})",
         path.display(),
         new_path.display()
     );
     resized.save(&new_path)?;
 
-    Ok((new_path, true))
+    Ok((new_path, Some(temp)))
+}
+
+/// Whether the dimensions meet Telegram's requirement for static stickers: one side exactly 512px, and the other at most 512px.
+const fn meets_requirements(width: u32, height: u32) -> bool {
+    let one_side_512 = width == STICKER_SIDE || height == STICKER_SIDE;
+    let both_leq_512 = width <= STICKER_SIDE && height <= STICKER_SIDE;
+    one_side_512 && both_leq_512
+}
+
+/// Dimensions of a static sticker for an image, scaled so that the longer side is exactly 512px, preserving the aspect ratio.
+fn sticker_dimensions(width: u32, height: u32) -> (u32, u32) {
+    // Scale the shorter side, rounding to the nearest pixel but keeping at least one
+    let scale = |shorter: u32, longer: u32| {
+        let longer = u64::from(longer.max(1));
+        let scaled = (u64::from(shorter) * u64::from(STICKER_SIDE) + longer / 2) / longer;
+        u32::try_from(scaled.max(1)).expect("Shorter side is at most 512px")
+    };
+    if width >= height {
+        (STICKER_SIDE, scale(height, width))
+    } else {
+        (scale(width, height), STICKER_SIDE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sticker_extensions() {
+        assert!(is_sticker("memes/cat.JPG"));
+        assert!(is_sticker("memes/dance.webm"));
+        assert!(!is_sticker("notes/cat.txt"));
+        assert!(!is_sticker("memes/cat"));
+    }
+
+    #[test]
+    fn sticker_dimensions_landscape() {
+        assert_eq!(sticker_dimensions(400, 300), (512, 384));
+        assert_eq!(sticker_dimensions(1920, 1080), (512, 288));
+        assert_eq!(sticker_dimensions(10_000, 1), (512, 1));
+    }
+
+    #[test]
+    fn sticker_dimensions_portrait() {
+        assert_eq!(sticker_dimensions(300, 400), (384, 512));
+        assert_eq!(sticker_dimensions(1080, 1920), (288, 512));
+        assert_eq!(sticker_dimensions(511, 1024), (256, 512));
+    }
+
+    #[test]
+    fn sticker_dimensions_square() {
+        assert_eq!(sticker_dimensions(100, 100), (512, 512));
+        assert_eq!(sticker_dimensions(512, 512), (512, 512));
+        assert_eq!(sticker_dimensions(2048, 2048), (512, 512));
+    }
+
+    #[test]
+    fn convert_small_image() {
+        let dir = std::env::temp_dir().join(format!("sense-convert-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (width, height, expected) in [(400, 300, (512, 384)), (30, 40, (384, 512))] {
+            let path = dir.join(format!("{width}x{height}.png"));
+            image::RgbImage::new(width, height).save(&path).unwrap();
+            let (converted, temp) = convert_if_necessary(&path).unwrap();
+            assert!(temp.is_some());
+            assert_eq!(image::open(&converted).unwrap().dimensions(), expected);
+
+            // Removed once no longer needed
+            drop(temp);
+            assert!(!converted.exists());
+        }
+
+        // Already meets the requirements
+        let path = dir.join("512x100.png");
+        image::RgbImage::new(512, 100).save(&path).unwrap();
+        let (converted, temp) = convert_if_necessary(&path).unwrap();
+        assert!(temp.is_none());
+        assert_eq!(converted, path);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -1,6 +1,9 @@
 //! Module for handling inline queries.
 
-use super::{ApiClient, BotConfig, BotResult, Database};
+use super::{
+    ApiClient, BotConfig, BotResult, Databases,
+    common::{count_query, resolve_file_ids},
+};
 use frankenstein::{
     AsyncTelegramApi,
     client_reqwest::Bot,
@@ -9,49 +12,65 @@ use frankenstein::{
         InputMessageContent, InputTextMessageContent,
     },
     methods::AnswerInlineQueryParams,
+    types::User,
 };
 use log::info;
 use semantic_search::Embedding;
 use std::sync::Arc;
-use tokio::sync::Mutex;
 
 /// Handles inline queries.
 pub async fn inline_handler(
     bot: &Bot,
+    me: &User,
     query: InlineQuery,
-    db: Arc<Mutex<Database>>,
+    db: Arc<Databases>,
     api: &ApiClient,
     config: &BotConfig,
 ) -> BotResult<()> {
     let InlineQuery {
         query: query_str,
         id: query_id,
+        offset,
+        from,
         ..
     } = query;
     let query_str = query_str.trim();
-    if query_str.is_empty() {
+    // Short fragments produce meaningless embeddings, so wait for more input
+    if query_str.is_empty() || query_str.chars().count() < config.min_query_len {
         bot.answer_inline_query(&text_query_params(
             &query_id,
-            "Meow! :3",
-            "Keep paw-typing to sniff out the purr-fect meme... 😸",
+            or_default(&config.empty_query_title, "Meow! :3"),
+            or_default(
+                &config.empty_query_text,
+                "Keep paw-typing to sniff out the purr-fect meme... 😸",
+            ),
         ))
         .await?;
     } else {
-        handle_query(bot, query_str, query_id, db, api, config).await?;
+        // Offset is the number of results already sent, empty for the first page
+        let offset = offset.parse().unwrap_or(0);
+        // Count new queries, not further pages
+        if offset == 0 {
+            count_query(&db.writer, from.id).await;
+        }
+        handle_query(bot, me, query_str, query_id, offset, db, api, config).await?;
     }
     Ok(())
 }
 
-/// Handles non-empty inline queries.
+/// Handles non-empty inline queries, answering the page of results starting at `offset`.
+#[allow(clippy::too_many_arguments, reason = "Handler context")]
 async fn handle_query(
     bot: &Bot,
+    me: &User,
     query_str: &str,
     query_id: String,
-    db: Arc<Mutex<Database>>,
+    offset: usize,
+    db: Arc<Databases>,
     api: &ApiClient,
     config: &BotConfig,
 ) -> BotResult<()> {
-    info!("Handling inline query: {query_str}");
+    info!("Handling inline query: {query_str} (offset {offset})");
     let Ok(raw_embedding) = api.embed(query_str).await else {
         bot.answer_inline_query(&text_query_params(
             &query_id,
@@ -62,10 +81,14 @@ async fn handle_query(
         return Ok(());
     };
     let embedding: Embedding = raw_embedding.into();
-    let results = {
-        let mut db = db.lock().await;
-        db.search_with_id(config.num_results, &embedding).await
-    };
+    // Fetch one more result than needed to tell whether there's a next page
+    let page_size = config.num_results;
+    let results = db
+        .reader
+        .read()
+        .await
+        .search_with_id(offset + page_size + 1, &embedding)
+        .await;
     let Ok(results) = results else {
         bot.answer_inline_query(&text_query_params(
             &query_id,
@@ -84,19 +107,49 @@ async fn handle_query(
         .await?;
         return Ok(());
     }
-    let stickers: Vec<InlineQueryResult> = results
-        .into_iter()
-        .enumerate()
-        .map(|(index, (_path, _similarity, file_id))| sticker(index.to_string(), file_id))
-        .collect();
+    let has_next = results.len() > offset + page_size;
+    let page: Vec<_> = results.into_iter().skip(offset).take(page_size).collect();
+    let page = resolve_file_ids(bot, me, &db.writer, config, page).await;
+    let mut inline_results = Vec::with_capacity(page.len());
+    {
+        let db = db.reader.read().await;
+        for (index, (path, similarity, file_id)) in page.into_iter().enumerate() {
+            let id = (offset + index).to_string();
+            // Records without a file id, e.g. text documents, are shown as articles
+            let result = if let Some(file_id) = file_id {
+                sticker(id, file_id)
+            } else {
+                let label = db
+                    .get(&path)
+                    .await
+                    .ok()
+                    .flatten()
+                    .map(|record| record.label);
+                article(id, &path, label.as_deref().unwrap_or_default(), similarity)
+            };
+            inline_results.push(result);
+        }
+    }
     let answer_params = AnswerInlineQueryParams::builder()
         .inline_query_id(query_id)
-        .results(stickers)
-        .build();
+        .results(inline_results);
+    // Omitting `next_offset` tells Telegram this is the last page
+    let answer_params = if has_next {
+        answer_params
+            .next_offset((offset + page_size).to_string())
+            .build()
+    } else {
+        answer_params.build()
+    };
     bot.answer_inline_query(&answer_params).await?;
     Ok(())
 }
 
+/// The configured text, or the default if empty.
+const fn or_default<'a>(text: &'a str, default: &'a str) -> &'a str {
+    if text.is_empty() { default } else { text }
+}
+
 /// Creates an answer inline query parameters.
 fn text_query_params(id: &str, title: &str, content: &str) -> AnswerInlineQueryParams {
     let message_content = InputMessageContent::Text(
@@ -118,6 +171,24 @@ fn text_query_params(id: &str, title: &str, content: &str) -> AnswerInlineQueryP
         .build()
 }
 
+/// Creates an article inline query result, showing the path, label and similarity, and sending the path and label when chosen.
+fn article(id: String, path: &str, label: &str, similarity: f32) -> InlineQueryResult {
+    let percent = similarity * 100.0;
+    let message_content = InputMessageContent::Text(
+        InputTextMessageContent::builder()
+            .message_text(format!("{path}\n{label}"))
+            .build(),
+    );
+    InlineQueryResult::Article(
+        InlineQueryResultArticle::builder()
+            .id(id)
+            .title(path)
+            .input_message_content(message_content)
+            .description(format!("{percent:.2}%: {label}"))
+            .build(),
+    )
+}
+
 /// Creates an sticker inline query result.
 fn sticker(id: String, file_id: String) -> InlineQueryResult {
     InlineQueryResult::Sticker(
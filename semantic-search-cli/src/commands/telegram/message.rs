@@ -1,19 +1,31 @@
 //! Module for handling messages.
 
-use super::{ApiClient, BotConfig, BotResult, Database, super::super::util::Record};
+use super::{
+    super::super::util::{BusyGuard, Record},
+    ApiClient, BotConfig, BotResult, Databases,
+    common::{count_query, init_stickers, resolve_file_ids},
+};
 use doc_for::{doc, doc_impl};
 use frankenstein::{
     AsyncTelegramApi, Error, ParseMode,
     client_reqwest::Bot,
     input_file::FileUpload,
-    methods::{SendMessageParams, SendStickerParams, SetMyCommandsParams},
+    methods::{SendDocumentParams, SendMessageParams, SendStickerParams, SetMyCommandsParams},
     stickers::StickerType,
     types::{BotCommand, ChatType, LinkPreviewOptions, Message, ReplyParameters, User},
 };
-use log::{error, info};
+use log::{error, info, warn};
 use semantic_search::Embedding;
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::{
+    path::Path,
+    sync::{Arc, atomic::AtomicBool},
+};
+
+/// Number of users listed by `/stats`.
+const STATS_TOP_USERS: usize = 10;
+
+/// Whether a reindex is in progress.
+static REINDEXING: AtomicBool = AtomicBool::new(false);
 
 const FALLBACK_MESSAGES: [&str; 5] = [
     "😹 Maow?",
@@ -35,20 +47,29 @@ pub enum Command {
     Inline,
     /// send a sticker by its file id.
     Sticker(String),
-    /// reply to a sticker with given description to add it to database. Only for bot owner.
+    /// reply to a sticker with given description, optionally led by emojis, to add it to database. Only for bot owner.
     Add(String),
+    /// upload stickers that are not in the sticker set yet. Only for bot owner.
+    Reindex,
+    /// reply to a sticker or give its file id to remove it from database. Only for bot owner.
+    Delete(String),
+    /// show how many searches users have made. Only for bot owner.
+    Stats,
 }
 
 impl Command {
     fn description(config: &BotConfig) -> String {
         let content = format!(
-            "{}\n/help - {}\n/search - {}\n/inline - {}\n/sticker - {}\n/add - {}",
+            "{}\n/help - {}\n/search - {}\n/inline - {}\n/sticker - {}\n/add - {}\n/reindex - {}\n/delete - {}\n/stats - {}",
             doc!(Command),
             doc!(Command, Help),
             doc!(Command, Search),
             doc!(Command, Inline),
             doc!(Command, Sticker),
             doc!(Command, Add),
+            doc!(Command, Reindex),
+            doc!(Command, Delete),
+            doc!(Command, Stats),
         );
         let postscript = config.postscript.trim();
         if postscript.is_empty() {
@@ -87,6 +108,9 @@ impl Command {
             "inline" => Some(Self::Inline),
             "sticker" => Some(Self::Sticker(arg.to_string())),
             "add" => Some(Self::Add(arg.to_string())),
+            "reindex" => Some(Self::Reindex),
+            "delete" => Some(Self::Delete(arg.to_string())),
+            "stats" => Some(Self::Stats),
             _ => None,
         }
     }
@@ -100,6 +124,9 @@ pub async fn set_commands(bot: &Bot) -> BotResult<()> {
         ("/inline", doc!(Command, Inline)),
         ("/sticker", doc!(Command, Sticker)),
         ("/add", doc!(Command, Sticker)),
+        ("/reindex", doc!(Command, Reindex)),
+        ("/delete", doc!(Command, Delete)),
+        ("/stats", doc!(Command, Stats)),
     ];
     let commands: Vec<_> = commands
         .into_iter()
@@ -119,7 +146,7 @@ pub async fn message_handler(
     bot: &Bot,
     me: &User,
     msg: Message,
-    db: Arc<Mutex<Database>>,
+    db: Arc<Databases>,
     api: &ApiClient,
     config: &BotConfig,
 ) -> BotResult<()> {
@@ -137,15 +164,15 @@ pub async fn message_handler(
             return reply(bot, &msg, format!("Sticker file_id: <code>{id}</code>")).await;
         } else {
             // Fallback answer.
-            return answer_fallback(bot, &msg).await;
+            return answer_fallback(bot, &msg, config).await;
         };
     };
     let Some(cmd) = Command::parse(text, username) else {
         // Cannot parse the command
-        return answer_fallback(bot, &msg).await;
+        return answer_fallback(bot, &msg, config).await;
     };
     info!("Received valid command: `{text}`, parsed as: {cmd:?}");
-    match answer_command(bot, &msg, cmd, db, api, config).await {
+    match answer_command(bot, me, &msg, cmd, db, api, config).await {
         Ok(_) => Ok(()),
         Err(e) => {
             error!("Failed to answer the command: {e}");
@@ -157,9 +184,10 @@ pub async fn message_handler(
 /// Answers the command.
 async fn answer_command(
     bot: &Bot,
+    me: &User,
     msg: &Message,
     cmd: Command,
-    db: Arc<Mutex<Database>>,
+    db: Arc<Databases>,
     api: &ApiClient,
     config: &BotConfig,
 ) -> BotResult<()> {
@@ -168,7 +196,10 @@ async fn answer_command(
             Ok(Command::description(config))
         }
         Command::Search(query) => {
-            answer_search(api, &query, db, config).await
+            if !query.is_empty() && let Some(user) = &msg.from {
+                count_query(&db.writer, user.id).await;
+            }
+            answer_search(bot, me, msg, api, &query, db, config).await
         }
         Command::Inline => {
             Ok("🐾 Just mention me in any chat, followed by your query, and I'll pounce into action to fetch the purr-fect meme for you! 😼✨".to_string())
@@ -203,7 +234,10 @@ async fn answer_command(
                 if user.id != config.owner {
                     Err("😾 Only my owner can use this command.".to_string())
                 } else if let Some(reply) = &msg.reply_to_message && let Some(sticker) = &reply.sticker {
-                    insert_sticker(db, api, sticker.file_id.clone(), description).await
+                    let (emojis, description) = split_emojis(&description);
+                    // Fall back to the emoji of the sticker itself
+                    let emojis = if emojis.is_empty() { sticker.emoji.clone() } else { Some(emojis.join(" ")) };
+                    insert_sticker(&db, api, sticker.file_id.clone(), description.to_string(), emojis, config).await
                 } else {
                     Err("🐾 Paws and reflect! Please reply to a sticker. 😾".to_string())
                 }
@@ -211,6 +245,43 @@ async fn answer_command(
                 Err("😾 Who're you?".to_string())
             }
         }
+        Command::Reindex => {
+            if let Some(user) = &msg.from {
+                if user.id != config.owner {
+                    Err("😾 Only my owner can use this command.".to_string())
+                } else {
+                    answer_reindex(bot, me, msg, db, config).await
+                }
+            } else {
+                Err("😾 Who're you?".to_string())
+            }
+        }
+        Command::Delete(file_id) => {
+            if let Some(user) = &msg.from {
+                if user.id != config.owner {
+                    Err("😾 Only my owner can use this command.".to_string())
+                } else if let Some(reply) = &msg.reply_to_message && let Some(sticker) = &reply.sticker {
+                    delete_sticker(&db, &sticker.file_id, config).await
+                } else if !file_id.is_empty() {
+                    delete_sticker(&db, &file_id, config).await
+                } else {
+                    Err("🐾 Paws and reflect! Please reply to a sticker or provide its file id. 😾".to_string())
+                }
+            } else {
+                Err("😾 Who're you?".to_string())
+            }
+        }
+        Command::Stats => {
+            if let Some(user) = &msg.from {
+                if user.id != config.owner {
+                    Err("😾 Only my owner can use this command.".to_string())
+                } else {
+                    answer_stats(db).await
+                }
+            } else {
+                Err("😾 Who're you?".to_string())
+            }
+        }
     };
     let reply_msg = match result {
         Ok(reply) => reply,
@@ -222,11 +293,14 @@ async fn answer_command(
     reply(bot, msg, reply_msg).await
 }
 
-/// Answers the search command.
+/// Answers the search command. If the best match has no sticker, the file itself is sent as a document, see [`send_document`].
 async fn answer_search(
+    bot: &Bot,
+    me: &User,
+    msg: &Message,
     api: &ApiClient,
     query: &str,
-    db: Arc<Mutex<Database>>,
+    db: Arc<Databases>,
     config: &BotConfig,
 ) -> Result<String, String> {
     if query.is_empty() {
@@ -236,38 +310,160 @@ async fn answer_search(
         return Err("Failed to embed the query".to_string());
     };
     let embedding: Embedding = raw_embedding.into();
-    let results = {
-        let mut db = db.lock().await;
-        db.search_with_id(config.num_results, &embedding).await
-    };
+    let results = db
+        .reader
+        .read()
+        .await
+        .search_with_id(config.num_results, &embedding)
+        .await;
     let Ok(results) = results else {
         return Err("Failed to search the database".to_string());
     };
+    let results = resolve_file_ids(bot, me, &db.writer, config, results).await;
+    // Files on disk are found relative to the root of the index
+    let document = match results.first() {
+        Some((path, _, None))
+            if config.max_document_size > 0 && !path.starts_with("tg-sticker://") =>
+        {
+            Some(db.reader.read().await.resolve(path))
+        }
+        _ => None,
+    };
     if results.is_empty() {
         return Ok("😿 No results found...".to_string());
     }
+    let mut notes = Vec::new();
+    if let Some(document) = document
+        && let Err(note) = send_document(bot, msg, &document, &results[0].0, config).await
+    {
+        notes.push(note);
+    }
     // Format the results
     let message: Vec<_> = results
         .iter()
         .map(|(path, similarity, file_id)| {
             let percent = similarity * 100.0;
-            format!("🐾 {percent:.2}%: {path} | <code>/sticker {file_id}</code>")
+            match file_id {
+                Some(file_id) => {
+                    format!("🐾 {percent:.2}%: {path} | <code>/sticker {file_id}</code>")
+                }
+                None => format!("🐾 {percent:.2}%: {path}"),
+            }
         })
+        .chain(notes)
         .collect();
     Ok(message.join("\n"))
 }
 
-/// Fallback message.
-async fn answer_fallback(bot: &Bot, msg: &Message) -> BotResult<()> {
+/// Send the file at `path`, stored as `name`, as a document replying to the message, or explain why it can't be sent, e.g. if it's larger than `max_document_size`.
+async fn send_document(
+    bot: &Bot,
+    msg: &Message,
+    path: &Path,
+    name: &str,
+    config: &BotConfig,
+) -> Result<(), String> {
+    let size = match std::fs::metadata(path) {
+        Ok(metadata) => metadata.len(),
+        Err(e) => {
+            warn!("Failed to read {}: {e}", path.display());
+            return Err(format!("😿 {name} can't be found on disk."));
+        }
+    };
+    if size > config.max_document_size {
+        let megabytes = size as f64 / 1e6;
+        return Err(format!(
+            "📦 {name} is too large to send ({megabytes:.1} MB)."
+        ));
+    }
+    let reply_params = ReplyParameters::builder()
+        .message_id(msg.message_id)
+        .build();
+    let send_params = SendDocumentParams::builder()
+        .chat_id(msg.chat.id)
+        .document(path.to_path_buf())
+        .caption(name)
+        .reply_parameters(reply_params)
+        .build();
+    match bot.send_document(&send_params).await {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            error!("Failed to send {name} as a document: {e}");
+            Err(format!("😿 Failed to send {name}."))
+        }
+    }
+}
+
+/// Answers the reindex command, uploading stickers without a file id and reporting progress in the chat.
+async fn answer_reindex(
+    bot: &Bot,
+    me: &User,
+    msg: &Message,
+    db: Arc<Databases>,
+    config: &BotConfig,
+) -> Result<String, String> {
+    // Only one reindex at a time, until the guard is dropped
+    let Some(_busy) = BusyGuard::acquire(&REINDEXING) else {
+        return Err("🐾 Already reindexing, please wait... 😾".to_string());
+    };
+    // Pick up records indexed since startup
+    db.rebuild_ann_index(config).await;
+    let total = db.writer.lock().await.paths_without_file_ids().await.len();
+    if total == 0 {
+        Ok("😼 All stickers are already uploaded!".to_string())
+    } else {
+        if let Err(e) = reply(bot, msg, format!("🐾 Reindexing {total} sticker(s)...")).await {
+            error!("Failed to report reindex progress: {e}");
+        }
+        // The database is unlocked between uploads, so that searches are answered meanwhile
+        match init_stickers(bot, me, &db.writer, config).await {
+            Ok(()) => {
+                let failed = db.writer.lock().await.paths_without_file_ids().await.len();
+                Ok(format!(
+                    "😼 Reindexed {} sticker(s), {failed} failed.",
+                    total - failed
+                ))
+            }
+            Err(e) => Err(format!("Failed to reindex: {e}")),
+        }
+    }
+}
+
+/// Answers the stats command, listing the most active users.
+async fn answer_stats(db: Arc<Databases>) -> Result<String, String> {
+    let usage = match db.reader.read().await.usage(STATS_TOP_USERS).await {
+        Ok(usage) => usage,
+        Err(e) => return Err(format!("Failed to get usage: {e}")),
+    };
+    let mut reply = format!(
+        "📊 {} search(es) by {} user(s) in total.",
+        usage.total, usage.users
+    );
+    if !usage.top.is_empty() {
+        reply.push_str("\nTop users:");
+        for (user_id, queries) in usage.top {
+            reply.push_str(&format!("\n<code>{user_id}</code>: {queries}"));
+        }
+    }
+
+    Ok(reply)
+}
+
+/// Fallback message, from `fallback_messages` in the config if provided.
+async fn answer_fallback(bot: &Bot, msg: &Message, config: &BotConfig) -> BotResult<()> {
     // Only answer fallback if the message is a private message.
     if !matches!(msg.chat.type_field, ChatType::Private) {
         return Ok(());
     }
     // Choose a pseudo-random message from the fallback messages.
-    let idx = msg.message_id.unsigned_abs() as usize % FALLBACK_MESSAGES.len();
-    let reply_msg = FALLBACK_MESSAGES[idx];
+    let idx = msg.message_id.unsigned_abs() as usize;
+    let reply_msg = if config.fallback_messages.is_empty() {
+        FALLBACK_MESSAGES[idx % FALLBACK_MESSAGES.len()].to_string()
+    } else {
+        config.fallback_messages[idx % config.fallback_messages.len()].clone()
+    };
 
-    reply(bot, msg, reply_msg.to_string()).await
+    reply(bot, msg, reply_msg).await
 }
 
 /// Reply to the message.
@@ -287,8 +483,15 @@ async fn reply(bot: &Bot, msg: &Message, text: String) -> BotResult<()> {
     Ok(())
 }
 
-/// Insert given sticker to database.
-async fn insert_sticker(db: Arc<Mutex<Database>>, api: &ApiClient, file_id: String, description: String) -> Result<String, String> {
+/// Insert given sticker to database, rebuilding the ANN index if enabled so that it can be found.
+async fn insert_sticker(
+    db: &Databases,
+    api: &ApiClient,
+    file_id: String,
+    description: String,
+    emojis: Option<String>,
+    config: &BotConfig,
+) -> Result<String, String> {
     let Ok(raw_embedding) = api.embed(&description).await else {
         return Err("Failed to embed the description".to_string());
     };
@@ -299,11 +502,127 @@ async fn insert_sticker(db: Arc<Mutex<Database>>, api: &ApiClient, file_id: Stri
         file_path: format!("tg-sticker://{file_id}"),
         file_id: Some(file_id),
         label: description,
+        mtime: None,
+        emojis,
     };
-    let mut db = db.lock().await;
-    if let Err(e) = db.insert(record).await {
+    let inserted = db.writer.lock().await.insert(record).await;
+    if let Err(e) = inserted {
         Err(format!("Failed to insert record: {e}"))
     } else {
+        db.rebuild_ann_index(config).await;
         Ok("Successfully inserted sticker.".to_string())
     }
 }
+
+/// Whether a character is pictographic, i.e. can be an emoji by itself, like 😹, ❤ or ©. Approximates the `Extended_Pictographic` Unicode property.
+const fn is_pictographic(c: char) -> bool {
+    matches!(
+        c,
+        '\u{A9}'
+            | '\u{AE}'
+            | '\u{203C}'
+            | '\u{2049}'
+            | '\u{2122}'
+            | '\u{2139}'
+            | '\u{2194}'..='\u{2199}'
+            | '\u{21A9}'..='\u{21AA}'
+            | '\u{231A}'..='\u{231B}'
+            | '\u{2328}'
+            | '\u{23CF}'
+            | '\u{23E9}'..='\u{23F3}'
+            | '\u{23F8}'..='\u{23FA}'
+            | '\u{24C2}'
+            | '\u{25AA}'..='\u{25AB}'
+            | '\u{25B6}'
+            | '\u{25C0}'
+            | '\u{25FB}'..='\u{25FE}'
+            | '\u{2600}'..='\u{27BF}'
+            | '\u{2934}'..='\u{2935}'
+            | '\u{2B05}'..='\u{2B07}'
+            | '\u{2B1B}'..='\u{2B1C}'
+            | '\u{2B50}'
+            | '\u{2B55}'
+            | '\u{3030}'
+            | '\u{303D}'
+            | '\u{3297}'
+            | '\u{3299}'
+            | '\u{1F000}'..='\u{1FAFF}'
+    )
+}
+
+/// Whether a word consists of emojis only, including sequences like 👍🏽, 🏳️‍🌈 or keycaps like 1️⃣.
+fn is_emoji(word: &str) -> bool {
+    let keycap = word.contains('\u{20E3}');
+    let mut base = false;
+    for c in word.chars() {
+        if is_pictographic(c) || (keycap && matches!(c, '0'..='9' | '#' | '*')) {
+            base = true;
+        } else if !matches!(
+            c,
+            // Joiner, variation selector, keycap and tags, as in 🏴󠁧󠁢󠁳󠁣󠁴󠁿
+            '\u{200D}' | '\u{FE0F}' | '\u{20E3}' | '\u{E0020}'..='\u{E007F}'
+        ) {
+            return false;
+        }
+    }
+    base
+}
+
+/// Split leading emojis off the text, e.g. `😹 😼 crying cat` gives `(["😹", "😼"], "crying cat")`. Emojis must be separated by spaces, and words like `-` that aren't emojis are kept in the text, see [`is_emoji`].
+fn split_emojis(text: &str) -> (Vec<&str>, &str) {
+    let mut emojis = Vec::new();
+    let mut rest = text.trim_start();
+    while let Some(word) = rest.split_whitespace().next()
+        && is_emoji(word)
+    {
+        emojis.push(word);
+        rest = rest[word.len()..].trim_start();
+    }
+    (emojis, rest)
+}
+
+/// Delete given sticker from database, rebuilding the ANN index if enabled so that it is no longer found.
+async fn delete_sticker(
+    db: &Databases,
+    file_id: &str,
+    config: &BotConfig,
+) -> Result<String, String> {
+    let deleted = db.writer.lock().await.delete_by_file_id(file_id).await;
+    match deleted {
+        Ok(0) => Err("No matching sticker found in database.".to_string()),
+        Ok(count) => {
+            db.rebuild_ann_index(config).await;
+            Ok(format!("Successfully deleted {count} record(s)."))
+        }
+        Err(e) => Err(format!("Failed to delete record: {e}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_leading_emojis() {
+        assert_eq!(
+            split_emojis("😹 😼 crying cat"),
+            (vec!["😹", "😼"], "crying cat")
+        );
+        assert_eq!(split_emojis("  😹"), (vec!["😹"], ""));
+        assert_eq!(split_emojis("crying cat"), (vec![], "crying cat"));
+        // Sequences with modifiers, joiners, tags or keycaps
+        assert_eq!(
+            split_emojis("1️⃣ #️⃣ 👍🏽 ❤️ 🏳️‍🌈 🏴󠁧󠁢󠁳󠁣󠁴󠁿 🇯🇵 first"),
+            (vec!["1️⃣", "#️⃣", "👍🏽", "❤️", "🏳️‍🌈", "🏴󠁧󠁢󠁳󠁣󠁴󠁿", "🇯🇵"], "first")
+        );
+    }
+
+    #[test]
+    fn split_no_emojis() {
+        // Punctuation isn't taken for emojis, which Telegram would reject
+        assert_eq!(split_emojis("- crying cat"), (vec![], "- crying cat"));
+        assert_eq!(split_emojis("... meh"), (vec![], "... meh"));
+        assert_eq!(split_emojis("1 cat"), (vec![], "1 cat"));
+        assert_eq!(split_emojis("😹cat"), (vec![], "😹cat"));
+    }
+}
@@ -4,20 +4,48 @@ mod common;
 mod inline;
 mod message;
 
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
 use crate::{Config, config::BotConfig, util::Database};
 use anyhow::{Context, Result};
 use argh::FromArgs;
 use frankenstein::{
-    AsyncTelegramApi, Error, client_reqwest::Bot, methods::GetUpdatesParams, updates::UpdateContent,
+    AsyncTelegramApi, Error, client_reqwest::Bot, methods::GetUpdatesParams, types::User,
+    updates::UpdateContent,
 };
 use log::{debug, error, info};
-use semantic_search::ApiClient;
-use tokio::sync::Mutex;
+use semantic_search::{ApiClient, SenseError};
+use tokio::{
+    sync::{Mutex, RwLock},
+    task::JoinHandle,
+};
 
 type BotResult<T> = Result<T, Error>;
 
+/// Databases shared by handlers, so that searches run concurrently, while changes are made one at a time.
+struct Databases {
+    /// Read-only database for searches, holding the ANN index if enabled, which is only locked for writing to rebuild it
+    reader: RwLock<Database>,
+    /// Writable database for changes, like added stickers or their file ids
+    writer: Mutex<Database>,
+}
+
+impl Databases {
+    /// Rebuild the ANN index of the reader, if enabled, to pick up changes made through the writer. Committed changes are visible to searches right away otherwise.
+    async fn rebuild_ann_index(&self, config: &BotConfig) {
+        if config.ann_index
+            && let Err(e) = self
+                .reader
+                .write()
+                .await
+                .build_ann_index(config.ann_probes)
+                .await
+        {
+            error!("Failed to rebuild ANN index: {e}");
+        }
+    }
+}
+
 /// start Telegram bot
 #[derive(FromArgs, PartialEq, Eq, Debug)]
 #[argh(subcommand, name = "tg", help_triggers("-h", "--help"))]
@@ -37,40 +65,80 @@ impl Telegram {
     /// 3. The leaked memory is small and will be freed when the program exits
     /// 4. It avoids the need to clone or `Arc` the objects
     pub async fn execute(&self, config: Config) -> Result<()> {
-        let mut db = Database::open(".sense/index.db3", false)
+        let path = &config.database.path;
+        let writer = Database::open(path, false, config.database.wal)
             .await
             .with_context(|| "Failed to open database, consider indexing first.")?;
-        let api = ApiClient::new(&config.api.key, config.api.model)?;
+        // Opened after the writable one, which creates or upgrades the database
+        let reader = Database::open(path, true, config.database.wal)
+            .await
+            .with_context(|| "Failed to open database for searching.")?;
+        let mut db = Databases {
+            reader: RwLock::new(reader),
+            writer: Mutex::new(writer),
+        };
+        let api = config.api.client()?;
 
         let token = &config.bot.token;
         if token.is_empty() {
             anyhow::bail!("No token provided for the Telegram bot.");
         }
         let bot = Bot::new(token); // TODO: throttle
-        let me = bot.get_me().await?.result;
+        // Validate both credentials up front, so that the bot doesn't run without being able to answer
+        let me = match bot.get_me().await {
+            Ok(response) => response.result,
+            Err(Error::Api(e)) => anyhow::bail!("Telegram token invalid: {}", e.description),
+            Err(e) => anyhow::bail!("Failed to reach Telegram: {e}"),
+        };
         info!("Bot username: {:?}", me.username);
+        match api.embed("Hello, world!").await {
+            Ok(_) => {}
+            Err(SenseError::ApiError { status, message })
+                if status.as_u16() == 401 || status.as_u16() == 403 =>
+            {
+                anyhow::bail!("Embedding API key invalid: {message}");
+            }
+            Err(e) => anyhow::bail!("Failed to make an embedding request: {e}"),
+        }
 
         // Set commands
         info!("Setting commands...");
         message::set_commands(&bot).await?;
 
         // Upload stickers
-        info!("Initializing stickers...");
-        let init_result = common::init_stickers(&bot, &me, &mut db, &config.bot).await;
-        if let Err(e) = init_result {
-            db.close().await?;
-            anyhow::bail!("Failed to initialize stickers: {e}");
+        if config.bot.lazy_upload {
+            info!("Stickers will be uploaded on demand, start handling updates...");
+        } else {
+            info!("Initializing stickers...");
+            let init_result = common::init_stickers(&bot, &me, &db.writer, &config.bot).await;
+            if let Err(e) = init_result {
+                db.writer.into_inner().close().await?;
+                anyhow::bail!("Failed to initialize stickers: {e}");
+            }
+            info!("Initialized stickers, start handling updates...");
+        }
+
+        if config.bot.ann_index {
+            info!("Building ANN index...");
+            let num_clusters = db
+                .reader
+                .get_mut()
+                .build_ann_index(config.bot.ann_probes)
+                .await?;
+            info!("Built ANN index with {num_clusters} cluster(s)");
         }
-        info!("Initialized stickers, start handling updates...");
 
         // Leaking `api`, `bot`, `me` and `bot_config` here
-        let bot = Box::leak(Box::new(bot));
-        let me = Box::leak(Box::new(me));
-        let api = Box::leak(Box::new(api));
-        let bot_config = Box::leak(Box::new(config.bot));
+        let bot: &Bot = Box::leak(Box::new(bot));
+        let me: &User = Box::leak(Box::new(me));
+        let api: &ApiClient = Box::leak(Box::new(api));
+        let bot_config: &BotConfig = Box::leak(Box::new(config.bot));
         let whitelist = &bot_config.whitelist;
 
-        let db = Arc::new(Mutex::new(db));
+        let db = Arc::new(db);
+        let debounce = Duration::from_millis(bot_config.debounce);
+        // Pending inline query handlers, keyed by user id
+        let mut pending: HashMap<u64, JoinHandle<()>> = HashMap::new();
         let mut update_params = GetUpdatesParams::builder().build();
         loop {
             match bot.get_updates(&update_params).await {
@@ -104,13 +172,20 @@ impl Telegram {
                                     continue;
                                 }
 
-                                tokio::spawn(inline::inline_handler(
-                                    bot,
-                                    query,
-                                    db.clone(),
-                                    api,
-                                    bot_config,
-                                ));
+                                // Forget handlers that are done, so that the map doesn't grow with every user
+                                pending.retain(|_, handle| !handle.is_finished());
+                                let db = db.clone();
+                                let handle = tokio::spawn(async move {
+                                    tokio::time::sleep(debounce).await;
+                                    // Detached, so that it can't be cancelled halfway, e.g. while uploading stickers
+                                    tokio::spawn(inline::inline_handler(
+                                        bot, me, query, db, api, bot_config,
+                                    ));
+                                });
+                                // Cancel the previous query from the same user, if still pending
+                                if let Some(previous) = pending.insert(sender, handle) {
+                                    previous.abort();
+                                }
                             }
                             _ => {}
                         }
@@ -1,50 +1,356 @@
 //! Configuration file parser.
 
+use crate::CliError;
 use anyhow::Result as AnyResult;
-use std::path::Path;
+use log::warn;
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
-use semantic_search::Model;
+use semantic_search::{ApiClient, DEFAULT_BASE_URL, Model};
 use serde::Deserialize;
+use toml::Table;
 
-/// Structure of the configuration file.
-#[derive(Deserialize, Debug)]
+/// Default number of results for `search`, `similar` and the Telegram bot.
+pub const DEFAULT_NUM_RESULTS: usize = 8;
+
+/// Environment variable holding the API key, if neither `api.key` nor `api.key_env` provides one.
+pub const API_KEY_ENV: &str = "SENSE_API_KEY";
+
+/// Current version of the configuration format.
+pub const CONFIG_VERSION: u32 = 2;
+
+/// Options added to the configuration format, by the version introducing them. New options go under a new version, bumping [`CONFIG_VERSION`].
+const CONFIG_CHANGES: [(u32, &[&str]); 2] = [
+    (
+        1,
+        &[
+            "version",
+            "api.key_env",
+            "api.proxy",
+            "api.base_url",
+            "search.num_results",
+            "server.token",
+            "database.path",
+            "database.wal",
+            "bot.debounce",
+            "bot.min_query_len",
+            "bot.fallback_messages",
+            "bot.empty_query_title",
+            "bot.empty_query_text",
+            "bot.lazy_upload",
+            "bot.keep_stickers",
+            "bot.ann_index",
+            "bot.ann_probes",
+        ],
+    ),
+    (
+        2,
+        &[
+            "index.include_hidden",
+            "index.roots",
+            "server.bind",
+            "server.metrics",
+            "server.tls_cert",
+            "server.tls_key",
+            "bot.emoji_map",
+            "bot.max_document_size",
+        ],
+    ),
+];
+
+/// Structure of the configuration file. Every section is optional, so commands that only touch the database work without a configuration file.
+#[derive(Deserialize, Debug, Clone, Default)]
 pub struct Config {
+    /// Version of the configuration format the file was written for. Missing means before versioning was introduced.
+    pub version: Option<u32>,
+    /// Path of the configuration file, if read from one.
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
     /// Server configuration.
     #[serde(default)]
     pub server: Server,
     /// API configuration.
+    #[serde(default)]
     pub api: ApiConfig,
+    /// Search configuration.
+    #[serde(default)]
+    pub search: SearchConfig,
+    /// Indexing configuration.
+    #[serde(default)]
+    pub index: IndexConfig,
     /// Telegram bot configuration.
     #[serde(default)]
     pub bot: BotConfig,
+    /// Database configuration.
+    #[serde(default)]
+    pub database: DatabaseConfig,
+    /// Unknown keys and sections.
+    #[serde(flatten)]
+    pub unknown: Table,
+}
+
+impl Config {
+    /// Warning about the configuration version, if it is older or newer than [`CONFIG_VERSION`], listing the options added since an older one.
+    #[must_use]
+    pub fn version_warning(&self) -> Option<String> {
+        let version = self.version.unwrap_or(0);
+        if version > CONFIG_VERSION {
+            return Some(format!(
+                "Config version {version} is newer than the supported version {CONFIG_VERSION}, consider upgrading sense"
+            ));
+        }
+        let added: Vec<_> = CONFIG_CHANGES
+            .iter()
+            .filter(|(since, _)| *since > version)
+            .flat_map(|(_, keys)| keys.iter().map(|key| format!("`{key}`")))
+            .collect();
+        (!added.is_empty()).then(|| {
+            format!(
+                "Config is written for version {version}, so options added since then use their defaults: {}. Set `version = {CONFIG_VERSION}` at the top of the config to silence this warning",
+                added.join(", ")
+            )
+        })
+    }
+
+    /// Keys that are not recognized, like `bot.tokn`, sorted.
+    #[must_use]
+    pub fn unknown_keys(&self) -> Vec<String> {
+        let sections = [
+            ("server", &self.server.unknown),
+            ("api", &self.api.unknown),
+            ("search", &self.search.unknown),
+            ("index", &self.index.unknown),
+            ("bot", &self.bot.unknown),
+            ("database", &self.database.unknown),
+        ];
+        let mut keys: Vec<_> = self.unknown.keys().cloned().collect();
+        for (section, unknown) in sections {
+            keys.extend(unknown.keys().map(|key| format!("{section}.{key}")));
+        }
+        keys.sort();
+        keys
+    }
 }
 
 /// Server configuration.
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
 #[serde(default)]
 pub struct Server {
-    /// Port for the server. Default is 8080.
+    /// Address to listen on. Default is `127.0.0.1`, so the server is only reachable locally.
+    pub bind: BindAddress,
+    /// Port for the server, unless given by `bind`. Default is 8080.
     pub port: u16,
+    /// Bearer token required for `POST /reindex`. If not set, or empty, reindexing over HTTP is disabled.
+    pub token: Option<String>,
+    /// Whether to expose Prometheus metrics at `GET /metrics`, if built with the `metrics` feature. Default is `false`.
+    pub metrics: bool,
+    /// Path to the PEM certificate chain for HTTPS. Requires `tls_key`.
+    pub tls_cert: Option<PathBuf>,
+    /// Path to the PEM private key for HTTPS. Requires `tls_cert`.
+    pub tls_key: Option<PathBuf>,
+    /// Unknown keys.
+    #[serde(flatten)]
+    pub unknown: Table,
 }
 
 impl Default for Server {
     fn default() -> Self {
-        Self { port: 8080 }
+        Self {
+            bind: BindAddress::default(),
+            port: 8080,
+            token: None,
+            metrics: false,
+            tls_cert: None,
+            tls_key: None,
+            unknown: Table::new(),
+        }
+    }
+}
+
+impl Server {
+    /// Socket address to listen on, with `bind` and the port overridden by the given values. The port is the first of `port`, that of the bind address, and `server.port`.
+    #[must_use]
+    pub fn address(&self, bind: Option<BindAddress>, port: Option<u16>) -> SocketAddr {
+        let bind = bind.unwrap_or(self.bind);
+        let port = port.or(bind.port).unwrap_or(self.port);
+        SocketAddr::new(bind.ip, port)
+    }
+
+    /// Paths to the certificate chain and private key for HTTPS, or `None` to serve plain HTTP.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if only one of `tls_cert` and `tls_key` is set.
+    pub fn tls(&self) -> AnyResult<Option<(&Path, &Path)>> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => Ok(Some((cert, key))),
+            (None, None) => Ok(None),
+            (Some(_), None) => {
+                Err(CliError::config("`server.tls_cert` is set without `server.tls_key`").into())
+            }
+            (None, Some(_)) => {
+                Err(CliError::config("`server.tls_key` is set without `server.tls_cert`").into())
+            }
+        }
+    }
+}
+
+/// Address for the server to listen on, like `127.0.0.1`, or with a port like `0.0.0.0:9000` or `[::1]:9000`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct BindAddress {
+    /// IP address of the interface
+    pub ip: IpAddr,
+    /// Port, if given
+    pub port: Option<u16>,
+}
+
+impl Default for BindAddress {
+    fn default() -> Self {
+        Self {
+            ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: None,
+        }
+    }
+}
+
+impl FromStr for BindAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(address) = s.parse::<SocketAddr>() {
+            return Ok(Self {
+                ip: address.ip(),
+                port: Some(address.port()),
+            });
+        }
+        s.parse().map(|ip| Self { ip, port: None }).map_err(|_| {
+            format!(
+                "Invalid bind address `{s}`, expected an IP address like `127.0.0.1`, optionally with a port like `0.0.0.0:9000`"
+            )
+        })
+    }
+}
+
+impl TryFrom<String> for BindAddress {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
     }
 }
 
 /// API configuration.
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone, Default)]
 pub struct ApiConfig {
-    /// API key for Silicon Cloud.
+    /// API key for Silicon Cloud. If empty, it is read from the environment when needed, see [`ApiConfig::resolve_key`].
+    #[serde(default)]
     pub key: String,
+    /// Name of an environment variable holding the API key, used if `key` is empty.
+    #[serde(default)]
+    pub key_env: Option<String>,
     /// Model to use for embedding.
     #[serde(default)]
     pub model: Model,
+    /// Proxy for API requests, overriding the `HTTP_PROXY` and `HTTPS_PROXY` environment variables.
+    #[serde(default)]
+    pub proxy: Option<String>,
+    /// Base URL of an OpenAI-compatible API to use instead of Silicon Cloud, like `https://api.openai.com/v1`.
+    #[serde(default)]
+    pub base_url: Option<String>,
+    /// Unknown keys.
+    #[serde(flatten)]
+    pub unknown: Table,
+}
+
+impl ApiConfig {
+    /// Resolve the API key using `var` to read environment variables. The precedence is: `key`, then the variable named by `key_env`, then [`API_KEY_ENV`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if none of them provides a non-empty key.
+    pub fn resolve_key<F>(&self, var: F) -> AnyResult<String>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        if !self.key.is_empty() {
+            return Ok(self.key.clone());
+        }
+        let found = self
+            .key_env
+            .iter()
+            .map(String::as_str)
+            .chain(std::iter::once(API_KEY_ENV))
+            .filter_map(var)
+            .find(|key| !key.is_empty());
+        found.ok_or_else(|| {
+            let named = self
+                .key_env
+                .as_ref()
+                .map(|name| format!(", `{name}` (`api.key_env`)"))
+                .unwrap_or_default();
+            CliError::config(format!(
+                "No API key found, set `api.key` in the config{named} or `{API_KEY_ENV}`"
+            ))
+            .into()
+        })
+    }
+
+    /// Create an API client from this configuration. The API key is only resolved here, so commands that don't call the API don't need one.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if no API key is found, the API key is malformed, or the proxy or base URL is invalid.
+    pub fn client(&self) -> AnyResult<ApiClient> {
+        let key = self.resolve_key(|name| std::env::var(name).ok())?;
+        Ok(ApiClient::with_base_url(
+            &key,
+            &self.model,
+            self.proxy.as_deref(),
+            self.base_url.as_deref().unwrap_or(DEFAULT_BASE_URL),
+        )?)
+    }
+}
+
+/// Search configuration.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SearchConfig {
+    /// Number of results to show when not given on the command line.
+    pub num_results: usize,
+    /// Unknown keys.
+    #[serde(flatten)]
+    pub unknown: Table,
+}
+
+impl Default for SearchConfig {
+    fn default() -> Self {
+        Self {
+            num_results: DEFAULT_NUM_RESULTS,
+            unknown: Table::new(),
+        }
+    }
+}
+
+/// Indexing configuration.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct IndexConfig {
+    /// Whether to index hidden files and directories, whose names start with `.`. Default is `false`.
+    pub include_hidden: bool,
+    /// Other roots under which files of the index may live, e.g. another copy or mount of the indexed directory. Records are only removed as deleted if their file exists under neither the root of the index nor any of these.
+    pub roots: Vec<PathBuf>,
+    /// Unknown keys.
+    #[serde(flatten)]
+    pub unknown: Table,
 }
 
 /// Telegram bot configuration.
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
 #[serde(default)]
 pub struct BotConfig {
     /// Telegram bot token.
@@ -59,6 +365,31 @@ pub struct BotConfig {
     pub num_results: usize,
     /// Postscript to be appended after the help message.
     pub postscript: String,
+    /// Debounce interval for inline queries, in milliseconds.
+    pub debounce: u64,
+    /// Minimum number of characters in an inline query before searching. Default is 2.
+    pub min_query_len: usize,
+    /// Replies to unrecognized messages, one picked per message. Default is the built-in cat sounds.
+    pub fallback_messages: Vec<String>,
+    /// Title of the article shown for short inline queries. Default is the built-in one.
+    pub empty_query_title: String,
+    /// Text of the article shown for short inline queries. Default is the built-in one.
+    pub empty_query_text: String,
+    /// Upload stickers on demand when they first appear in results, instead of all at startup.
+    pub lazy_upload: bool,
+    /// Keep uploaded stickers in numbered sticker sets, instead of emptying the sticker set after each batch.
+    pub keep_stickers: bool,
+    /// Search with an in-memory approximate nearest-neighbor index built at startup, instead of scanning all embeddings.
+    pub ann_index: bool,
+    /// Number of clusters searched per query when `ann_index` is enabled. Default is 16.
+    pub ann_probes: usize,
+    /// Emojis for stickers whose label contains a keyword, as space-separated emojis by keyword.
+    pub emoji_map: BTreeMap<String, String>,
+    /// Largest file sent as a document when the best match of `/search` has no sticker, in bytes, or `0` to never send one. Default is 50 MB, the limit of Telegram.
+    pub max_document_size: u64,
+    /// Unknown keys.
+    #[serde(flatten)]
+    pub unknown: Table,
 }
 
 impl Default for BotConfig {
@@ -67,9 +398,64 @@ impl Default for BotConfig {
             token: String::new(),
             owner: 0,
             whitelist: Vec::new(),
-            num_results: 8,
+            num_results: DEFAULT_NUM_RESULTS,
             sticker_set: "meme".to_string(),
             postscript: String::new(),
+            debounce: 300,
+            min_query_len: 2,
+            fallback_messages: Vec::new(),
+            empty_query_title: String::new(),
+            empty_query_text: String::new(),
+            lazy_upload: false,
+            keep_stickers: false,
+            ann_index: false,
+            ann_probes: 16,
+            emoji_map: BTreeMap::new(),
+            max_document_size: 50_000_000,
+            unknown: Table::new(),
+        }
+    }
+}
+
+impl BotConfig {
+    /// Emojis for a sticker with the given label, from every keyword in `emoji_map` the label contains, case-insensitively. Duplicates are removed.
+    #[must_use]
+    pub fn emojis_for(&self, label: &str) -> Vec<&str> {
+        let label = label.to_lowercase();
+        let mut emojis = Vec::new();
+        for (keyword, mapped) in &self.emoji_map {
+            if !label.contains(&keyword.to_lowercase()) {
+                continue;
+            }
+            for emoji in mapped.split_whitespace() {
+                if !emojis.contains(&emoji) {
+                    emojis.push(emoji);
+                }
+            }
+        }
+        emojis
+    }
+}
+
+/// Database configuration.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct DatabaseConfig {
+    /// Path to the SQLite database. Default is `.sense/index.db3`.
+    pub path: PathBuf,
+    /// Whether to use WAL mode with `synchronous=NORMAL`. Default is `true`.
+    pub wal: bool,
+    /// Unknown keys.
+    #[serde(flatten)]
+    pub unknown: Table,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from(".sense/index.db3"),
+            wal: true,
+            unknown: Table::new(),
         }
     }
 }
@@ -83,17 +469,31 @@ fn parse_config_from_str(content: &str) -> Result<Config, toml::de::Error> {
     toml::from_str(content)
 }
 
-/// Parse the configuration file into a `Config` structure.
+/// Parse the configuration file into a `Config` structure, warning if its version is outdated or it has unknown keys.
 ///
 /// # Errors
 ///
-/// Returns an [IO error](std::io::Error) if reading fails, or a [TOML error](toml::de::Error) if parsing fails.
-pub fn parse_config<T>(path: T) -> AnyResult<Config>
+/// Returns an [IO error](std::io::Error) if reading fails, a [TOML error](toml::de::Error) if parsing fails, or an error listing unknown keys if `strict` is set.
+pub fn parse_config<T>(path: T, strict: bool) -> AnyResult<Config>
 where
     T: AsRef<Path>,
 {
-    let content = std::fs::read_to_string(path)?;
-    Ok(parse_config_from_str(&content)?)
+    let content = std::fs::read_to_string(&path)?;
+    let mut config = parse_config_from_str(&content)?;
+    config.path = Some(path.as_ref().to_path_buf());
+    if let Some(warning) = config.version_warning() {
+        warn!("{warning}");
+    }
+    let unknown = config.unknown_keys();
+    if !unknown.is_empty() {
+        let unknown = unknown.join(", ");
+        if strict {
+            return Err(CliError::config(format!("Unknown config key(s): {unknown}")).into());
+        }
+        warn!("Ignoring unknown config key(s), check for typos: {unknown}");
+    }
+
+    Ok(config)
 }
 
 #[cfg(test)]
@@ -129,6 +529,83 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parse_config_server_token() {
+        let content = r#"
+            [api]
+            key = "test_key"
+        "#;
+        let config = parse_config_from_str(content).unwrap();
+        assert_eq!(config.server.token, None);
+        assert!(!config.server.metrics);
+
+        let content = r#"
+            [server]
+            token = "secret"
+            metrics = true
+
+            [api]
+            key = "test_key"
+        "#;
+        let config = parse_config_from_str(content).unwrap();
+        assert_eq!(config.server.token.as_deref(), Some("secret"));
+        assert!(config.server.metrics);
+    }
+
+    #[test]
+    fn parse_config_server_bind() {
+        let config = parse_config_from_str("").unwrap();
+        let address = config.server.address(None, None);
+        assert_eq!(address, "127.0.0.1:8080".parse().unwrap());
+
+        let content = r#"
+            [server]
+            bind = "0.0.0.0"
+            port = 9000
+        "#;
+        let config = parse_config_from_str(content).unwrap();
+        let address = config.server.address(None, None);
+        assert_eq!(address, "0.0.0.0:9000".parse().unwrap());
+
+        // The port of the bind address takes precedence over `server.port`, but not `--port`
+        let bind = Some("[::1]:9001".parse().unwrap());
+        let address = config.server.address(bind, None);
+        assert_eq!(address, "[::1]:9001".parse().unwrap());
+        let address = config.server.address(bind, Some(9002));
+        assert_eq!(address, "[::1]:9002".parse().unwrap());
+
+        assert!("localhost:9000".parse::<BindAddress>().is_err());
+        let content = r#"
+            [server]
+            bind = "0.0.0.0:port"
+        "#;
+        assert!(parse_config_from_str(content).is_err());
+    }
+
+    #[test]
+    fn parse_config_server_tls() {
+        let config = parse_config_from_str("").unwrap();
+        assert!(config.server.tls().unwrap().is_none());
+
+        let content = r#"
+            [server]
+            tls_cert = "cert.pem"
+            tls_key = "key.pem"
+        "#;
+        let config = parse_config_from_str(content).unwrap();
+        let (cert, key) = config.server.tls().unwrap().unwrap();
+        assert_eq!(cert, Path::new("cert.pem"));
+        assert_eq!(key, Path::new("key.pem"));
+
+        // Only one of them is an error
+        let content = r#"
+            [server]
+            tls_cert = "cert.pem"
+        "#;
+        let config = parse_config_from_str(content).unwrap();
+        assert!(config.server.tls().is_err());
+    }
+
     #[test]
     fn parse_config_2() {
         let content = r#"
@@ -178,21 +655,292 @@ mod tests {
     }
 
     #[test]
-    #[should_panic(expected = "missing field `api`")]
-    fn parse_config_fail_1() {
+    fn parse_config_custom_model() {
+        let content = r#"
+            [api]
+            key = "test_key"
+            model = "my/model"
+        "#;
+        test(
+            content,
+            8080,
+            "test_key",
+            Model::Custom("my/model".to_string()),
+            "",
+        );
+    }
+
+    #[test]
+    fn parse_config_proxy() {
+        let content = r#"
+            [api]
+            key = "test_key"
+        "#;
+        let config = parse_config_from_str(content).unwrap();
+        assert_eq!(config.api.proxy, None);
+
+        let content = r#"
+            [api]
+            key = "test_key"
+            proxy = "http://127.0.0.1:7890"
+        "#;
+        let config = parse_config_from_str(content).unwrap();
+        assert_eq!(config.api.proxy.as_deref(), Some("http://127.0.0.1:7890"));
+    }
+
+    #[test]
+    fn parse_config_base_url() {
+        let content = r#"
+            [api]
+            key = "sk-proj-abc"
+            model = "text-embedding-3-small"
+            base_url = "https://api.openai.com/v1"
+        "#;
+        let config = parse_config_from_str(content).unwrap();
+        assert_eq!(
+            config.api.base_url.as_deref(),
+            Some("https://api.openai.com/v1")
+        );
+        config.api.client().unwrap();
+
+        let content = r#"
+            [api]
+            key = "sk-proj-abc"
+            base_url = "not a url"
+        "#;
+        let error = parse_config_from_str(content)
+            .unwrap()
+            .api
+            .client()
+            .err()
+            .unwrap();
+        assert_eq!(CliError::from(error).exit_code(), 2);
+    }
+
+    #[test]
+    fn parse_config_index_roots() {
+        let config = parse_config_from_str("").unwrap();
+        assert!(config.index.roots.is_empty());
+        let content = r#"
+            [index]
+            roots = ["/mnt/stickers", "backup"]
+        "#;
+        let config = parse_config_from_str(content).unwrap();
+        assert_eq!(
+            config.index.roots,
+            [PathBuf::from("/mnt/stickers"), PathBuf::from("backup")]
+        );
+        assert!(config.unknown_keys().is_empty());
+    }
+
+    #[test]
+    fn parse_config_database() {
+        let content = r#"
+            [api]
+            key = "test_key"
+        "#;
+        let config = parse_config_from_str(content).unwrap();
+        assert_eq!(config.database.path, Path::new(".sense/index.db3"));
+        assert!(config.database.wal);
+
+        let content = r#"
+            [api]
+            key = "test_key"
+
+            [database]
+            path = "docs.db3"
+            wal = false
+        "#;
+        let config = parse_config_from_str(content).unwrap();
+        assert_eq!(config.database.path, Path::new("docs.db3"));
+        assert!(!config.database.wal);
+    }
+
+    #[test]
+    fn parse_config_search() {
+        let content = r#"
+            [api]
+            key = "test_key"
+        "#;
+        let config = parse_config_from_str(content).unwrap();
+        assert_eq!(config.search.num_results, DEFAULT_NUM_RESULTS);
+        assert_eq!(config.bot.num_results, DEFAULT_NUM_RESULTS);
+
+        let content = r#"
+            [api]
+            key = "test_key"
+
+            [search]
+            num_results = 20
+        "#;
+        let config = parse_config_from_str(content).unwrap();
+        assert_eq!(config.search.num_results, 20);
+    }
+
+    #[test]
+    fn parse_config_without_api() {
         let content = r"
             [server]
             port = 8080
         ";
-        test(content, 8080, "test_key", Model::BgeLargeZhV1_5, "");
+        test(content, 8080, "", Model::BgeLargeZhV1_5, "");
+        let empty = parse_config_from_str("").unwrap();
+        assert!(empty.api.resolve_key(|_| None).is_err());
     }
 
     #[test]
-    #[should_panic(expected = "missing field `key`")]
+    #[should_panic(expected = "invalid type: integer")]
     fn parse_config_fail_2() {
         let content = r"
             [api]
+            key = 1234
         ";
         test(content, 8080, "test_key", Model::BgeLargeZhV1_5, "");
     }
+
+    #[test]
+    fn emoji_map() {
+        let content = r#"
+            [bot.emoji_map]
+            happy = "😄"
+            cat = "🐱 😺"
+            Cry = "😢"
+        "#;
+        let config = parse_config_from_str(content).unwrap();
+        assert!(config.unknown_keys().is_empty());
+        let bot = &config.bot;
+        assert_eq!(bot.emojis_for("A HAPPY cat"), ["🐱", "😺", "😄"]);
+        assert_eq!(bot.emojis_for("crying"), ["😢"]);
+
+        let bot = BotConfig::default();
+        assert!(bot.emojis_for("happy").is_empty());
+    }
+
+    #[test]
+    fn unknown_keys() {
+        let content = r#"
+            typo = 1
+            [bot]
+            tokn = "test_token"
+            num_results = 4
+            [api]
+            key = "test_key"
+            mdoel = "BAAI/bge-m3"
+            [extra]
+        "#;
+        let config = parse_config_from_str(content).unwrap();
+        assert_eq!(config.bot.num_results, 4);
+        assert_eq!(config.api.model, Model::default());
+        assert_eq!(
+            config.unknown_keys(),
+            ["api.mdoel", "bot.tokn", "extra", "typo"]
+        );
+
+        let config = parse_config_from_str("[api]\nkey = \"test_key\"").unwrap();
+        assert!(config.unknown_keys().is_empty());
+    }
+
+    #[test]
+    fn version_warning() {
+        let config = parse_config_from_str("").unwrap();
+        let warning = config.version_warning().unwrap();
+        assert!(warning.starts_with("Config is written for version 0"));
+        assert!(warning.contains("`version`, `api.key_env`"));
+        assert!(warning.contains("`bot.max_document_size`"));
+
+        let config = parse_config_from_str("version = 1").unwrap();
+        let warning = config.version_warning().unwrap();
+        assert!(!warning.contains("`api.key_env`"));
+        assert!(warning.contains("`index.roots`"));
+
+        let current = format!("version = {CONFIG_VERSION}");
+        let config = parse_config_from_str(&current).unwrap();
+        assert!(config.version_warning().is_none());
+
+        let newer = format!("version = {}", CONFIG_VERSION + 1);
+        let config = parse_config_from_str(&newer).unwrap();
+        assert!(config.version_warning().unwrap().contains("newer"));
+        assert!(config.unknown_keys().is_empty());
+    }
+
+    #[test]
+    fn config_changes_complete() {
+        // Options that predate versioning
+        let original = [
+            "api.key",
+            "api.model",
+            "server.port",
+            "bot.token",
+            "bot.owner",
+            "bot.whitelist",
+            "bot.sticker_set",
+            "bot.num_results",
+            "bot.postscript",
+        ];
+        let readme = include_str!("../README.md");
+        let sample = readme
+            .split_once("#### Sample Configuration\n\n```toml\n")
+            .and_then(|(_, rest)| rest.split_once("```"))
+            .unwrap()
+            .0;
+        let config = parse_config_from_str(sample).unwrap();
+        assert!(config.unknown_keys().is_empty());
+        assert_eq!(config.version, Some(CONFIG_VERSION));
+
+        // Every option in the sample is either original or listed as a change
+        let sample: Table = toml::from_str(sample).unwrap();
+        for (key, value) in &sample {
+            let keys: Vec<_> = value.as_table().map_or_else(
+                || vec![key.clone()],
+                |section| section.keys().map(|name| format!("{key}.{name}")).collect(),
+            );
+            for key in keys {
+                assert!(
+                    original.contains(&key.as_str())
+                        || CONFIG_CHANGES
+                            .iter()
+                            .any(|(_, keys)| keys.contains(&key.as_str())),
+                    "{key} is missing from CONFIG_CHANGES"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn resolve_key() {
+        let env = |name: &str| match name {
+            "MY_KEY" => Some("my_key".to_string()),
+            API_KEY_ENV => Some("sense_key".to_string()),
+            _ => None,
+        };
+        let resolve = |content: &str, var: &dyn Fn(&str) -> Option<String>| {
+            parse_config_from_str(content).unwrap().api.resolve_key(var)
+        };
+
+        // Explicit key > named env var > `SENSE_API_KEY`
+        let explicit = r#"
+            [api]
+            key = "test_key"
+            key_env = "MY_KEY"
+        "#;
+        assert_eq!(resolve(explicit, &env).unwrap(), "test_key");
+        let named = r#"
+            [api]
+            key_env = "MY_KEY"
+        "#;
+        assert_eq!(resolve(named, &env).unwrap(), "my_key");
+        let missing = r#"
+            [api]
+            key_env = "MISSING"
+        "#;
+        assert_eq!(resolve(missing, &env).unwrap(), "sense_key");
+        let empty = "[api]";
+        assert_eq!(resolve(empty, &env).unwrap(), "sense_key");
+
+        let err = resolve(missing, &|_| None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "No API key found, set `api.key` in the config, `MISSING` (`api.key_env`) or `SENSE_API_KEY`"
+        );
+    }
 }
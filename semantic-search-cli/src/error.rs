@@ -0,0 +1,145 @@
+//! Errors of the CLI, by the kind of failure.
+
+use semantic_search::SenseError;
+use std::io;
+use thiserror::Error;
+
+/// Possible errors of the CLI, each wrapping the [`anyhow::Error`] with the context added along the way.
+///
+/// Errors are classified when converted from [`anyhow::Error`], by the first cause of a known type: a [`CliError`] created where the failure is detected, a [TOML error](toml::de::Error), a [`SenseError`] or a missing database.
+#[derive(Debug, Error)]
+pub enum CliError {
+    /// The config file is missing, malformed or incomplete, e.g. without an API key.
+    #[error(transparent)]
+    Config(anyhow::Error),
+    /// The database does not exist, e.g. as nothing was indexed yet.
+    #[error(transparent)]
+    DatabaseNotFound(anyhow::Error),
+    /// The API rejected a request or returned an unexpected response, e.g. because the API key is invalid.
+    #[error(transparent)]
+    Api(anyhow::Error),
+    /// The API could not be reached.
+    #[error(transparent)]
+    Network(anyhow::Error),
+    /// Any other error.
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+/// Kind of a [`CliError`], without the error itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Config,
+    DatabaseNotFound,
+    Api,
+    Network,
+}
+
+impl CliError {
+    /// Configuration error with the given message.
+    pub fn config(message: impl Into<String>) -> Self {
+        Self::Config(anyhow::Error::msg(message.into()))
+    }
+
+    /// Exit code of the process for this error: `2` for config errors, `3` if the database is not found, `4` for API errors, `5` for network errors and `1` for others.
+    #[must_use]
+    pub const fn exit_code(&self) -> u8 {
+        match self {
+            Self::Config(_) => 2,
+            Self::DatabaseNotFound(_) => 3,
+            Self::Api(_) => 4,
+            Self::Network(_) => 5,
+            Self::Other(_) => 1,
+        }
+    }
+
+    /// Kind of this error, or `None` for [`Other`](Self::Other).
+    const fn kind(&self) -> Option<Kind> {
+        match self {
+            Self::Config(_) => Some(Kind::Config),
+            Self::DatabaseNotFound(_) => Some(Kind::DatabaseNotFound),
+            Self::Api(_) => Some(Kind::Api),
+            Self::Network(_) => Some(Kind::Network),
+            Self::Other(_) => None,
+        }
+    }
+}
+
+/// Kind of a single cause of an error, if known.
+fn kind_of(cause: &(dyn std::error::Error + 'static)) -> Option<Kind> {
+    if let Some(error) = cause.downcast_ref::<CliError>() {
+        return error.kind();
+    }
+    if cause.is::<toml::de::Error>() {
+        return Some(Kind::Config);
+    }
+    if let Some(error) = cause.downcast_ref::<SenseError>() {
+        return match error {
+            SenseError::InvalidBaseUrl { .. } => Some(Kind::Config),
+            SenseError::RequestFailed { .. } => Some(Kind::Network),
+            SenseError::ApiError { .. }
+            | SenseError::MalformedApiKey
+            | SenseError::InvalidHeaderValue
+            | SenseError::MalformedResponse { .. }
+            | SenseError::DimensionMismatch { .. } => Some(Kind::Api),
+            _ => None,
+        };
+    }
+    match cause.downcast_ref::<sqlx::Error>() {
+        Some(sqlx::Error::Io(error)) if error.kind() == io::ErrorKind::NotFound => {
+            Some(Kind::DatabaseNotFound)
+        }
+        _ => None,
+    }
+}
+
+impl From<anyhow::Error> for CliError {
+    /// Classify the error by the first cause of a known kind, see [`CliError`].
+    fn from(error: anyhow::Error) -> Self {
+        match error.chain().find_map(kind_of) {
+            Some(Kind::Config) => Self::Config(error),
+            Some(Kind::DatabaseNotFound) => Self::DatabaseNotFound(error),
+            Some(Kind::Api) => Self::Api(error),
+            Some(Kind::Network) => Self::Network(error),
+            None => Self::Other(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    fn kind(error: anyhow::Error) -> Option<Kind> {
+        CliError::from(error).kind()
+    }
+
+    #[test]
+    fn classify() {
+        let toml = toml::from_str::<toml::Table>("x").unwrap_err();
+        let error = Err::<(), _>(toml).context("Failed to parse config file");
+        assert_eq!(kind(error.unwrap_err()), Some(Kind::Config));
+
+        // Errors classified where detected keep their kind through context
+        let error = Err::<(), _>(CliError::config("No API key found")).context("Failed to search");
+        let error = CliError::from(error.unwrap_err());
+        assert_eq!(error.kind(), Some(Kind::Config));
+        assert_eq!(error.exit_code(), 2);
+        assert_eq!(format!("{error:#}"), "Failed to search: No API key found");
+
+        let error = anyhow::Error::from(SenseError::MalformedApiKey);
+        assert_eq!(kind(error), Some(Kind::Api));
+        let mismatch = SenseError::DimensionMismatch {
+            expected: 1024,
+            actual: 768,
+        };
+        assert_eq!(kind(anyhow::Error::from(mismatch)), Some(Kind::Api));
+
+        let missing = sqlx::Error::Io(io::Error::from(io::ErrorKind::NotFound));
+        let error = Err::<(), _>(missing).context("Failed to open database");
+        assert_eq!(kind(error.unwrap_err()), Some(Kind::DatabaseNotFound));
+
+        assert_eq!(kind(anyhow::anyhow!("Something went wrong")), None);
+    }
+}
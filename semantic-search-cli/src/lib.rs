@@ -8,36 +8,291 @@
 
 pub mod commands;
 mod config;
+mod error;
 mod util;
 
+use anstyle::{AnsiColor, Style};
 use anyhow::Result;
 use argh::FromArgs;
-use commands::Command;
-pub use config::{Config, parse_config};
-use log::{debug, info, warn};
+use commands::{ALIASES, Command, ScoreFormat, SearchResult};
+pub use config::{BindAddress, Config, parse_config};
+pub use error::CliError;
+use log::{LevelFilter, debug, info, warn};
+use serde_json::json;
+use std::{
+    io::{self, IsTerminal, Write},
+    path::PathBuf,
+    str::FromStr,
+};
+pub use util::find_project_dir;
 
 /// 🔎 Semantic search.
 #[derive(FromArgs, Debug)]
-#[argh(help_triggers("-h", "--help"))]
+#[argh(
+    help_triggers("-h", "--help"),
+    note = "Commands have short aliases: `s` or `q` for `search`, `i` or `idx` for `index`, `sim` for `similar` and `mv` for `rename`."
+)]
 pub struct Args {
+    /// increase logging verbosity, repeat for more (-v for debug, -v -v for trace)
+    #[argh(switch, short = 'v')]
+    pub verbose: u8,
+    /// decrease logging verbosity, repeat for less (-q for warnings, -q -q for errors only)
+    #[argh(switch, short = 'q')]
+    pub quiet: u8,
+    /// path to the config file, defaults to `.sense/config.toml` in the closest directory holding `.sense`, searching up from the working directory
+    #[argh(option, short = 'c')]
+    pub config: Option<PathBuf>,
+    /// path to the database, overriding `database.path` in the config
+    #[argh(option)]
+    pub db: Option<PathBuf>,
+    /// fail on unknown keys in the config file, e.g. typos like `tokn`
+    #[argh(switch)]
+    pub strict_config: bool,
+    /// output format of `search`, `similar`, `compare`, `embed`, `export` and the summary of `index`: `text`, `json` or `ndjson`, defaulting to what each command prints by default
+    #[argh(option)]
+    pub format: Option<OutputFormat>,
+    /// when to color the results of `search` and `similar` printed as text: `auto` (default, if stdout is a terminal and `NO_COLOR` isn't set), `always` or `never`
+    #[argh(option, default = "ColorChoice::Auto")]
+    pub color: ColorChoice,
+    /// print the version and enabled features, then exit
+    #[argh(switch, short = 'V')]
+    pub version: bool,
     /// the command to execute.
     #[argh(subcommand)]
-    pub command: Command,
+    pub command: Option<Command>,
 }
 
-/// Execute the command.
+/// Output formats of commands, given by the global `--format` flag.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum OutputFormat {
+    /// Human-readable text.
+    #[default]
+    Text,
+    /// A single JSON value, e.g. an array of results.
+    Json,
+    /// Newline-delimited JSON, e.g. one result per line.
+    Ndjson,
+}
+
+/// When to color the output of commands, given by the global `--color` flag.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum ColorChoice {
+    /// Color if stdout is a terminal and the `NO_COLOR` environment variable isn't set.
+    #[default]
+    Auto,
+    /// Always color.
+    Always,
+    /// Never color.
+    Never,
+}
+
+impl ColorChoice {
+    /// Whether to color output written to stdout.
+    #[must_use]
+    pub fn enabled(self) -> bool {
+        match self {
+            Self::Auto => {
+                // See https://no-color.org, only a non-empty value counts
+                io::stdout().is_terminal()
+                    && std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+            }
+            Self::Always => true,
+            Self::Never => false,
+        }
+    }
+}
+
+impl FromStr for ColorChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(Self::Auto),
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            _ => Err(format!(
+                "Unknown color choice `{s}`, expected `auto`, `always` or `never`"
+            )),
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            "ndjson" => Ok(Self::Ndjson),
+            _ => Err(format!(
+                "Unknown format `{s}`, expected `text`, `json` or `ndjson`"
+            )),
+        }
+    }
+}
+
+/// Global options taking a value, which may come before the command.
+const VALUE_OPTIONS: [&str; 5] = ["-c", "--config", "--db", "--format", "--color"];
+
+/// Replace the alias of the command among `args`, not including the program name, with the command it stands for, like `s` with `search`. Global options before the command and their values are skipped.
+fn expand_alias(args: &mut [String]) {
+    let mut index = 0;
+    while let Some(arg) = args.get(index) {
+        if VALUE_OPTIONS.contains(&arg.as_str()) {
+            index += 2;
+        } else if arg.starts_with('-') {
+            index += 1;
+        } else {
+            break;
+        }
+    }
+    if let Some(arg) = args.get_mut(index)
+        && let Some((_, command)) = ALIASES.iter().find(|(alias, _)| alias == arg)
+    {
+        *arg = (*command).to_string();
+    }
+}
+
+/// Move a lone `-` among `args`, including the program name, behind a `--`, as argh takes it for an unknown option otherwise, so that it can stand for stdin, like in `sense search -`. A `-` following an option is left as is, as it may be its value, and so are arguments already containing `--`.
+fn escape_stdin(args: &mut Vec<String>) {
+    if args.iter().any(|arg| arg == "--") {
+        return;
+    }
+    let position = args
+        .windows(2)
+        .position(|pair| pair[1] == "-" && !pair[0].starts_with('-'));
+    if let Some(position) = position {
+        args.remove(position + 1);
+        args.extend(["--".to_string(), "-".to_string()]);
+    }
+}
+
+impl Args {
+    /// Parse the arguments of the process like [`argh::from_env`], expanding the alias of the command, if any, see [`ALIASES`](commands::ALIASES), and accepting a lone `-` for stdin.
+    ///
+    /// Like [`argh::from_env`], this exits the process after printing the help if requested, or an error if the arguments are invalid.
+    #[must_use]
+    pub fn from_env() -> Self {
+        let mut strings: Vec<String> = std::env::args_os()
+            .map(std::ffi::OsString::into_string)
+            .collect::<Result<_, _>>()
+            .unwrap_or_else(|arg| {
+                eprintln!("Invalid utf8: {}", arg.to_string_lossy());
+                std::process::exit(1)
+            });
+        if strings.is_empty() {
+            eprintln!("No program name, argv is empty");
+            std::process::exit(1)
+        }
+        expand_alias(&mut strings[1..]);
+        escape_stdin(&mut strings);
+
+        let cmd = std::path::Path::new(&strings[0])
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(&strings[0]);
+        let strs: Vec<&str> = strings.iter().map(String::as_str).collect();
+        Self::from_args(&[cmd], &strs[1..]).unwrap_or_else(|early_exit| match early_exit.status {
+            Ok(()) => {
+                println!("{}", early_exit.output);
+                std::process::exit(0)
+            }
+            Err(()) => {
+                eprintln!(
+                    "{}\nRun {cmd} --help for more information.",
+                    early_exit.output
+                );
+                std::process::exit(1)
+            }
+        })
+    }
+
+    /// Log level filter derived from `--verbose` and `--quiet`, starting from `info`.
+    #[must_use]
+    pub fn log_level(&self) -> LevelFilter {
+        const LEVELS: [LevelFilter; 6] = [
+            LevelFilter::Off,
+            LevelFilter::Error,
+            LevelFilter::Warn,
+            LevelFilter::Info,
+            LevelFilter::Debug,
+            LevelFilter::Trace,
+        ];
+        let level = (3 + usize::from(self.verbose)).saturating_sub(usize::from(self.quiet));
+        LEVELS[level.min(LEVELS.len() - 1)]
+    }
+}
+
+/// Cargo features the CLI was compiled with.
+const FEATURES: &[&str] = &[
+    #[cfg(feature = "metrics")]
+    "metrics",
+];
+
+/// Version of the CLI and the features of it and the `semantic-search` library, like `sense 0.1.7 (features: none)`.
+#[must_use]
+pub fn version() -> String {
+    let features: Vec<_> = semantic_search::FEATURES
+        .iter()
+        .chain(FEATURES)
+        .copied()
+        .collect();
+    let features = if features.is_empty() {
+        "none".to_string()
+    } else {
+        features.join(", ")
+    };
+    format!("sense {} (features: {features})", env!("CARGO_PKG_VERSION"))
+}
+
+/// Execute the command, printing its output in the given format, or the default one of the command, and colored as given by `color`.
+///
+/// Output meant for other programs, like search results, is printed to stdout, while progress, summaries and prompts go to stderr, as logs or otherwise. Commands without structured output ignore the format.
 ///
 /// # Errors
 ///
-/// Returns an [IO error](std::io::Error) if reading or writing fails.
+/// Returns a [`CliError`] classifying the failure, e.g. [`CliError::DatabaseNotFound`] before indexing.
+#[allow(clippy::future_not_send, reason = "Main function")]
+pub async fn execute(
+    command: Command,
+    config: Config,
+    format: Option<OutputFormat>,
+    color: ColorChoice,
+) -> Result<(), CliError> {
+    Box::pin(run(command, config, format, color.enabled()))
+        .await
+        .map_err(CliError::from)
+}
+
+/// Execute the command, see [`execute`].
 #[allow(clippy::future_not_send, reason = "Main function")]
-pub async fn execute(command: Command, config: Config) -> Result<()> {
-    debug!("Executing command: {:?}", command);
-    debug!("Config: {:?}", config);
+async fn run(
+    command: Command,
+    config: Config,
+    format: Option<OutputFormat>,
+    color: bool,
+) -> Result<()> {
+    debug!("Executing command: {command:?}");
+    debug!("Config: {config:?}");
 
     match command {
+        Command::Index(index) if index.estimate => {
+            let summary = index.estimate(config).await?;
+            if index.json || matches!(format, Some(OutputFormat::Json | OutputFormat::Ndjson)) {
+                println!("{}", serde_json::to_string(&summary)?);
+            } else {
+                println!(
+                    "{} new, {} changed and {} deleted file(s), needing about {} embedding request(s)",
+                    summary.new, summary.changed, summary.deleted, summary.embed_calls
+                );
+            }
+        }
         Command::Index(index) => {
             info!("Indexing files...");
+            let dry_run = index.dry_run;
+            let json =
+                index.json || matches!(format, Some(OutputFormat::Json | OutputFormat::Ndjson));
             let summary = index.execute(config).await?;
             let attention_required = summary.changed + summary.new > 0;
             info!("Indexing complete!");
@@ -48,15 +303,175 @@ pub async fn execute(command: Command, config: Config) -> Result<()> {
                 );
             } else if summary.deleted > 0 {
                 info!("{} file(s) deleted since last index. 🗑️", summary.deleted);
-            } else {
+            } else if summary.renamed == 0 {
                 info!("No changes detected. ☕");
             }
+            if summary.skipped > 0 {
+                info!(
+                    "{} file(s) skipped by size or extension. 🙈",
+                    summary.skipped
+                );
+            }
+            if summary.renamed > 0 {
+                info!(
+                    "{} file(s) moved or renamed, keeping their labels and embeddings. 🚚",
+                    summary.renamed
+                );
+            }
+            if summary.stopped_early {
+                warn!("Stopped early due to --max-files, run again to index more files. ✋");
+            }
+            if dry_run {
+                info!(
+                    "Dry run: nothing written, {} embedding request(s) would be made, plus one per relabeled file. 🧪",
+                    summary.embed_calls
+                );
+            } else if summary.embed_calls > 0 {
+                info!(
+                    "{} embedding request(s) made, using {} token(s). 💸",
+                    summary.embed_calls, summary.tokens
+                );
+            }
+            if json {
+                println!("{}", serde_json::to_string(&summary)?);
+            } else if !summary.duplicates.is_empty() {
+                info!("Likely duplicates: {} pair(s) 👯", summary.duplicates.len());
+                for (path, other_path, similarity) in &summary.duplicates {
+                    let percent = similarity * 100.0;
+                    println!("{percent:.2}%: {path} <-> {other_path}");
+                }
+            }
+        }
+        Command::Search(search) if search.batch => {
+            let count = search.execute_batch(config).await?;
+            info!("Searched {count} query(s). 🔎");
         }
         Command::Search(search) => {
             let results = search.execute(config).await?;
-            for (file_path, similarity) in results {
+            let mut writer = search.writer()?;
+            let output_format = search.output_format(format);
+            // Files written with `--output` are never colored
+            let color = color && search.output.is_none();
+            write_results(
+                &mut writer,
+                &results,
+                output_format,
+                search.score_format,
+                color,
+            )?;
+            writer.flush()?;
+            if let Some(path) = &search.output {
+                info!(
+                    "Wrote {} result(s) to {}. 📝",
+                    results.len(),
+                    path.display()
+                );
+            }
+        }
+        Command::Similar(similar) => {
+            let results: Vec<_> = similar
+                .execute(config)
+                .await?
+                .into_iter()
+                .map(|(file, similarity)| SearchResult {
+                    file,
+                    similarity,
+                    preview: None,
+                })
+                .collect();
+            let output_format = format.unwrap_or_default();
+            write_results(
+                &mut io::stdout().lock(),
+                &results,
+                output_format,
+                ScoreFormat::default(),
+                color,
+            )?;
+        }
+        Command::Embed(embed) => println!("{}", embed.execute(config, format).await?),
+        Command::Compare(compare) => {
+            let similarity = compare.execute(config).await?;
+            if matches!(format, Some(OutputFormat::Json | OutputFormat::Ndjson)) {
+                println!("{}", json!({ "similarity": similarity }));
+            } else {
                 let percent = similarity * 100.0;
-                println!("{percent:.2}%: {file_path}");
+                println!("{similarity:.4} ({percent:.2}%)");
+            }
+        }
+        Command::Eval(eval) => {
+            let summary = eval.execute(config).await?;
+            if eval.verbose {
+                for pair in &summary.pairs {
+                    let rank = pair
+                        .rank
+                        .map_or_else(|| format!(">{}", summary.k), |rank| rank.to_string());
+                    let percent = pair.similarity * 100.0;
+                    println!("{rank}\t{percent:.2}%\t{}\t{}", pair.query, pair.expected);
+                }
+            }
+            let rows = [
+                ("Pairs".to_owned(), summary.pairs.len().to_string()),
+                (
+                    format!("Recall@{}", summary.k),
+                    format!("{:.2}%", summary.recall() * 100.0),
+                ),
+                ("MRR".to_owned(), format!("{:.4}", summary.mrr())),
+                (
+                    "Mean similarity".to_owned(),
+                    format!("{:.2}%", summary.mean_similarity() * 100.0),
+                ),
+            ];
+            for (name, value) in rows {
+                println!("{name:<16} {value}");
+            }
+        }
+        Command::Label(label) => {
+            label.execute(config).await?;
+            info!("Label updated. 🏷️");
+        }
+        Command::Rename(rename) => {
+            rename.execute(config).await?;
+            info!("File renamed. 🚚");
+        }
+        Command::Check(check) => {
+            if !check.execute(config).await? {
+                anyhow::bail!("Some checks failed");
+            }
+        }
+        Command::Export(export) => {
+            let count = export.execute(config, format).await?;
+            info!("Exported {count} record(s). 📦");
+        }
+        Command::Import(import) => {
+            let count = import.execute(config).await?;
+            info!("Imported {count} record(s). 📥");
+        }
+        Command::Prune(prune) => {
+            let summary = prune.execute(config).await?;
+            if prune.dry_run {
+                info!(
+                    "Dry run: {} file(s) in {} group(s) of duplicates would be removed. 🧪",
+                    summary.removed, summary.groups
+                );
+            } else {
+                info!(
+                    "Removed {} file(s) in {} group(s) of duplicates. ✂️",
+                    summary.removed, summary.groups
+                );
+            }
+        }
+        Command::Clean(clean) => {
+            let summary = clean.execute(config).await?;
+            if clean.dry_run {
+                info!(
+                    "Dry run: {} missing and {} ignored file(s) would be removed. 🧪",
+                    summary.missing, summary.orphans
+                );
+            } else {
+                info!(
+                    "Removed {} missing and {} ignored file(s). 🧹",
+                    summary.missing, summary.orphans
+                );
             }
         }
         Command::Telegram(telegram) => telegram.execute(config).await?,
@@ -65,3 +480,94 @@ pub async fn execute(command: Command, config: Config) -> Result<()> {
 
     Ok(())
 }
+
+/// Similarity from which results are colored green, or yellow from [`MID_SIMILARITY`], and red below.
+const HIGH_SIMILARITY: f32 = 0.75;
+/// Similarity from which results are colored yellow, see [`HIGH_SIMILARITY`].
+const MID_SIMILARITY: f32 = 0.5;
+
+/// Style of a similarity, graded by how high it is.
+fn similarity_style(similarity: f32) -> Style {
+    let color = if similarity >= HIGH_SIMILARITY {
+        AnsiColor::Green
+    } else if similarity >= MID_SIMILARITY {
+        AnsiColor::Yellow
+    } else {
+        AnsiColor::Red
+    };
+    Style::new().fg_color(Some(color.into()))
+}
+
+/// Write search results in the given format. As text, each result is a line with its similarity in `score_format`, followed by its preview if any. If `color` is set, similarities are colored by how high they are, and paths in cyan.
+fn write_results(
+    writer: &mut impl Write,
+    results: &[SearchResult],
+    format: OutputFormat,
+    score_format: ScoreFormat,
+    color: bool,
+) -> Result<()> {
+    match format {
+        OutputFormat::Text => {
+            for result in results {
+                let similarity = score_format.format(result.similarity);
+                // Plain styles print nothing
+                let (score_style, path_style) = if color {
+                    (
+                        similarity_style(result.similarity),
+                        Style::new().fg_color(Some(AnsiColor::Cyan.into())),
+                    )
+                } else {
+                    (Style::new(), Style::new())
+                };
+                writeln!(
+                    writer,
+                    "{score_style}{similarity}{score_style:#}: {path_style}{}{path_style:#}",
+                    result.file
+                )?;
+                if let Some(preview) = &result.preview {
+                    writeln!(writer, "    {preview}")?;
+                }
+            }
+        }
+        OutputFormat::Json => {
+            serde_json::to_writer(&mut *writer, results)?;
+            writeln!(writer)?;
+        }
+        OutputFormat::Ndjson => {
+            for result in results {
+                serde_json::to_writer(&mut *writer, result)?;
+                writeln!(writer)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expanded(args: &[&str]) -> Vec<String> {
+        let mut args: Vec<String> = args.iter().map(ToString::to_string).collect();
+        expand_alias(&mut args);
+        args
+    }
+
+    #[test]
+    fn test_expand_alias() {
+        assert_eq!(expanded(&["s", "cat"]), ["search", "cat"]);
+        assert_eq!(
+            expanded(&["-v", "mv", "a", "b"]),
+            ["-v", "rename", "a", "b"]
+        );
+        // Values of global options are not taken for the command
+        assert_eq!(
+            expanded(&["--color", "never", "s", "cat"]),
+            ["--color", "never", "search", "cat"]
+        );
+        assert_eq!(expanded(&["--db", "s", "i"]), ["--db", "s", "index"]);
+        // Only the command is expanded
+        assert_eq!(expanded(&["search", "s"]), ["search", "s"]);
+    }
+}
@@ -1,15 +1,43 @@
 #![warn(clippy::all, clippy::nursery, clippy::pedantic, clippy::cargo)]
 
 use anyhow::{Context, Result};
-use env_logger::Env;
+use env_logger::{Env, Target};
 use log::debug;
-use semantic_search_cli::{Args, execute, parse_config};
+use semantic_search_cli::{
+    Args, CliError, Config, execute, find_project_dir, parse_config, version,
+};
 use std::io::Write;
 use std::path::Path;
+use std::process::ExitCode;
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    env_logger::Builder::from_env(Env::default().default_filter_or("info"))
+async fn main() -> ExitCode {
+    match run().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            // Exit with a code per kind of error, printing it like returning it from `main` would
+            let error = CliError::from(error);
+            let code = error.exit_code();
+            eprintln!("Error: {:?}", anyhow::Error::from(error));
+            ExitCode::from(code)
+        }
+    }
+}
+
+/// Parse arguments and the config, then execute the command.
+#[allow(clippy::future_not_send, reason = "Main function")]
+async fn run() -> Result<()> {
+    let args = Args::from_env();
+    // Before parsing the config, so that it works without one
+    if args.version {
+        println!("{}", version());
+        return Ok(());
+    }
+
+    // An explicit `RUST_LOG` takes precedence over `--verbose` and `--quiet`
+    // Logs always go to stderr, so that stdout only carries the output of commands
+    env_logger::Builder::from_env(Env::default().default_filter_or(args.log_level().as_str()))
+        .target(Target::Stderr)
         .format(|buf, record| {
             let level = record.level();
             let style = buf.default_level_style(level);
@@ -17,14 +45,46 @@ async fn main() -> Result<()> {
         })
         .init();
 
-    let args: Args = argh::from_env();
-    debug!("Args: {:?}", args);
-    debug!("Working directory: {:?}", std::env::current_dir()?);
+    debug!("Args: {args:?}");
+    debug!("Working directory: {}", std::env::current_dir()?.display());
+
+    let Some(command) = args.command else {
+        anyhow::bail!("No command given, run with --help to see available commands");
+    };
 
-    let config = parse_config(Path::new(".sense/config.toml"))
-        .with_context(|| "Failed to parse config file, consider creating one")?;
+    let mut config = if let Some(path) = &args.config {
+        if !path.exists() {
+            return Err(
+                CliError::config(format!("Config file {} does not exist", path.display())).into(),
+            );
+        }
+        parse_config(path, args.strict_config)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))?
+    } else {
+        // Search upward for `.sense`, so that commands work from subdirectories of the project
+        let project = find_project_dir(&std::env::current_dir()?);
+        if let Some(project) = &project {
+            debug!("Project directory: {}", project.display());
+        }
+        let project = project.as_deref().unwrap_or_else(|| Path::new(""));
+        let path = project.join(".sense/config.toml");
+        let mut config = if path.exists() {
+            parse_config(&path, args.strict_config)
+                .with_context(|| "Failed to parse config file")?
+        } else {
+            // Commands that call the API will fail later, asking for the API key
+            debug!("No config file found, using defaults");
+            Config::default()
+        };
+        // A relative database path belongs to the project, not the working directory
+        config.database.path = project.join(&config.database.path);
+        config
+    };
+    if let Some(db) = args.db {
+        config.database.path = db;
+    }
 
-    Box::pin(execute(args.command, config)).await?;
+    Box::pin(execute(command, config, args.format, args.color)).await?;
 
     Ok(())
 }
@@ -1,35 +1,71 @@
 //! Utility functions for the semantic search CLI.
 
+mod ann;
+
+use ann::AnnIndex;
 use futures_core::stream::BoxStream;
-use futures_util::stream::StreamExt;
+use futures_util::stream::{StreamExt, TryStreamExt};
+use glob::Pattern;
 use log::info;
 use semantic_search::{Embedding, embedding::EmbeddingBytes};
 use sha2::{Digest, Sha256};
 use sqlx::{
-    Connection, Executor, Result as SqlResult, Row, SqliteConnection, sqlite::SqliteConnectOptions,
+    Executor, Result as SqlResult, Row, SqliteConnection, SqlitePool,
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous},
 };
 use std::{
-    fs::File,
-    io::{self, Read, Result as IOResult, Write},
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap},
+    fs::{File, TryLockError},
+    io::{self, BufReader, Result as IOResult, Write},
     iter,
     path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering as AtomicOrdering},
+    time::UNIX_EPOCH,
 };
 
 pub const TABLE_NAME: &str = "files";
+/// Table counting search queries per user of the Telegram bot.
+const USAGE_TABLE_NAME: &str = "usage";
+/// Table of key-value metadata about the index.
+const META_TABLE_NAME: &str = "meta";
+/// Key of the root of the index in the meta table, which stored paths are relative to.
+const ROOT_KEY: &str = "root";
+
+/// Columns added after the initial schema and their types, in the order they were added.
+const ADDED_COLUMNS: [(&str, &str); 3] = [
+    ("mtime", "INTEGER"),
+    ("emojis", "TEXT"),
+    ("sticker_set", "TEXT"),
+];
+
+/// Page cache size of database connections, in KiB.
+const CACHE_SIZE_KIB: u32 = 16 * 1024;
+/// Maximum number of connections of a read-only database, each with its own page cache.
+const MAX_READ_CONNECTIONS: u32 = 4;
+/// Maximum number of parameters bound to a single query, the lowest limit of any SQLite version.
+const MAX_VARIABLES: usize = 999;
+
+/// Directory holding the configuration and database by default, which is never indexed.
+pub const DATA_DIR: &str = ".sense";
+
+/// Find the closest directory holding [`DATA_DIR`], starting from `start` and going up its ancestors, like git finds `.git`.
+#[must_use]
+pub fn find_project_dir(start: &Path) -> Option<PathBuf> {
+    start
+        .ancestors()
+        .find(|dir| dir.join(DATA_DIR).is_dir())
+        .map(Path::to_path_buf)
+}
+
+/// Size of the buffer used when hashing files.
+const HASH_BUFFER_SIZE: usize = 64 * 1024;
 
 /// Calculate SHA-256 hash of a file.
 pub fn hash_file<T: AsRef<Path>>(file: T) -> IOResult<String> {
-    let mut file = File::open(file)?;
+    let mut reader = BufReader::with_capacity(HASH_BUFFER_SIZE, File::open(file)?);
     let mut hasher = Sha256::new();
-    let mut buffer = [0; 1024];
-
-    loop {
-        let bytes_read = file.read(&mut buffer)?;
-        if bytes_read == 0 {
-            break;
-        }
-        hasher.update(&buffer[..bytes_read]);
-    }
+    io::copy(&mut reader, &mut hasher)?;
 
     let result = hasher.finalize();
     let result = base16ct::lower::encode_string(&result);
@@ -37,29 +73,165 @@ pub fn hash_file<T: AsRef<Path>>(file: T) -> IOResult<String> {
     Ok(result)
 }
 
-/// Check if a file is hidden.
+/// Calculate SHA-256 hashes of files on blocking threads, hashing at most `jobs` files at a time. Hashes are returned in the same order as `files`.
+pub async fn hash_files(files: Vec<PathBuf>, jobs: usize) -> IOResult<Vec<String>> {
+    futures_util::stream::iter(files)
+        .map(|file| async move {
+            tokio::task::spawn_blocking(move || hash_file(file))
+                .await
+                .map_err(io::Error::other)?
+        })
+        .buffered(jobs.max(1))
+        .try_collect()
+        .await
+}
+
+/// Modification time of a file in nanoseconds since the Unix epoch, or `None` if not available.
+pub fn modified_time<T: AsRef<Path>>(file: T) -> Option<i64> {
+    let modified = std::fs::metadata(file).ok()?.modified().ok()?;
+    let duration = modified.duration_since(UNIX_EPOCH).ok()?;
+    i64::try_from(duration.as_nanos()).ok()
+}
+
+/// Check if a file is hidden. Paths without a file name, like `..` or `/`, are not hidden.
 fn is_hidden(entry: &Path) -> bool {
     entry
         .file_name()
-        .unwrap()
-        .to_string_lossy()
-        .starts_with('.')
+        .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+}
+
+/// Absolute path of a file that may not exist, with symlinks of its directory resolved, to compare with paths under a canonical root.
+fn absolute(path: &Path) -> PathBuf {
+    let absolute = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    match (absolute.parent(), absolute.file_name()) {
+        (Some(dir), Some(name)) => dir
+            .canonicalize()
+            .map_or_else(|_| absolute.clone(), |dir| dir.join(name)),
+        _ => absolute,
+    }
+}
+
+/// Options of [`iter_files`].
+#[derive(Debug, Default)]
+pub struct WalkOptions {
+    /// Whether to include hidden files and directories, whose names start with `.`
+    pub include_hidden: bool,
+    /// Absolute paths of files never yielded
+    excluded: Vec<PathBuf>,
+    /// Files larger than this many bytes are filtered out
+    pub max_size: Option<u64>,
+    /// Only files with these extensions pass the filters, if any, each possibly comma-separated
+    pub ext: Vec<String>,
+    /// Files with these extensions are filtered out, each possibly comma-separated
+    pub no_ext: Vec<String>,
+}
+
+impl WalkOptions {
+    /// Walk without excluding any file, including hidden ones if `include_hidden` is set.
+    #[must_use]
+    pub const fn new(include_hidden: bool) -> Self {
+        Self {
+            include_hidden,
+            excluded: Vec::new(),
+            max_size: None,
+            ext: Vec::new(),
+            no_ext: Vec::new(),
+        }
+    }
+
+    /// Never yield the file at `path`, like the configuration file. Relative paths are resolved against the working directory.
+    #[must_use]
+    pub fn exclude(mut self, path: &Path) -> Self {
+        self.excluded.push(absolute(path));
+        self
+    }
+
+    /// Never yield the database at `path`, its lock file, or the journals SQLite keeps next to it.
+    #[must_use]
+    pub fn exclude_database(self, path: &Path) -> Self {
+        let companion = |suffix: &str| {
+            let mut companion = path.as_os_str().to_owned();
+            companion.push(suffix);
+            PathBuf::from(companion)
+        };
+        self.exclude(path)
+            .exclude(&path.with_extension("lock"))
+            .exclude(&companion("-wal"))
+            .exclude(&companion("-shm"))
+            .exclude(&companion("-journal"))
+    }
+
+    /// Whether the file or directory at `path` is skipped.
+    fn skips(&self, path: &Path) -> bool {
+        path.file_name() == Some(DATA_DIR.as_ref())
+            || !self.include_hidden && is_hidden(path)
+            || self.excluded.iter().any(|excluded| excluded == path)
+    }
+
+    /// Whether the file at `path` is filtered out by its size or extension. Unlike skipped files, [`iter_files`] still yields it.
+    #[must_use]
+    pub fn filters(&self, path: &Path) -> bool {
+        let ext = path
+            .extension()
+            .map(|ext| ext.to_string_lossy())
+            .unwrap_or_default();
+        if !self.ext.is_empty() && !contains_extension(&self.ext, &ext) {
+            return true;
+        }
+        if !ext.is_empty() && contains_extension(&self.no_ext, &ext) {
+            return true;
+        }
+        self.max_size.is_some_and(|max_size| {
+            path.metadata()
+                .is_ok_and(|metadata| metadata.len() > max_size)
+        })
+    }
+
+    /// Whether the file or directory at `relative` under `root`, or one of its directories, is skipped, so that [`iter_files`] yields nothing there.
+    #[must_use]
+    pub fn skips_relative(&self, root: &Path, relative: &str) -> bool {
+        let mut path = root.to_path_buf();
+        Path::new(relative).components().any(|component| {
+            path.push(component);
+            self.skips(&path)
+        })
+    }
+
+    /// Whether the file at `relative` under `root` would not be indexed now, because it or one of its directories is skipped, or it is filtered out.
+    #[must_use]
+    pub fn ignores(&self, root: &Path, relative: &str) -> bool {
+        self.skips_relative(root, relative) || self.filters(&root.join(relative))
+    }
 }
 
-/// Iterate over all files in a directory recursively, skipping hidden files.
+/// Whether `extensions`, possibly comma-separated and with a leading dot, contain `ext`, case-insensitively.
+fn contains_extension(extensions: &[String], ext: &str) -> bool {
+    extensions
+        .iter()
+        .flat_map(|list| list.split(','))
+        .map(|candidate| candidate.trim().trim_start_matches('.'))
+        .any(|candidate| candidate.eq_ignore_ascii_case(ext))
+}
+
+/// Iterate over all files in a directory recursively, skipping hidden files unless `options` include them. The [`DATA_DIR`] directory and files excluded by `options` are always skipped.
 pub fn iter_files<'a, T1: AsRef<Path>>(
     dir: T1,
     ref_path: &'a Path,
-) -> Box<dyn Iterator<Item = (PathBuf, String)> + 'a> {
+    options: &'a WalkOptions,
+) -> Box<dyn Iterator<Item = (PathBuf, String)> + Send + 'a> {
     let iter = std::fs::read_dir(dir)
         .unwrap()
         .filter_map(|entry| {
             let path = entry.ok()?.path();
-            if is_hidden(&path) { None } else { Some(path) }
+            if options.skips(&path) {
+                None
+            } else {
+                Some(path)
+            }
         })
         .flat_map(move |path| {
             if path.is_dir() {
-                iter_files(&path, ref_path)
+                iter_files(&path, ref_path, options)
             } else {
                 let relative = path
                     .strip_prefix(ref_path)
@@ -73,10 +245,139 @@ pub fn iter_files<'a, T1: AsRef<Path>>(
     Box::new(iter)
 }
 
-/// Prompt for user input.
+/// Guard of a flag telling that some work is in progress, like a reindex, so that it isn't started twice. The flag is cleared when the guard is dropped, even if the work panics or returns early.
+pub struct BusyGuard(&'static AtomicBool);
+
+impl BusyGuard {
+    /// Set the flag, or return `None` if it is already set.
+    pub fn acquire(flag: &'static AtomicBool) -> Option<Self> {
+        (!flag.swap(true, AtomicOrdering::AcqRel)).then(|| Self(flag))
+    }
+}
+
+impl Drop for BusyGuard {
+    fn drop(&mut self) {
+        self.0.store(false, AtomicOrdering::Release);
+    }
+}
+
+/// Order similarities from highest to lowest, ranking NaN last.
+pub fn descending(a: f32, b: f32) -> Ordering {
+    b.partial_cmp(&a)
+        .unwrap_or_else(|| a.is_nan().cmp(&b.is_nan()))
+}
+
+/// The `n` most similar items seen so far, kept in a bounded heap whose top is the least similar.
+pub struct TopK<T> {
+    n: usize,
+    heap: BinaryHeap<Ranked<T>>,
+}
+
+impl<T> TopK<T> {
+    /// Keep at most `n` items.
+    #[must_use]
+    pub fn new(n: usize) -> Self {
+        Self {
+            n,
+            heap: BinaryHeap::with_capacity(n),
+        }
+    }
+
+    /// Offer an item, keeping it if it is among the `n` most similar so far.
+    pub fn push(&mut self, item: T, similarity: f32) {
+        if self.heap.len() < self.n {
+            self.heap.push(Ranked(item, similarity));
+        } else if let Some(mut least) = self.heap.peek_mut()
+            && descending(least.1, similarity).is_gt()
+        {
+            *least = Ranked(item, similarity);
+        }
+    }
+
+    /// The kept items, most similar first.
+    #[must_use]
+    pub fn into_sorted_vec(self) -> Vec<(T, f32)> {
+        self.heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Ranked(item, similarity)| (item, similarity))
+            .collect()
+    }
+}
+
+/// An item ordered by similarity, from highest to lowest as in [`descending`].
+struct Ranked<T>(T, f32);
+
+impl<T> PartialEq for Ranked<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl<T> Eq for Ranked<T> {}
+
+impl<T> PartialOrd for Ranked<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Ranked<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        descending(self.1, other.1)
+    }
+}
+
+/// Group files connected by duplicate pairs, e.g. from [`Database::find_duplicates`]. Grouping is transitive, so two files in a group may be less similar than the threshold if both are similar to a third one. Paths in each group are sorted, and so are groups by their first path.
+pub fn group_duplicates(pairs: &[(String, String, f32)]) -> Vec<Vec<String>> {
+    // Union-find over indices of paths
+    let mut indices = HashMap::new();
+    let mut paths = Vec::new();
+    let mut parents: Vec<usize> = Vec::new();
+    let mut index_of = |path: &String| {
+        *indices.entry(path.clone()).or_insert_with(|| {
+            paths.push(path.clone());
+            parents.push(parents.len());
+            parents.len() - 1
+        })
+    };
+    let edges: Vec<_> = pairs
+        .iter()
+        .map(|(a, b, _)| (index_of(a), index_of(b)))
+        .collect();
+    for (a, b) in edges {
+        let (a, b) = (find_root(&mut parents, a), find_root(&mut parents, b));
+        parents[a.max(b)] = a.min(b);
+    }
+
+    let mut groups: HashMap<usize, Vec<String>> = HashMap::new();
+    for (i, path) in paths.into_iter().enumerate() {
+        groups
+            .entry(find_root(&mut parents, i))
+            .or_default()
+            .push(path);
+    }
+    let mut groups: Vec<_> = groups.into_values().collect();
+    for group in &mut groups {
+        group.sort();
+    }
+    groups.sort();
+    groups
+}
+
+/// Find the root of `i` in a union-find forest, compressing the path along the way.
+fn find_root(parents: &mut [usize], mut i: usize) -> usize {
+    while parents[i] != i {
+        parents[i] = parents[parents[i]];
+        i = parents[i];
+    }
+    i
+}
+
+/// Prompt for user input on stderr, keeping stdout for the output of commands.
 pub fn prompt(message: &str) -> IOResult<String> {
-    print!("{message}");
-    io::stdout().flush()?;
+    eprint!("{message}");
+    io::stderr().flush()?;
 
     let mut input = String::new();
     io::stdin().read_line(&mut input)?;
@@ -97,42 +398,227 @@ pub struct Record {
     /// Embedding of the file
     #[sqlx(try_from = "Vec<u8>")]
     pub embedding: Embedding,
+    /// Modification time of the file in nanoseconds since the Unix epoch, if known
+    pub mtime: Option<i64>,
+    /// Emojis associated with the sticker, separated by spaces, if any
+    pub emojis: Option<String>,
+}
+
+/// A record in the database without its embedding, as returned by [`Database::search_with_metadata`].
+#[derive(Debug, PartialEq, Eq, Clone, sqlx::FromRow)]
+pub struct Metadata {
+    /// Path to the file (relative to working directory)
+    pub file_path: String,
+    /// SHA-256 hash of the file
+    pub file_hash: String,
+    /// File id used in Telegram
+    pub file_id: Option<String>,
+    /// Label of the file
+    pub label: String,
+    /// Modification time of the file in nanoseconds since the Unix epoch, if known
+    pub mtime: Option<i64>,
+    /// Emojis associated with the sticker, separated by spaces, if any
+    pub emojis: Option<String>,
+}
+
+/// Filter on file paths, applied before ranking so that top-N is computed over matching files only.
+#[derive(Debug, Default)]
+pub struct PathFilter {
+    /// Only match paths under this directory.
+    pub under: Option<PathBuf>,
+    /// Only match paths matching this glob pattern. Note that `*` also matches path separators.
+    pub glob: Option<Pattern>,
+    /// Exclude paths matching any of these glob patterns.
+    pub exclude: Vec<Pattern>,
+}
+
+impl PathFilter {
+    /// Check whether the path passes the filter.
+    pub fn matches(&self, path: &str) -> bool {
+        let under = self
+            .under
+            .as_ref()
+            .is_none_or(|under| Path::new(path).starts_with(under));
+        let glob = self
+            .glob
+            .as_ref()
+            .is_none_or(|pattern| pattern.matches(path));
+        let excluded = self.exclude.iter().any(|pattern| pattern.matches(path));
+        under && glob && !excluded
+    }
+}
+
+/// Search query counts of the Telegram bot.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Usage {
+    /// Total number of queries
+    pub total: u64,
+    /// Number of users who made queries
+    pub users: u64,
+    /// User ids and their number of queries, most active first
+    pub top: Vec<(u64, u64)>,
 }
 
 /// Simple database wrapper.
+///
+/// Methods take `&self` unless they update the approximate nearest-neighbor index, and run concurrently on a read-only database, which has a pool of up to [`MAX_READ_CONNECTIONS`] connections. A database opened for writing, or in memory, has a single connection instead, so that a transaction covers every query until it is committed or rolled back, and reads within it see its writes.
 pub struct Database {
-    conn: SqliteConnection,
+    pool: SqlitePool,
+    /// Whether the database is opened read-only.
+    read_only: bool,
+    /// Lock file held while the database is open for writing, released on drop.
+    _lock: Option<File>,
+    /// Approximate nearest-neighbor index, if built.
+    ann: Option<AnnIndex>,
+    /// All embeddings, if loaded into memory.
+    embeddings: Option<Vec<(String, Embedding)>>,
+    /// Directory containing the database file, or `None` in memory.
+    dir: Option<PathBuf>,
+    /// Root of the index, resolved to an absolute path, if recorded.
+    root: Option<PathBuf>,
 }
 
 impl Database {
-    /// Open a database connection, creating if not exists.
+    /// Open a database connection, creating if not exists. Opening a database that does not exist as read-only fails with an [IO error](io::Error) of kind [`NotFound`](io::ErrorKind::NotFound).
+    ///
+    /// Opening for writing upgrades the schema of a database created by an older version. Read-only opens never write, so they fail with an [IO error](io::Error) of kind [`InvalidData`](io::ErrorKind::InvalidData) asking to run `sense index` instead.
+    ///
+    /// Opening for writing takes an advisory lock on a lock file next to the database (e.g. `index.lock` for `index.db3`), failing fast if another process holds it. The lock is released when the database is dropped, or by the OS if the process exits abnormally.
+    ///
+    /// If `wal` is set, opening for writing switches the database to WAL mode with `synchronous=NORMAL`, otherwise back to the default rollback journal. The journal mode is persistent, so read-only connections leave it as is.
     #[allow(clippy::future_not_send, reason = "Should be `Send` if `T: Send`")]
-    pub async fn open<T: AsRef<Path>>(path: T, read_only: bool) -> SqlResult<Self> {
+    pub async fn open<T: AsRef<Path>>(path: T, read_only: bool, wal: bool) -> SqlResult<Self> {
         let path = path.as_ref();
+        let lock = if read_only {
+            None
+        } else {
+            Some(Self::lock(path)?)
+        };
         let exists = path.exists();
-        let options = SqliteConnectOptions::new()
+        if read_only && !exists {
+            return Err(Self::not_found(path));
+        }
+        let mut options = SqliteConnectOptions::new()
             .filename(path)
             .read_only(read_only)
-            .create_if_missing(!exists);
-        let mut conn = SqliteConnection::connect_with(&options).await?;
+            .create_if_missing(!exists)
+            .pragma("cache_size", format!("-{CACHE_SIZE_KIB}"));
+        if !read_only {
+            options = if wal {
+                options
+                    .journal_mode(SqliteJournalMode::Wal)
+                    .synchronous(SqliteSynchronous::Normal)
+            } else {
+                options.journal_mode(SqliteJournalMode::Delete)
+            };
+        }
+        let max_connections = if read_only { MAX_READ_CONNECTIONS } else { 1 };
+        let pool = Self::pool_options(max_connections)
+            .connect_with(options)
+            .await?;
+        let mut conn = pool.acquire().await?;
 
         if !exists {
-            // Should error when initializing connection
-            assert!(!read_only, "Database does not exist");
             info!("Initializing database...");
             Self::init(&mut conn).await?;
+        } else if !read_only {
+            Self::migrate(&mut conn).await?;
+        } else if Self::is_outdated(&mut conn).await? {
+            return Err(Self::outdated(path));
         }
+        let dir = path.canonicalize()?.parent().map(Path::to_path_buf);
+        let root = Self::read_root(&mut conn)
+            .await?
+            .map(|root| Self::resolve_root(dir.as_deref(), &root));
+        drop(conn);
 
-        Ok(Self { conn })
+        Ok(Self {
+            pool,
+            read_only,
+            _lock: lock,
+            ann: None,
+            embeddings: None,
+            dir,
+            root,
+        })
     }
 
-    /// Open a database connection in memory for testing.
-    #[cfg(test)]
-    pub async fn dummy() -> SqlResult<Self> {
-        let mut conn = SqliteConnection::connect("sqlite::memory:").await?;
-        Self::init(&mut conn).await?;
+    /// Open an existing database for writing, failing like a read-only [`open`](Self::open) instead of creating it if it does not exist, for commands that only change what is indexed.
+    #[allow(clippy::future_not_send, reason = "Should be `Send` if `T: Send`")]
+    pub async fn open_existing<T: AsRef<Path>>(path: T, wal: bool) -> SqlResult<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Err(Self::not_found(path));
+        }
+        Self::open(path, false, wal).await
+    }
+
+    /// Error for a database that does not exist at `path`.
+    fn not_found(path: &Path) -> sqlx::Error {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} does not exist", path.display()),
+        )
+        .into()
+    }
+
+    /// Error opening a database with an outdated schema as read-only, which would have to be written to upgrade it.
+    fn outdated(path: &Path) -> sqlx::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} was created by an older version, run `sense index` to upgrade the database",
+                path.display()
+            ),
+        )
+        .into()
+    }
 
-        Ok(Self { conn })
+    /// Options of a connection pool. Connections are kept open, so that open transactions and in-memory databases are never lost.
+    fn pool_options(max_connections: u32) -> SqlitePoolOptions {
+        SqlitePoolOptions::new()
+            .max_connections(max_connections)
+            .max_lifetime(None)
+            .idle_timeout(None)
+    }
+
+    /// Take the advisory lock for writing to the database at `path`.
+    fn lock(path: &Path) -> SqlResult<File> {
+        let lock_path = path.with_extension("lock");
+        let file = File::options()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(&lock_path)?;
+        match file.try_lock() {
+            Ok(()) => Ok(file),
+            Err(TryLockError::WouldBlock) => Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!(
+                    "Another process is indexing or using {}, as {} is locked",
+                    path.display(),
+                    lock_path.display()
+                ),
+            )
+            .into()),
+            Err(TryLockError::Error(e)) => Err(e.into()),
+        }
+    }
+
+    /// Open an empty database in memory, for testing or dry runs.
+    pub async fn memory() -> SqlResult<Self> {
+        let pool = Self::pool_options(1).connect("sqlite::memory:").await?;
+        Self::init(&mut *pool.acquire().await?).await?;
+
+        Ok(Self {
+            pool,
+            read_only: false,
+            _lock: None,
+            ann: None,
+            embeddings: None,
+            dir: None,
+            root: None,
+        })
     }
 
     /// Initialize the database.
@@ -143,7 +629,25 @@ impl Database {
             file_hash TEXT NOT NULL,
             file_id TEXT,
             label TEXT NOT NULL,
-            embedding BLOB NOT NULL
+            embedding BLOB NOT NULL,
+            mtime INTEGER,
+            emojis TEXT,
+            sticker_set TEXT
+            )"
+        );
+        conn.execute(query.as_str()).await?;
+        Self::init_usage(conn).await?;
+        Self::init_meta(conn).await?;
+
+        Ok(())
+    }
+
+    /// Create the usage table if not exists. Only user ids and counts are stored, never query text.
+    async fn init_usage(conn: &mut SqliteConnection) -> SqlResult<()> {
+        let query = format!(
+            "CREATE TABLE IF NOT EXISTS {USAGE_TABLE_NAME} (
+            user_id INTEGER PRIMARY KEY,
+            queries INTEGER NOT NULL
             )"
         );
         conn.execute(query.as_str()).await?;
@@ -151,11 +655,121 @@ impl Database {
         Ok(())
     }
 
+    /// Create the meta table if not exists.
+    async fn init_meta(conn: &mut SqliteConnection) -> SqlResult<()> {
+        let query = format!(
+            "CREATE TABLE IF NOT EXISTS {META_TABLE_NAME} (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+            )"
+        );
+        conn.execute(query.as_str()).await?;
+
+        Ok(())
+    }
+
+    /// Find columns missing from a database created by an older version.
+    async fn missing_columns(
+        conn: &mut SqliteConnection,
+    ) -> SqlResult<Vec<(&'static str, &'static str)>> {
+        let query =
+            format!("SELECT COUNT(*) FROM pragma_table_info('{TABLE_NAME}') WHERE name = ?");
+        let mut missing = Vec::new();
+        for (name, kind) in ADDED_COLUMNS {
+            let count: i64 = sqlx::query_scalar(query.as_str())
+                .bind(name)
+                .fetch_one(&mut *conn)
+                .await?;
+            if count == 0 {
+                missing.push((name, kind));
+            }
+        }
+
+        Ok(missing)
+    }
+
+    /// Check whether the database was created by an older version and needs migration.
+    async fn is_outdated(conn: &mut SqliteConnection) -> SqlResult<bool> {
+        let query = "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?";
+        let meta: i64 = sqlx::query_scalar(query)
+            .bind(META_TABLE_NAME)
+            .fetch_one(&mut *conn)
+            .await?;
+        Ok(meta == 0 || !Self::missing_columns(conn).await?.is_empty())
+    }
+
+    /// Upgrade the schema of a database created by an older version.
+    async fn migrate(conn: &mut SqliteConnection) -> SqlResult<()> {
+        for (name, kind) in Self::missing_columns(conn).await? {
+            info!("Upgrading database: adding `{name}` column...");
+            let query = format!("ALTER TABLE {TABLE_NAME} ADD COLUMN {name} {kind}");
+            conn.execute(query.as_str()).await?;
+        }
+        Self::init_usage(conn).await?;
+        Self::init_meta(conn).await?;
+
+        Ok(())
+    }
+
+    /// Read the root of the index as stored, if recorded.
+    async fn read_root(conn: &mut SqliteConnection) -> SqlResult<Option<String>> {
+        let query = format!("SELECT value FROM {META_TABLE_NAME} WHERE key = ?");
+        sqlx::query_scalar(query.as_str())
+            .bind(ROOT_KEY)
+            .fetch_optional(conn)
+            .await
+    }
+
+    /// Resolve a stored root, which may be relative to the directory of the database.
+    fn resolve_root(dir: Option<&Path>, root: &str) -> PathBuf {
+        let root = dir.map_or_else(|| PathBuf::from(root), |dir| dir.join(root));
+        root.canonicalize().unwrap_or(root)
+    }
+
+    /// Root of the index, which stored paths are relative to, as an absolute path. `None` for databases never indexed, or indexed by an older version, whose paths are relative to the working directory.
+    pub fn root(&self) -> Option<&Path> {
+        self.root.as_deref()
+    }
+
+    /// Record the root of the index.
+    ///
+    /// If the root contains the database, like the default `.sense/index.db3`, it is stored relative to the directory of the database, so that moving the whole project keeps the index working. Otherwise, it is stored as an absolute path.
+    pub async fn set_root(&mut self, root: &Path) -> SqlResult<()> {
+        let root = root.canonicalize()?;
+        let stored = self
+            .dir
+            .as_deref()
+            .and_then(|dir| dir.strip_prefix(&root).ok())
+            .map_or_else(
+                || root.to_string_lossy().into_owned(),
+                |inner| match inner.components().count() {
+                    0 => ".".to_owned(),
+                    depth => vec![".."; depth].join("/"),
+                },
+            );
+        let query = format!("INSERT OR REPLACE INTO {META_TABLE_NAME} (key, value) VALUES (?, ?)");
+        sqlx::query(query.as_str())
+            .bind(ROOT_KEY)
+            .bind(&stored)
+            .execute(&self.pool)
+            .await?;
+        self.root = Some(root);
+
+        Ok(())
+    }
+
+    /// Path of a stored file on disk: joined onto the root if recorded, or as is, i.e. relative to the working directory.
+    pub fn resolve(&self, path: &str) -> PathBuf {
+        self.root
+            .as_ref()
+            .map_or_else(|| PathBuf::from(path), |root| root.join(path))
+    }
+
     /// Insert a record into the database, replacing if exists.
     pub async fn insert(&mut self, record: Record) -> SqlResult<bool> {
         let bytes: EmbeddingBytes = record.embedding.into();
         let query = format!(
-            "INSERT OR REPLACE INTO {TABLE_NAME} (file_path, file_hash, file_id, label, embedding) VALUES (?, ?, ?, ?, ?)"
+            "INSERT OR REPLACE INTO {TABLE_NAME} (file_path, file_hash, file_id, label, embedding, mtime, emojis) VALUES (?, ?, ?, ?, ?, ?, ?)"
         );
         let query = sqlx::query(query.as_str());
         let result = query
@@ -164,79 +778,294 @@ impl Database {
             .bind(&record.file_id)
             .bind(&record.label)
             .bind(&bytes[..])
-            .execute(&mut self.conn)
+            .bind(record.mtime)
+            .bind(&record.emojis)
+            .execute(&self.pool)
             .await?;
+        if let Some(embeddings) = &mut self.embeddings {
+            let embedding = bytes.into();
+            match embeddings
+                .iter_mut()
+                .find(|(path, _)| *path == record.file_path)
+            {
+                Some((_, existing)) => *existing = embedding,
+                None => embeddings.push((record.file_path.clone(), embedding)),
+            }
+        }
+        if let Some(ann) = &mut self.ann {
+            ann.insert(record.file_path, bytes.into());
+        }
 
         Ok(result.rows_affected() == 1)
     }
 
+    /// Begin a transaction. Writes are only persisted after [`commit`](Self::commit).
+    ///
+    /// Transactions are no-ops on a read-only database, as nothing can be written and its queries may run on different connections.
+    pub async fn begin(&self) -> SqlResult<()> {
+        self.transaction("BEGIN").await
+    }
+
+    /// Commit the current transaction.
+    pub async fn commit(&self) -> SqlResult<()> {
+        self.transaction("COMMIT").await
+    }
+
+    /// Roll back the current transaction, discarding its writes.
+    pub async fn rollback(&self) -> SqlResult<()> {
+        self.transaction("ROLLBACK").await
+    }
+
+    /// Execute a transaction statement, unless the database is read-only.
+    async fn transaction(&self, statement: &str) -> SqlResult<()> {
+        if !self.read_only {
+            self.pool.execute(statement).await?;
+        }
+        Ok(())
+    }
+
     /// Get a record from the database.
-    pub async fn get(&mut self, file_path: &str) -> SqlResult<Option<Record>> {
+    pub async fn get(&self, file_path: &str) -> SqlResult<Option<Record>> {
         let query = format!(
-            "SELECT file_path, file_hash, file_id, label, embedding FROM {TABLE_NAME} WHERE file_path = ?"
+            "SELECT file_path, file_hash, file_id, label, embedding, mtime, emojis FROM {TABLE_NAME} WHERE file_path = ?"
         );
         let query = sqlx::query_as::<_, Record>(query.as_str());
-        let result = query.bind(file_path).fetch_optional(&mut self.conn).await?;
+        let result = query.bind(file_path).fetch_optional(&self.pool).await?;
 
         Ok(result)
     }
 
-    /// Search for the top-N matches, returning the file path and similarity.
+    /// Stored modification time of every record, by path.
+    pub async fn mtimes(&self) -> SqlResult<HashMap<String, Option<i64>>> {
+        let query = format!("SELECT file_path, mtime FROM {TABLE_NAME}");
+        sqlx::query_as(query.as_str())
+            .fetch(&self.pool)
+            .try_collect()
+            .await
+    }
+
+    /// Get the records of several paths, in their order, skipping paths that aren't indexed and repeated ones.
+    ///
+    /// Looks up up to [`MAX_VARIABLES`] paths per query, instead of one like [`get`](Self::get).
+    pub async fn get_many(&self, file_paths: &[&str]) -> SqlResult<Vec<Record>> {
+        let mut found = HashMap::with_capacity(file_paths.len());
+        for chunk in file_paths.chunks(MAX_VARIABLES) {
+            let placeholders = vec!["?"; chunk.len()].join(", ");
+            let query = format!(
+                "SELECT file_path, file_hash, file_id, label, embedding, mtime, emojis FROM {TABLE_NAME} WHERE file_path IN ({placeholders})"
+            );
+            let mut query = sqlx::query_as::<_, Record>(query.as_str());
+            for file_path in chunk {
+                query = query.bind(file_path);
+            }
+            for record in query.fetch_all(&self.pool).await? {
+                found.insert(record.file_path.clone(), record);
+            }
+        }
+
+        Ok(file_paths
+            .iter()
+            .filter_map(|file_path| found.remove(*file_path))
+            .collect())
+    }
+
+    /// Build an approximate nearest-neighbor index from all embeddings, searching `probes` clusters per query, and return the number of clusters.
+    ///
+    /// Once built, [`search`](Self::search) and [`search_with_id`](Self::search_with_id) use the index instead of scanning all embeddings. Inserts and deletes through this connection keep it up to date, even if rolled back, but changes made by other processes are only picked up by rebuilding it.
+    pub async fn build_ann_index(&mut self, probes: usize) -> SqlResult<usize> {
+        let records: Vec<_> = self.iter_embeddings().try_collect().await?;
+        let ann = tokio::task::spawn_blocking(move || AnnIndex::build(records, probes))
+            .await
+            .map_err(io::Error::other)?;
+        let num_clusters = ann.num_clusters();
+        self.ann = Some(ann);
+
+        Ok(num_clusters)
+    }
+
+    /// Load all embeddings into memory, returning their number.
+    ///
+    /// Once loaded, [`search`](Self::search) and [`search_with_id`](Self::search_with_id) compare against them instead of reading every embedding from the database, unless an approximate nearest-neighbor index is built. Like the index, they are kept up to date by inserts and deletes through this connection, but changes made by other processes are only picked up by loading them again.
+    pub async fn load_embeddings(&mut self) -> SqlResult<usize> {
+        let embeddings: Vec<_> = self.iter_embeddings().try_collect().await?;
+        let count = embeddings.len();
+        self.embeddings = Some(embeddings);
+
+        Ok(count)
+    }
+
+    /// Search for the top-N matches among paths passing the filter, returning the file path and similarity.
     pub async fn search(
-        &mut self,
+        &self,
         n: usize,
         embedding: &Embedding,
+        filter: &PathFilter,
     ) -> SqlResult<Vec<(String, f32)>> {
+        if let Some(ann) = &self.ann {
+            return Ok(ann.search(n, embedding, |path| filter.matches(path)));
+        }
+        if let Some(embeddings) = &self.embeddings {
+            let mut results = TopK::new(n);
+            for (file_path, other_embedding) in embeddings {
+                if filter.matches(file_path) {
+                    results.push(file_path, embedding.cosine_similarity(other_embedding));
+                }
+            }
+            return Ok(results
+                .into_sorted_vec()
+                .into_iter()
+                .map(|(file_path, similarity)| (file_path.clone(), similarity))
+                .collect());
+        }
         let mut rows = self.iter_embeddings();
-        let mut results = Vec::with_capacity(n);
+        let mut results = TopK::new(n);
 
         while let Some(row) = rows.next().await {
             let (file_path, other_embedding) = row?;
-            let similarity = embedding.cosine_similarity(&other_embedding);
-            // Top N results
-            if results.len() < n {
-                results.push((file_path, similarity));
-            } else if results.last().unwrap().1 < similarity {
-                results.pop();
-                results.push((file_path, similarity));
+            if filter.matches(&file_path) {
+                let similarity = embedding.cosine_similarity(&other_embedding);
+                results.push(file_path, similarity);
             }
-            results.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+        }
+
+        Ok(results.into_sorted_vec())
+    }
+
+    /// Like [`search`](Self::search), but returning the stored metadata of each match instead of just its path.
+    pub async fn search_with_metadata(
+        &self,
+        n: usize,
+        embedding: &Embedding,
+        filter: &PathFilter,
+    ) -> SqlResult<Vec<(Metadata, f32)>> {
+        let matches = self.search(n, embedding, filter).await?;
+        let query = format!(
+            "SELECT file_path, file_hash, file_id, label, mtime, emojis FROM {TABLE_NAME} WHERE file_path = ?"
+        );
+        let mut results = Vec::with_capacity(matches.len());
+        for (file_path, similarity) in matches {
+            let metadata = sqlx::query_as::<_, Metadata>(query.as_str())
+                .bind(&file_path)
+                .fetch_one(&self.pool)
+                .await?;
+            results.push((metadata, similarity));
         }
 
         Ok(results)
     }
 
+    /// Find pairs of files whose similarity is at least `threshold`, most similar first.
+    ///
+    /// Compares every pair of files, so it takes quadratic time and loads all embeddings into memory.
+    pub async fn find_duplicates(&self, threshold: f32) -> SqlResult<Vec<(String, String, f32)>> {
+        let records: Vec<_> = self.iter_embeddings().try_collect().await?;
+        let mut pairs = Vec::new();
+
+        for (i, (file_path, embedding)) in records.iter().enumerate() {
+            for (other_path, other_embedding) in &records[i + 1..] {
+                let similarity = embedding.cosine_similarity(other_embedding);
+                if similarity >= threshold {
+                    pairs.push((file_path.clone(), other_path.clone(), similarity));
+                }
+            }
+        }
+        pairs.sort_by(|a, b| descending(a.2, b.2));
+
+        Ok(pairs)
+    }
+
     /// Delete a record from the database.
-    async fn delete(&mut self, file_path: &str) -> SqlResult<bool> {
+    pub async fn delete(&mut self, file_path: &str) -> SqlResult<bool> {
         let query = format!("DELETE FROM {TABLE_NAME} WHERE file_path = ?");
         let query = sqlx::query(query.as_str());
-        let result = query.bind(file_path).execute(&mut self.conn).await?;
+        let result = query.bind(file_path).execute(&self.pool).await?;
+        if let Some(embeddings) = &mut self.embeddings {
+            embeddings.retain(|(path, _)| path != file_path);
+        }
+        if let Some(ann) = &mut self.ann {
+            ann.remove(file_path);
+        }
 
         Ok(result.rows_affected() == 1)
     }
 
+    /// Move a record to another path, keeping its hash, label, embedding and other fields. Returns whether `old_path` was indexed.
+    ///
+    /// Fails if `new_path` is already indexed.
+    pub async fn rename(&mut self, old_path: &str, new_path: &str) -> SqlResult<bool> {
+        let query = format!(
+            "UPDATE {TABLE_NAME} SET file_path = ? WHERE file_path = ? RETURNING embedding"
+        );
+        let embedding: Option<Vec<u8>> = sqlx::query_scalar(query.as_str())
+            .bind(new_path)
+            .bind(old_path)
+            .fetch_optional(&self.pool)
+            .await?;
+        let Some(embedding) = embedding else {
+            return Ok(false);
+        };
+        if let Some(embeddings) = &mut self.embeddings
+            && let Some((path, _)) = embeddings.iter_mut().find(|(path, _)| path == old_path)
+        {
+            new_path.clone_into(path);
+        }
+        if let Some(ann) = &mut self.ann {
+            ann.remove(old_path);
+            let embedding = embedding
+                .as_slice()
+                .try_into()
+                .expect("Invalid embedding size");
+            ann.insert(new_path.to_owned(), embedding);
+        }
+
+        Ok(true)
+    }
+
+    /// Delete records with the given file id, including stickers added via the Telegram bot. Returns the number of deleted records.
+    pub async fn delete_by_file_id(&mut self, file_id: &str) -> SqlResult<u64> {
+        let query = format!(
+            "DELETE FROM {TABLE_NAME} WHERE file_id = ? OR file_path = ? RETURNING file_path"
+        );
+        let query = sqlx::query(query.as_str());
+        let rows = query
+            .bind(file_id)
+            .bind(format!("tg-sticker://{file_id}"))
+            .fetch_all(&self.pool)
+            .await?;
+        if let Some(embeddings) = &mut self.embeddings {
+            let removed: Vec<&str> = rows.iter().map(|row| row.get(0)).collect();
+            embeddings.retain(|(path, _)| !removed.contains(&path.as_str()));
+        }
+        if let Some(ann) = &mut self.ann {
+            for row in &rows {
+                ann.remove(row.get(0));
+            }
+        }
+
+        Ok(rows.len() as u64)
+    }
+
     /// Iterate over all records in the database. (path only)
     #[allow(
         clippy::iter_not_returning_iterator,
         reason = "It returns a stream, also called async iterator"
     )]
-    pub fn iter(&mut self) -> BoxStream<'_, SqlResult<String>> {
+    pub fn iter(&self) -> BoxStream<'_, SqlResult<String>> {
         let query = sqlx::query(queries::QUERY_PATH);
-        let result = query
-            .fetch(&mut self.conn)
+        query
+            .fetch(&self.pool)
             .map(|row| {
                 let row = row?;
                 Ok(row.get(0))
             })
-            .boxed();
-        result
+            .boxed()
     }
 
     /// Iterate over all records in the database, together with embeddings.
-    pub fn iter_embeddings(&mut self) -> BoxStream<'_, SqlResult<(String, Embedding)>> {
+    pub fn iter_embeddings(&self) -> BoxStream<'_, SqlResult<(String, Embedding)>> {
         let query = sqlx::query(queries::QUERY_EMBEDDING);
         query
-            .fetch(&mut self.conn)
+            .fetch(&self.pool)
             .map(|row| {
                 let row = row?;
                 let file_path: String = row.get(0);
@@ -247,12 +1076,18 @@ impl Database {
             .boxed()
     }
 
+    /// Iterate over all records in the database, including labels and embeddings.
+    pub fn iter_records(&self) -> BoxStream<'_, SqlResult<Record>> {
+        let query = sqlx::query_as::<_, Record>(queries::QUERY_RECORD);
+        query.fetch(&self.pool).boxed()
+    }
+
     /// Retrieve all records' paths without file id.
-    pub async fn paths_without_file_ids(&mut self) -> Vec<String> {
+    pub async fn paths_without_file_ids(&self) -> Vec<String> {
         let query = format!("SELECT file_path FROM {TABLE_NAME} WHERE file_id IS NULL");
         let query = sqlx::query(query.as_str());
         query
-            .fetch(&mut self.conn)
+            .fetch(&self.pool)
             .filter_map(|row| async {
                 match row {
                     Ok(row) => Some(row.get(0)),
@@ -266,26 +1101,39 @@ impl Database {
             .await
     }
 
-    /// Clean up the database, removing records that no longer exist on disk.
+    /// Find records that no longer exist on disk, without removing them.
+    ///
+    /// Relative paths are resolved against each of `roots`, and a record is only stale if its file exists under none of them, so that files under another root, or moved there, are kept. Absolute paths are checked as is, and stickers added from Telegram are never stale.
     #[allow(clippy::future_not_send, reason = "Should be `Send` if `T: Send`")]
-    pub async fn clean<T>(&mut self, ref_path: T) -> SqlResult<usize>
+    pub async fn stale<T>(&self, roots: &[T]) -> Vec<String>
     where
-        T: AsRef<Path>,
+        T: AsRef<Path> + Sync,
     {
-        let ref_path = ref_path.as_ref();
         let records = self.iter();
-        let to_delete: Vec<_> = records
+        records
             .filter_map(|path| async {
                 let path = path.ok()?;
                 if path.starts_with("tg-sticker://") {
-                    None
-                } else {
-                    let full_path = ref_path.join(&path);
-                    if full_path.exists() { None } else { Some(path) }
+                    return None;
                 }
+                let exists = if Path::new(&path).is_absolute() {
+                    Path::new(&path).exists()
+                } else {
+                    roots.iter().any(|root| root.as_ref().join(&path).exists())
+                };
+                if exists { None } else { Some(path) }
             })
             .collect()
-            .await;
+            .await
+    }
+
+    /// Clean up the database, removing records that no longer exist under any of `roots`, as found by [`stale`](Self::stale).
+    #[allow(clippy::future_not_send, reason = "Should be `Send` if `T: Send`")]
+    pub async fn clean<T>(&mut self, roots: &[T]) -> SqlResult<usize>
+    where
+        T: AsRef<Path> + Sync,
+    {
+        let to_delete = self.stale(roots).await;
         let count = to_delete.len();
 
         for path in to_delete {
@@ -295,17 +1143,31 @@ impl Database {
         Ok(count)
     }
 
-    /// Search for the top-N matches, returning the file path, similarity and file id, ensuring file id exists.
+    /// Search for the top-N matches, returning the file path, similarity and file id, if any.
     pub async fn search_with_id(
-        &mut self,
+        &self,
         n: usize,
         embedding: &Embedding,
-    ) -> SqlResult<Vec<(String, f32, String)>> {
+    ) -> SqlResult<Vec<(String, f32, Option<String>)>> {
+        if self.ann.is_some() || self.embeddings.is_some() {
+            let matches = self.search(n, embedding, &PathFilter::default()).await?;
+            let query = format!("SELECT file_id FROM {TABLE_NAME} WHERE file_path = ?");
+            let mut results = Vec::with_capacity(matches.len());
+            for (file_path, similarity) in matches {
+                let file_id: Option<String> = sqlx::query_scalar(query.as_str())
+                    .bind(&file_path)
+                    .fetch_optional(&self.pool)
+                    .await?
+                    .flatten();
+                results.push((file_path, similarity, file_id));
+            }
+            return Ok(results);
+        }
         let query = format!("SELECT file_path, embedding, file_id FROM {TABLE_NAME}");
         let query = sqlx::query(query.as_str());
-        let mut rows = query.fetch(&mut self.conn);
+        let mut rows = query.fetch(&self.pool);
 
-        let mut results = Vec::with_capacity(n);
+        let mut results = TopK::new(n);
         while let Some(row) = rows.next().await {
             let row = row?;
             let file_path: String = row.get(0);
@@ -313,36 +1175,84 @@ impl Database {
             let other_embedding: Embedding =
                 other_embedding.try_into().expect("Invalid embedding size");
             let similarity = embedding.cosine_similarity(&other_embedding);
-            let file_id: String = row.get(2);
-            // Top N results
-            if results.len() < n {
-                results.push((file_path, similarity, file_id));
-            } else if results.last().unwrap().1 < similarity {
-                results.pop();
-                results.push((file_path, similarity, file_id));
-            }
-            results.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+            let file_id: Option<String> = row.get(2);
+            results.push((file_path, file_id), similarity);
         }
 
-        Ok(results)
+        Ok(results
+            .into_sorted_vec()
+            .into_iter()
+            .map(|((file_path, file_id), similarity)| (file_path, similarity, file_id))
+            .collect())
     }
 
     /// Sets file id for a record.
-    pub async fn set_file_id(&mut self, file_path: &str, file_id: &str) -> SqlResult<bool> {
+    pub async fn set_file_id(&self, file_path: &str, file_id: &str) -> SqlResult<bool> {
         let query = format!("UPDATE {TABLE_NAME} SET file_id = ? WHERE file_path = ?");
         let query = sqlx::query(query.as_str());
         let result = query
             .bind(Some(file_id))
             .bind(file_path)
-            .execute(&mut self.conn)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Sets the sticker set a record's sticker is kept in.
+    pub async fn set_sticker_set(&self, file_path: &str, sticker_set: &str) -> SqlResult<bool> {
+        let query = format!("UPDATE {TABLE_NAME} SET sticker_set = ? WHERE file_path = ?");
+        let query = sqlx::query(query.as_str());
+        let result = query
+            .bind(sticker_set)
+            .bind(file_path)
+            .execute(&self.pool)
             .await?;
 
         Ok(result.rows_affected() == 1)
     }
 
-    /// Close the database connection.
+    /// Count a search query made by a user.
+    pub async fn count_query(&self, user_id: u64) -> SqlResult<()> {
+        let query = format!(
+            "INSERT INTO {USAGE_TABLE_NAME} (user_id, queries) VALUES (?, 1) ON CONFLICT(user_id) DO UPDATE SET queries = queries + 1"
+        );
+        let query = sqlx::query(query.as_str());
+        query
+            .bind(user_id.cast_signed())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Usage statistics, listing at most `n` of the most active users.
+    pub async fn usage(&self, n: usize) -> SqlResult<Usage> {
+        let query = format!("SELECT COALESCE(SUM(queries), 0), COUNT(*) FROM {USAGE_TABLE_NAME}");
+        let (total, users): (i64, i64) =
+            sqlx::query_as(query.as_str()).fetch_one(&self.pool).await?;
+        let query = format!(
+            "SELECT user_id, queries FROM {USAGE_TABLE_NAME} ORDER BY queries DESC, user_id LIMIT ?"
+        );
+        let top: Vec<(i64, i64)> = sqlx::query_as(query.as_str())
+            .bind(i64::try_from(n).unwrap_or(i64::MAX))
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(Usage {
+            total: total.cast_unsigned(),
+            users: users.cast_unsigned(),
+            top: top
+                .into_iter()
+                .map(|(user_id, queries)| (user_id.cast_unsigned(), queries.cast_unsigned()))
+                .collect(),
+        })
+    }
+
+    /// Close the database connections.
     pub async fn close(self) -> SqlResult<()> {
-        self.conn.close().await
+        self.pool.close().await;
+        Ok(())
     }
 }
 
@@ -350,11 +1260,27 @@ impl Database {
 mod queries {
     pub const QUERY_PATH: &str = "SELECT file_path FROM files";
     pub const QUERY_EMBEDDING: &str = "SELECT file_path, embedding FROM files";
+    pub const QUERY_RECORD: &str =
+        "SELECT file_path, file_hash, file_id, label, embedding, mtime, emojis FROM files";
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use sqlx::Connection;
+
+    /// Record at `path` with `hash` and `embedding`, labeled `test_label`, without file id, modification time or emojis, for tests that only care about some fields.
+    fn record(path: &str, hash: &str, embedding: Embedding) -> Record {
+        Record {
+            file_path: path.to_owned(),
+            file_hash: hash.to_owned(),
+            file_id: None,
+            label: "test_label".to_owned(),
+            embedding,
+            mtime: None,
+            emojis: None,
+        }
+    }
 
     #[cfg(not(windows))]
     #[test]
@@ -368,15 +1294,35 @@ mod tests {
         );
     }
 
+    #[test]
+    fn hidden() {
+        assert!(is_hidden(Path::new("dir/.hidden")));
+        assert!(!is_hidden(Path::new("dir/visible")));
+        // No file name component
+        assert!(!is_hidden(Path::new("dir/..")));
+        assert!(!is_hidden(Path::new("/")));
+    }
+
+    #[tokio::test]
+    async fn hash_license_parallel() {
+        let files = vec![PathBuf::from("../LICENSE"), PathBuf::from("../README.md")];
+        let hashes = hash_files(files.clone(), 2).await.unwrap();
+        let expected: Vec<_> = files.iter().map(|f| hash_file(f).unwrap()).collect();
+
+        assert_eq!(hashes, expected);
+    }
+
     #[tokio::test]
     async fn test_db() {
-        let mut db = Database::dummy().await.unwrap();
+        let mut db = Database::memory().await.unwrap();
         let mut record = Record {
             file_path: "test_file_path".to_owned(),
             file_hash: "test_file_hash".to_owned(),
             file_id: None,
             label: "test_label".to_owned(),
             embedding: Embedding::default(),
+            mtime: None,
+            emojis: None,
         };
         let record2 = Record {
             file_path: "test_file_path2".to_owned(),
@@ -384,6 +1330,8 @@ mod tests {
             file_id: None,
             label: "test_label2".to_owned(),
             embedding: Embedding::from([2.3; 1024]),
+            mtime: None,
+            emojis: None,
         };
 
         // Insert record
@@ -410,4 +1358,652 @@ mod tests {
         let result = db.get(&record2.file_path).await.unwrap().unwrap();
         assert_eq!(result, record2);
     }
+
+    #[test]
+    fn nan_ranked_last() {
+        let mut similarities = [0.5, f32::NAN, 1.0, -1.0];
+        similarities.sort_by(|a, b| descending(*a, *b));
+        assert_eq!(similarities[..3], [1.0, 0.5, -1.0]);
+        assert!(similarities[3].is_nan());
+    }
+
+    #[tokio::test]
+    async fn search_zero_embedding() {
+        let mut db = Database::memory().await.unwrap();
+        for (path, embedding) in [("zero", [0.0; 1024]), ("one", [1.0; 1024])] {
+            db.insert(record(path, "test_file_hash", Embedding::from(embedding)))
+                .await
+                .unwrap();
+        }
+
+        // Zero vectors have zero similarity with anything, instead of NaN
+        let filter = PathFilter::default();
+        let results = db
+            .search(2, &Embedding::from([1.0; 1024]), &filter)
+            .await
+            .unwrap();
+        assert_eq!(results[0].0, "one");
+        assert_eq!(results[1], ("zero".to_owned(), 0.0));
+        let results = db.search(2, &Embedding::default(), &filter).await.unwrap();
+        assert!(results.iter().all(|(_, similarity)| *similarity == 0.0));
+    }
+
+    #[test]
+    fn path_filter() {
+        let filter = PathFilter {
+            under: Some(PathBuf::from("docs")),
+            glob: None,
+            exclude: Vec::new(),
+        };
+        assert!(filter.matches("docs/a.txt"));
+        assert!(filter.matches("docs/nested/b.txt"));
+        assert!(!filter.matches("docs2/a.txt"));
+        assert!(!filter.matches("a.txt"));
+
+        let filter = PathFilter {
+            under: Some(PathBuf::from("docs")),
+            glob: Some(Pattern::new("*.md").unwrap()),
+            exclude: Vec::new(),
+        };
+        assert!(filter.matches("docs/nested/readme.md"));
+        assert!(!filter.matches("docs/a.txt"));
+        assert!(!filter.matches("readme.md"));
+
+        let filter = PathFilter {
+            under: None,
+            glob: None,
+            exclude: vec![
+                Pattern::new("docs/drafts/*").unwrap(),
+                Pattern::new("*.tmp").unwrap(),
+            ],
+        };
+        assert!(filter.matches("docs/a.txt"));
+        assert!(!filter.matches("docs/drafts/nested/a.txt"));
+        assert!(!filter.matches("a.tmp"));
+    }
+
+    #[tokio::test]
+    async fn search_filtered() {
+        let mut db = Database::memory().await.unwrap();
+        for (path, value) in [("a/1", 1.0), ("a/2", 0.5), ("b/1", 1.0), ("b/2", 1.0)] {
+            let mut embedding = [1.0; 1024];
+            embedding[0] = value;
+            db.insert(record(path, "test_file_hash", Embedding::from(embedding)))
+                .await
+                .unwrap();
+        }
+
+        // Top-N is computed over the filtered set, so `a/2` is still included
+        let filter = PathFilter {
+            under: Some(PathBuf::from("a")),
+            glob: None,
+            exclude: Vec::new(),
+        };
+        let results = db
+            .search(2, &Embedding::from([1.0; 1024]), &filter)
+            .await
+            .unwrap();
+        let paths: Vec<_> = results.iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(paths, ["a/1", "a/2"]);
+
+        // Same order with metadata
+        let results = db
+            .search_with_metadata(2, &Embedding::from([1.0; 1024]), &filter)
+            .await
+            .unwrap();
+        let paths: Vec<_> = results
+            .iter()
+            .map(|(metadata, _)| metadata.file_path.as_str())
+            .collect();
+        assert_eq!(paths, ["a/1", "a/2"]);
+        assert_eq!(results[0].0.label, "test_label");
+        assert_eq!(results[0].0.file_hash, "test_file_hash");
+
+        // Excluded files don't take up any of the top-N
+        let filter = PathFilter {
+            exclude: vec![Pattern::new("b/*").unwrap()],
+            ..PathFilter::default()
+        };
+        let results = db
+            .search(2, &Embedding::from([1.0; 1024]), &filter)
+            .await
+            .unwrap();
+        let paths: Vec<_> = results.iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(paths, ["a/1", "a/2"]);
+    }
+
+    #[tokio::test]
+    async fn ann_index() {
+        let mut db = Database::memory().await.unwrap();
+        for (path, value) in [("a", 1.0), ("b", 0.5), ("c", -1.0)] {
+            let mut embedding = [1.0; 1024];
+            embedding[0] = value;
+            let record = Record {
+                file_id: Some(format!("id_{path}")),
+                ..record(path, "test_file_hash", Embedding::from(embedding))
+            };
+            db.insert(record).await.unwrap();
+        }
+        let query = Embedding::from([1.0; 1024]);
+        let exact = db.search_with_id(3, &query).await.unwrap();
+
+        // Probing every cluster gives the same results as an exact search
+        assert_eq!(db.build_ann_index(usize::MAX).await.unwrap(), 1);
+        assert_eq!(db.search_with_id(3, &query).await.unwrap(), exact);
+
+        // Deletes are reflected in the index
+        assert_eq!(db.delete_by_file_id("id_a").await.unwrap(), 1);
+        let results = db.search_with_id(3, &query).await.unwrap();
+        assert_eq!(results, exact[1..]);
+    }
+
+    #[tokio::test]
+    async fn get_many() {
+        let mut db = Database::memory().await.unwrap();
+        let paths: Vec<_> = (0..=MAX_VARIABLES).map(|i| format!("file_{i}")).collect();
+        for path in &paths {
+            let record = record(path, "test_file_hash", Embedding::from([1.0; 1024]));
+            db.insert(record).await.unwrap();
+        }
+
+        // Spanning more than one query, in the given order
+        let mut wanted: Vec<_> = paths.iter().map(String::as_str).rev().collect();
+        let records = db.get_many(&wanted).await.unwrap();
+        let found: Vec<_> = records
+            .iter()
+            .map(|record| record.file_path.as_str())
+            .collect();
+        assert_eq!(found, wanted);
+
+        // Missing and repeated paths are skipped
+        wanted = vec!["file_2", "missing", "file_1", "file_2"];
+        let records = db.get_many(&wanted).await.unwrap();
+        let found: Vec<_> = records
+            .iter()
+            .map(|record| record.file_path.as_str())
+            .collect();
+        assert_eq!(found, ["file_2", "file_1"]);
+        assert!(db.get_many(&[]).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn mtimes() {
+        let mut db = Database::memory().await.unwrap();
+        for (path, mtime) in [("a", Some(1)), ("b", None)] {
+            let record = Record {
+                mtime,
+                ..record(path, "test_file_hash", Embedding::from([1.0; 1024]))
+            };
+            db.insert(record).await.unwrap();
+        }
+
+        let mtimes = db.mtimes().await.unwrap();
+        assert_eq!(mtimes.len(), 2);
+        assert_eq!(mtimes["a"], Some(1));
+        assert_eq!(mtimes["b"], None);
+    }
+
+    #[tokio::test]
+    async fn rename() {
+        let mut db = Database::memory().await.unwrap();
+        for path in ["a", "b"] {
+            let record = Record {
+                file_id: Some(format!("id_{path}")),
+                label: format!("label_{path}"),
+                mtime: Some(1),
+                ..record(path, &format!("hash_{path}"), Embedding::from([1.0; 1024]))
+            };
+            db.insert(record).await.unwrap();
+        }
+        let before = db.get("a").await.unwrap().unwrap();
+
+        assert!(db.rename("a", "c").await.unwrap());
+        assert!(db.get("a").await.unwrap().is_none());
+        let after = db.get("c").await.unwrap().unwrap();
+        assert_eq!(
+            after,
+            Record {
+                file_path: "c".to_owned(),
+                ..before
+            }
+        );
+
+        // Missing source, or existing destination
+        assert!(!db.rename("a", "d").await.unwrap());
+        assert!(db.rename("b", "c").await.is_err());
+        assert!(db.get("b").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn in_memory() {
+        let mut db = Database::memory().await.unwrap();
+        let record = |path: &str, value: f32| {
+            let mut embedding = [1.0; 1024];
+            embedding[0] = value;
+            Record {
+                file_id: Some(format!("id_{path}")),
+                ..record(path, "test_file_hash", Embedding::from(embedding))
+            }
+        };
+        for (path, value) in [("a", 1.0), ("b", 0.5), ("c", -1.0)] {
+            db.insert(record(path, value)).await.unwrap();
+        }
+        let query = Embedding::from([1.0; 1024]);
+        let exact = db.search_with_id(2, &query).await.unwrap();
+
+        // Same results as reading embeddings from the database
+        assert_eq!(db.load_embeddings().await.unwrap(), 3);
+        assert_eq!(db.search_with_id(2, &query).await.unwrap(), exact);
+
+        // Inserts, replacements and deletes are reflected in memory
+        db.insert(record("c", 0.9)).await.unwrap();
+        db.insert(record("d", 0.0)).await.unwrap();
+        assert!(db.delete("a").await.unwrap());
+        let results = db.search(4, &query, &PathFilter::default()).await.unwrap();
+        let paths: Vec<_> = results.iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(paths, ["c", "b", "d"]);
+    }
+
+    #[test]
+    fn top_k() {
+        let mut top = TopK::new(2);
+        for (item, similarity) in [("a", 0.1), ("b", f32::NAN), ("c", 0.9), ("d", 0.5)] {
+            top.push(item, similarity);
+        }
+        let items: Vec<_> = top
+            .into_sorted_vec()
+            .into_iter()
+            .map(|(item, _)| item)
+            .collect();
+        assert_eq!(items, ["c", "d"]);
+
+        let mut top = TopK::new(0);
+        top.push("a", 1.0);
+        assert!(top.into_sorted_vec().is_empty());
+    }
+
+    #[tokio::test]
+    async fn find_duplicates() {
+        let mut db = Database::memory().await.unwrap();
+        for (path, value) in [("a", 1.0), ("b", 1.0), ("c", -1.0)] {
+            let record = record(path, "test_file_hash", Embedding::from([value; 1024]));
+            db.insert(record).await.unwrap();
+        }
+
+        let pairs = db.find_duplicates(0.98).await.unwrap();
+        assert_eq!(pairs.len(), 1);
+        assert_eq!((pairs[0].0.as_str(), pairs[0].1.as_str()), ("a", "b"));
+    }
+
+    #[test]
+    fn group_duplicates() {
+        let pair = |a: &str, b: &str| (a.to_owned(), b.to_owned(), 0.99);
+        let pairs = [
+            pair("c", "b"),
+            pair("x", "y"),
+            pair("a", "c"),
+            pair("d", "b"),
+        ];
+        let groups = super::group_duplicates(&pairs);
+        assert_eq!(groups, [vec!["a", "b", "c", "d"], vec!["x", "y"]]);
+        assert!(super::group_duplicates(&[]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn migrate_columns() {
+        let pool = Database::pool_options(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        let mut conn = pool.acquire().await.unwrap();
+        let query = format!(
+            "CREATE TABLE {TABLE_NAME} (
+            file_path TEXT PRIMARY KEY,
+            file_hash TEXT NOT NULL,
+            file_id TEXT,
+            label TEXT NOT NULL,
+            embedding BLOB NOT NULL
+            )"
+        );
+        conn.execute(query.as_str()).await.unwrap();
+        assert!(Database::is_outdated(&mut conn).await.unwrap());
+
+        Database::migrate(&mut conn).await.unwrap();
+        assert!(!Database::is_outdated(&mut conn).await.unwrap());
+        drop(conn);
+        let mut db = Database {
+            pool,
+            read_only: false,
+            _lock: None,
+            ann: None,
+            embeddings: None,
+            dir: None,
+            root: None,
+        };
+        let record = Record {
+            mtime: Some(42),
+            emojis: Some("😹 😼".to_owned()),
+            ..record("test_file_path", "test_file_hash", Embedding::default())
+        };
+        db.insert(record.clone()).await.unwrap();
+        assert_eq!(db.get(&record.file_path).await.unwrap(), Some(record));
+    }
+
+    #[test]
+    fn iter_files() {
+        let dir = std::env::temp_dir().join(format!("sense-iter-files-{}", std::process::id()));
+        for file in [
+            "cat.jpg",
+            ".config/notes.txt",
+            ".sense/config.toml",
+            ".sense/index.db3",
+            "custom/config.toml",
+            "custom/index.db3",
+            "custom/index.db3-wal",
+            "custom/index.lock",
+        ] {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "").unwrap();
+        }
+        let dir = dir.canonicalize().unwrap();
+        let files = |options: &WalkOptions| {
+            let mut files: Vec<_> = super::iter_files(&dir, &dir, options)
+                .map(|(_, relative)| relative)
+                .collect();
+            files.sort();
+            files
+        };
+
+        let options = WalkOptions::new(false);
+        assert_eq!(
+            files(&options),
+            [
+                "cat.jpg",
+                "custom/config.toml",
+                "custom/index.db3",
+                "custom/index.db3-wal",
+                "custom/index.lock",
+            ]
+        );
+
+        // `.sense` is skipped even with hidden files, and so are the configured database and config
+        let options = WalkOptions::new(true)
+            .exclude(&dir.join("custom/config.toml"))
+            .exclude_database(&dir.join("custom/index.db3"));
+        assert_eq!(files(&options), [".config/notes.txt", "cat.jpg"]);
+
+        // Indexed files that would now be skipped or filtered out are ignored
+        assert!(!options.ignores(&dir, "cat.jpg"));
+        assert!(options.ignores(&dir, "custom/index.db3"));
+        assert!(options.ignores(&dir, ".sense/index.db3"));
+        let options = WalkOptions {
+            no_ext: vec!["png,.JPG".to_owned()],
+            ..WalkOptions::new(false)
+        };
+        assert!(options.ignores(&dir, ".config/notes.txt"));
+        assert!(options.ignores(&dir, "cat.jpg"));
+        assert!(!options.ignores(&dir, "custom/config.toml"));
+        // Directories are only skipped, never filtered out
+        assert!(!options.skips_relative(&dir, "cat.jpg"));
+        assert!(options.skips_relative(&dir, ".config"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn root() {
+        let dir = std::env::temp_dir().join(format!("sense-root-{}", std::process::id()));
+        let project = dir.join("project");
+        std::fs::create_dir_all(project.join(".sense")).unwrap();
+        std::fs::write(project.join("cat.jpg"), "").unwrap();
+        let path = project.join(".sense/index.db3");
+
+        let mut db = Database::open(&path, false, true).await.unwrap();
+        assert_eq!(db.root(), None);
+        assert_eq!(db.resolve("cat.jpg"), Path::new("cat.jpg"));
+        db.set_root(&project).await.unwrap();
+        let project = project.canonicalize().unwrap();
+        assert_eq!(db.root(), Some(project.as_path()));
+        assert_eq!(db.resolve("cat.jpg"), project.join("cat.jpg"));
+        let mut conn = db.pool.acquire().await.unwrap();
+        let stored = Database::read_root(&mut conn).await.unwrap();
+        assert_eq!(stored.as_deref(), Some(".."));
+        drop(conn);
+        db.close().await.unwrap();
+
+        // Moving the project along with its database keeps the root
+        let moved = dir.join("moved");
+        std::fs::rename(&project, &moved).unwrap();
+        let db = Database::open(moved.join(".sense/index.db3"), true, true)
+            .await
+            .unwrap();
+        let moved = moved.canonicalize().unwrap();
+        assert_eq!(db.root(), Some(moved.as_path()));
+        assert!(db.resolve("cat.jpg").exists());
+        db.close().await.unwrap();
+
+        // A root not containing the database is stored as is
+        let mut db = Database::memory().await.unwrap();
+        db.set_root(&moved).await.unwrap();
+        let mut conn = db.pool.acquire().await.unwrap();
+        let stored = Database::read_root(&mut conn).await.unwrap();
+        assert_eq!(stored.as_deref(), moved.to_str());
+        drop(conn);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn lock() {
+        let dir = std::env::temp_dir().join(format!("sense-lock-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("index.db3");
+
+        let db = Database::open(&path, false, true).await.unwrap();
+        // Writers are excluded, readers are not
+        assert!(Database::open(&path, false, true).await.is_err());
+        Database::open(&path, true, true).await.unwrap();
+        // Released on drop
+        drop(db);
+        Database::open(&path, false, true).await.unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn open_outdated() {
+        let dir = std::env::temp_dir().join(format!("sense-outdated-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("index.db3");
+        let options = SqliteConnectOptions::new()
+            .filename(&path)
+            .create_if_missing(true);
+        let mut conn = SqliteConnection::connect_with(&options).await.unwrap();
+        let query = format!(
+            "CREATE TABLE {TABLE_NAME} (
+            file_path TEXT PRIMARY KEY,
+            file_hash TEXT NOT NULL,
+            file_id TEXT,
+            label TEXT NOT NULL,
+            embedding BLOB NOT NULL
+            )"
+        );
+        conn.execute(query.as_str()).await.unwrap();
+        conn.close().await.unwrap();
+
+        // Read-only opens leave the schema as is
+        let Err(sqlx::Error::Io(error)) = Database::open(&path, true, false).await else {
+            panic!("Opened an outdated database as read-only");
+        };
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("sense index"));
+        assert!(!dir.join("index.lock").exists());
+
+        drop(Database::open(&path, false, false).await.unwrap());
+        Database::open(&path, true, false).await.unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn busy_guard() {
+        static BUSY: AtomicBool = AtomicBool::new(false);
+        let guard = BusyGuard::acquire(&BUSY).unwrap();
+        // Failing to acquire leaves the flag set
+        assert!(BusyGuard::acquire(&BUSY).is_none());
+        assert!(BUSY.load(AtomicOrdering::Acquire));
+        drop(guard);
+        assert!(!BUSY.load(AtomicOrdering::Acquire));
+
+        // Cleared on panic
+        std::panic::catch_unwind(|| {
+            let _guard = BusyGuard::acquire(&BUSY).unwrap();
+            panic!("Reindex failed");
+        })
+        .unwrap_err();
+        assert!(BusyGuard::acquire(&BUSY).is_some());
+    }
+
+    #[tokio::test]
+    async fn open_existing() {
+        let dir = std::env::temp_dir().join(format!("sense-existing-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("index.db3");
+
+        let Err(sqlx::Error::Io(error)) = Database::open_existing(&path, false).await else {
+            panic!("Opened a missing database");
+        };
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert!(!path.exists());
+        drop(Database::open(&path, false, false).await.unwrap());
+        Database::open_existing(&path, false).await.unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn usage() {
+        let db = Database::memory().await.unwrap();
+        for user_id in [1, 2, 2, 3, 3, 3] {
+            db.count_query(user_id).await.unwrap();
+        }
+        let usage = db.usage(2).await.unwrap();
+        assert_eq!(
+            usage,
+            Usage {
+                total: 6,
+                users: 3,
+                top: vec![(3, 3), (2, 2)],
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn delete_by_file_id() {
+        let mut db = Database::memory().await.unwrap();
+        let records = [
+            ("a.jpg", Some("id_a")),
+            ("tg-sticker://id_b", None),
+            ("c.jpg", None),
+        ];
+        for (file_path, file_id) in records {
+            db.insert(Record {
+                file_id: file_id.map(str::to_owned),
+                ..record(file_path, "hash", Embedding::default())
+            })
+            .await
+            .unwrap();
+        }
+
+        assert_eq!(db.delete_by_file_id("id_a").await.unwrap(), 1);
+        assert_eq!(db.delete_by_file_id("id_b").await.unwrap(), 1);
+        assert_eq!(db.delete_by_file_id("id_c").await.unwrap(), 0);
+        assert!(db.get("c.jpg").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn clean() {
+        let dir = std::env::temp_dir().join(format!("sense-clean-{}", std::process::id()));
+        let (root_a, root_b) = (dir.join("a"), dir.join("b"));
+        std::fs::create_dir_all(root_a.join("sub")).unwrap();
+        std::fs::create_dir_all(&root_b).unwrap();
+        std::fs::write(root_a.join("sub/only-a.jpg"), "").unwrap();
+        std::fs::write(root_b.join("only-b.jpg"), "").unwrap();
+        std::fs::write(dir.join("absolute.jpg"), "").unwrap();
+
+        let mut db = Database::memory().await.unwrap();
+        let absolute = dir.join("absolute.jpg").to_string_lossy().into_owned();
+        let paths = [
+            "sub/only-a.jpg",
+            "only-b.jpg",
+            "gone.jpg",
+            "tg-sticker://id",
+            absolute.as_str(),
+        ];
+        for file_path in paths {
+            db.insert(record(file_path, "hash", Embedding::default()))
+                .await
+                .unwrap();
+        }
+
+        // Files under one root are not stale because of the other
+        let mut stale = db.stale(&[&root_a, &root_b]).await;
+        stale.sort();
+        assert_eq!(stale, ["gone.jpg"]);
+        let mut stale = db.stale(&[&root_a]).await;
+        stale.sort();
+        assert_eq!(stale, ["gone.jpg", "only-b.jpg"]);
+
+        // A file moved to another root is kept
+        std::fs::rename(root_a.join("sub"), root_b.join("sub")).unwrap();
+        assert_eq!(db.clean(&[&root_a, &root_b]).await.unwrap(), 1);
+        assert!(db.get("sub/only-a.jpg").await.unwrap().is_some());
+        assert!(db.get("gone.jpg").await.unwrap().is_none());
+
+        // Until it is gone from every root
+        std::fs::remove_dir_all(root_b.join("sub")).unwrap();
+        assert_eq!(db.clean(&[&root_a, &root_b]).await.unwrap(), 1);
+        assert!(db.get("sub/only-a.jpg").await.unwrap().is_none());
+        assert!(db.get(&absolute).await.unwrap().is_some());
+        assert!(db.get("tg-sticker://id").await.unwrap().is_some());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_transaction() {
+        let mut db = Database::memory().await.unwrap();
+        let record = record(
+            "test_file_path",
+            "test_file_hash",
+            Embedding::from([1.2; 1024]),
+        );
+
+        // Rolled back writes are discarded
+        db.begin().await.unwrap();
+        db.insert(record.clone()).await.unwrap();
+        db.rollback().await.unwrap();
+        assert_eq!(db.get(&record.file_path).await.unwrap(), None);
+
+        // Committed writes are persisted
+        db.begin().await.unwrap();
+        db.insert(record.clone()).await.unwrap();
+        db.commit().await.unwrap();
+        assert_eq!(db.get(&record.file_path).await.unwrap(), Some(record));
+    }
+
+    #[test]
+    fn project_dir() {
+        let dir = std::env::temp_dir().join(format!("sense-project-{}", std::process::id()));
+        let nested = dir.join("photos/2024");
+        std::fs::create_dir_all(&nested).unwrap();
+        std::fs::create_dir_all(dir.join(DATA_DIR)).unwrap();
+        // A file named like the data directory is not a project
+        std::fs::write(dir.join("photos").join(DATA_DIR), "").unwrap();
+
+        assert_eq!(find_project_dir(&nested), Some(dir.clone()));
+        assert_eq!(find_project_dir(&dir), Some(dir.clone()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
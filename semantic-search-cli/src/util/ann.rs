@@ -0,0 +1,221 @@
+//! In-memory approximate nearest-neighbor index over embeddings.
+
+use super::descending;
+use semantic_search::Embedding;
+use std::{num::NonZeroUsize, thread};
+
+/// Number of k-means iterations when training centroids.
+const ITERATIONS: usize = 8;
+/// Number of sampled embeddings per cluster used for training centroids.
+const SAMPLES_PER_CLUSTER: usize = 64;
+
+/// An inverted file (IVF) index.
+///
+/// Embeddings are grouped into about `√n` clusters with k-means, and a query is only compared against members of the `probes` clusters whose centroids are most similar to it. Results are approximate, as a close neighbor may lie in a cluster that isn't probed.
+#[derive(Debug)]
+pub struct AnnIndex {
+    /// Number of clusters to search per query
+    probes: usize,
+    /// Centroid of each cluster
+    centroids: Vec<Embedding>,
+    /// File paths and embeddings in each cluster
+    clusters: Vec<Vec<(String, Embedding)>>,
+}
+
+impl AnnIndex {
+    /// Build an index over the given file paths and embeddings, searching `probes` clusters per query.
+    pub fn build(records: Vec<(String, Embedding)>, probes: usize) -> Self {
+        let embeddings: Vec<_> = records.iter().map(|(_, embedding)| embedding).collect();
+        let centroids = train(&embeddings, records.len().isqrt());
+        let assignments = assign(&centroids, &embeddings);
+        let mut clusters = vec![Vec::new(); centroids.len()];
+        for (record, cluster) in records.into_iter().zip(assignments) {
+            clusters[cluster].push(record);
+        }
+
+        Self {
+            probes: probes.max(1),
+            centroids,
+            clusters,
+        }
+    }
+
+    /// Number of clusters.
+    pub const fn num_clusters(&self) -> usize {
+        self.centroids.len()
+    }
+
+    /// Find the `n` most similar embeddings whose path matches `filter`, most similar first.
+    pub fn search<F>(&self, n: usize, embedding: &Embedding, filter: F) -> Vec<(String, f32)>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let mut centroids: Vec<_> = self
+            .centroids
+            .iter()
+            .map(|centroid| centroid.cosine_similarity(embedding))
+            .enumerate()
+            .collect();
+        centroids.sort_by(|a, b| descending(a.1, b.1));
+
+        let mut results: Vec<_> = centroids
+            .iter()
+            .take(self.probes)
+            .flat_map(|&(cluster, _)| &self.clusters[cluster])
+            .filter(|(file_path, _)| filter(file_path))
+            .map(|(file_path, other)| (file_path, embedding.cosine_similarity(other)))
+            .collect();
+        results.sort_by(|a, b| descending(a.1, b.1));
+        results
+            .into_iter()
+            .take(n)
+            .map(|(file_path, similarity)| (file_path.clone(), similarity))
+            .collect()
+    }
+
+    /// Add or replace the embedding of a file, in the cluster with the most similar centroid. Centroids are not updated.
+    pub fn insert(&mut self, file_path: String, embedding: Embedding) {
+        self.remove(&file_path);
+        if self.centroids.is_empty() {
+            self.centroids.push(embedding.clone());
+            self.clusters.push(Vec::new());
+        }
+        let cluster = nearest(&self.centroids, &embedding);
+        self.clusters[cluster].push((file_path, embedding));
+    }
+
+    /// Remove the embedding of a file, if present.
+    pub fn remove(&mut self, file_path: &str) {
+        for cluster in &mut self.clusters {
+            cluster.retain(|(path, _)| path != file_path);
+        }
+    }
+}
+
+/// Pick `k` centroids with spherical k-means, trained on an evenly spaced sample of `embeddings`.
+fn train(embeddings: &[&Embedding], k: usize) -> Vec<Embedding> {
+    if embeddings.is_empty() {
+        return Vec::new();
+    }
+    let step = (embeddings.len() / (k * SAMPLES_PER_CLUSTER)).max(1);
+    let sample: Vec<_> = embeddings.iter().step_by(step).copied().collect();
+    let mut centroids: Vec<Embedding> = sample
+        .iter()
+        .step_by((sample.len() / k).max(1))
+        .take(k)
+        .map(|&embedding| embedding.clone())
+        .collect();
+
+    for _ in 0..ITERATIONS {
+        let assignments = assign(&centroids, &sample);
+        let mut sums = vec![[0.0; 1024]; centroids.len()];
+        let mut counts = vec![0_usize; centroids.len()];
+        for (embedding, cluster) in sample.iter().zip(assignments) {
+            let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+            if norm == 0.0 {
+                continue;
+            }
+            for (sum, x) in sums[cluster].iter_mut().zip(embedding.iter()) {
+                *sum += x / norm;
+            }
+            counts[cluster] += 1;
+        }
+        // Empty clusters keep their previous centroid
+        for ((centroid, sum), count) in centroids.iter_mut().zip(sums).zip(counts) {
+            if count > 0 {
+                *centroid = sum.into();
+            }
+        }
+    }
+
+    centroids
+}
+
+/// Index of the most similar centroid for each embedding, computed in parallel.
+fn assign(centroids: &[Embedding], embeddings: &[&Embedding]) -> Vec<usize> {
+    let threads = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+    let chunk_size = embeddings.len().div_ceil(threads).max(1);
+    thread::scope(|scope| {
+        // Spawn all threads before joining any of them
+        #[allow(clippy::needless_collect, reason = "Threads must run concurrently")]
+        let handles: Vec<_> = embeddings
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|embedding| nearest(centroids, embedding))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().expect("Thread assigning clusters panicked"))
+            .collect()
+    })
+}
+
+/// Index of the centroid most similar to `embedding`. Assumes `centroids` is not empty.
+fn nearest(centroids: &[Embedding], embedding: &Embedding) -> usize {
+    centroids
+        .iter()
+        .map(|centroid| centroid.cosine_similarity(embedding))
+        .enumerate()
+        .min_by(|a, b| descending(a.1, b.1))
+        .map_or(0, |(index, _)| index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Embedding with `value` at `index` and `1.0` at the first position.
+    fn embedding(index: usize, value: f32) -> Embedding {
+        let mut raw = [0.0; 1024];
+        raw[0] = 1.0;
+        raw[index] = value;
+        raw.into()
+    }
+
+    #[test]
+    fn exhaustive_probes() {
+        let records: Vec<_> = (1..=100)
+            .map(|i| (format!("file_{i}"), embedding(i % 10 + 1, i as f32 / 10.0)))
+            .collect();
+        let query = embedding(3, 2.0);
+        let exact = {
+            let mut exact: Vec<_> = records
+                .iter()
+                .map(|(path, other)| (path.clone(), query.cosine_similarity(other)))
+                .collect();
+            exact.sort_by(|a, b| descending(a.1, b.1));
+            exact.truncate(5);
+            exact
+        };
+
+        // Probing every cluster is an exact search
+        let index = AnnIndex::build(records, usize::MAX);
+        assert_eq!(index.num_clusters(), 10);
+        assert_eq!(index.search(5, &query, |_| true), exact);
+        let filtered = index.search(5, &query, |path| path != exact[0].0);
+        assert_eq!(filtered[..4], exact[1..]);
+    }
+
+    #[test]
+    fn insert_remove() {
+        let mut index = AnnIndex::build(Vec::new(), 1);
+        assert_eq!(index.num_clusters(), 0);
+        let query = embedding(1, 1.0);
+        assert!(index.search(1, &query, |_| true).is_empty());
+
+        index.insert("file".to_owned(), query.clone());
+        index.insert("file".to_owned(), query.clone());
+        let results = index.search(2, &query, |_| true);
+        assert_eq!(results.len(), 1);
+        assert!((results[0].1 - 1.0).abs() < 1e-6);
+
+        index.remove("file");
+        assert!(index.search(1, &query, |_| true).is_empty());
+    }
+}
@@ -2,12 +2,18 @@
 //!
 //! This module contains logic for the Silicon Flow API.
 
-use std::fmt::Display;
+use std::{fmt::Display, sync::OnceLock};
 
-use super::{SenseError, embedding::EmbeddingBytes};
-use base64::{Engine as _, engine::general_purpose::STANDARD as DECODER};
+use super::{
+    SenseError,
+    embedding::{DIMENSION, Embedding, EmbeddingBytes},
+};
+use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
 use doc_for::{DocDyn, doc_impl};
-use reqwest::{Client, ClientBuilder, Url, header::HeaderMap};
+use reqwest::{
+    Client, ClientBuilder, Proxy, Url,
+    header::{AUTHORIZATION, HeaderValue},
+};
 use serde::{Deserialize, Serialize};
 
 // == API key validation and model definitions ==
@@ -19,9 +25,10 @@ use serde::{Deserialize, Serialize};
     doc_dyn = true,
     gen_attr = "serde(rename = {doc})"
 )]
-#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
 pub enum Model {
     /// BAAI/bge-large-zh-v1.5
+    #[default]
     BgeLargeZhV1_5,
     /// BAAI/bge-large-en-v1.5
     BgeLargeEnV1_5,
@@ -31,22 +38,72 @@ pub enum Model {
     BgeM3,
     /// Pro/BAAI/bge-m3
     ProBgeM3,
+    /// text-embedding-3-small
+    TextEmbedding3Small,
+    /// text-embedding-3-large
+    TextEmbedding3Large,
+    /// Any other model name accepted by the backend
+    #[serde(untagged)]
+    Custom(String),
 }
 
-impl Default for Model {
-    fn default() -> Self {
-        Self::BgeLargeZhV1_5
+impl Model {
+    /// Number of dimensions of the embeddings returned by the model. Custom models are assumed to return 1024-dimensional embeddings, the only size [`Embedding`] supports for now.
+    #[must_use]
+    pub const fn dimension(&self) -> usize {
+        match self {
+            Self::BceEmbeddingBaseV1 => 768,
+            // `text-embedding-3-*` models are shortened on request
+            Self::BgeLargeZhV1_5
+            | Self::BgeLargeEnV1_5
+            | Self::BgeM3
+            | Self::ProBgeM3
+            | Self::TextEmbedding3Small
+            | Self::TextEmbedding3Large
+            | Self::Custom(_) => DIMENSION,
+        }
+    }
+
+    /// Number of dimensions to request, for models that can shorten their embeddings to 1024 dimensions.
+    const fn requested_dimensions(&self) -> Option<u32> {
+        match self {
+            Self::TextEmbedding3Small | Self::TextEmbedding3Large => Some(1024),
+            _ => None,
+        }
     }
 }
 
 impl Display for Model {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.doc_dyn().unwrap())
+        match self {
+            Self::Custom(name) => write!(f, "{name}"),
+            _ => write!(f, "{}", self.doc_dyn().unwrap()),
+        }
     }
 }
 
+/// Base URL of the Silicon Flow API, used unless another OpenAI-compatible backend is given.
+pub const DEFAULT_BASE_URL: &str = "https://api.siliconflow.cn/v1";
+
+/// Whether the endpoint is that of Silicon Flow, whose keys have a known shape.
+fn is_silicon_flow(endpoint: &Url) -> bool {
+    endpoint.host_str().is_some_and(|host| {
+        ["siliconflow.cn", "siliconflow.com"]
+            .iter()
+            .any(|domain| host == *domain || host.ends_with(&format!(".{domain}")))
+    })
+}
+
 /// Validate that the API key is well-formed.
-fn validate_api_key(key: &str) -> Result<(), SenseError> {
+///
+/// The key is sent in the `Authorization` header, so it must be non-empty and consist of visible ASCII characters only. Besides, if `silicon_flow` is set, it must look like a Silicon Flow key: 51 characters long, alphanumeric after the 3-character prefix. Keys of other backends have other shapes.
+fn validate_api_key(key: &str, silicon_flow: bool) -> Result<(), SenseError> {
+    if key.is_empty() || !key.chars().all(|c| c.is_ascii_graphic()) {
+        return Err(SenseError::MalformedApiKey);
+    }
+    if !silicon_flow {
+        return Ok(());
+    }
     if key.len() != 51 {
         return Err(SenseError::MalformedApiKey);
     }
@@ -69,6 +126,9 @@ struct RequestBody<'a> {
     input: &'a str,
     /// The encoding format, either "float" or "base64".
     encoding_format: &'a str,
+    /// The number of dimensions of the embedding, only sent for models that support it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dimensions: Option<u32>,
 }
 
 /// ResponseBody.data: The list of embeddings generated by the model.
@@ -77,22 +137,53 @@ struct Data {
     /// Fixed string "embedding".
     #[serde(rename = "object")]
     _object: String,
-    /// Base64-encoded embedding.
-    embedding: String,
+    /// The embedding, in the requested encoding format or not.
+    embedding: EmbeddingData,
     /// Unused.
     #[serde(rename = "index")]
     _index: i32,
 }
 
+/// Data.embedding: An embedding in either encoding format. Base64 is requested as it is more compact, but some OpenAI-compatible backends ignore `encoding_format` and always return floats.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum EmbeddingData {
+    /// Base64-encoded bytes (little-endian), for `encoding_format: "base64"`.
+    Base64(String),
+    /// Array of floats, for `encoding_format: "float"`.
+    Float(Vec<f32>),
+}
+
+impl EmbeddingData {
+    /// Decode the embedding into floats, whatever its number of dimensions.
+    fn into_floats(self) -> Result<Vec<f32>, SenseError> {
+        match self {
+            Self::Base64(encoded) => {
+                let bytes = BASE64.decode(encoded)?;
+                if bytes.len() % 4 != 0 {
+                    return Err(SenseError::MalformedResponse {
+                        message: format!("{} bytes of embedding, not whole floats", bytes.len()),
+                    });
+                }
+                Ok(bytes
+                    .chunks_exact(4)
+                    .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+                    .collect())
+            }
+            Self::Float(floats) => Ok(floats),
+        }
+    }
+}
+
 /// ResponseBody.usage: The usage information for the request.
 #[derive(Deserialize)]
-#[allow(dead_code, reason = "For deserialization only")]
 #[allow(clippy::struct_field_names, reason = "Consistency with API response")]
 struct Usage {
     /// The number of tokens used by the prompt.
     prompt_tokens: u32,
     /// The number of tokens used by the completion.
-    completion_tokens: u32,
+    #[serde(rename = "completion_tokens", default)]
+    _completion_tokens: u32,
     /// The total number of tokens used by the request.
     total_tokens: u32,
 }
@@ -100,48 +191,166 @@ struct Usage {
 /// The response body for the Silicon Flow API.
 #[derive(Deserialize)]
 struct ResponseBody {
+    /// The id of the request, if provided.
+    #[serde(default)]
+    id: Option<String>,
     /// The name of the model used to generate the embedding.
     model: String,
     /// The list of embeddings generated by the model.
     data: Vec<Data>,
     /// The usage information for the request.
-    #[serde(rename = "usage")]
-    _usage: Usage,
+    usage: Usage,
+}
+
+/// Error response body, either flat (`{"message": ...}`, used by Silicon Flow) or nested (`{"error": {"message": ...}}`).
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ErrorBody {
+    /// Flat style.
+    Flat {
+        /// The error message.
+        message: String,
+    },
+    /// Nested style.
+    Nested {
+        /// The error object.
+        error: ErrorDetail,
+    },
+}
+
+/// ErrorBody.error: The error object in nested style.
+#[derive(Deserialize)]
+struct ErrorDetail {
+    /// The error message.
+    message: String,
+}
+
+impl ErrorBody {
+    /// Extract the error message from a response body, falling back to the raw body.
+    fn message(body: String) -> String {
+        match serde_json::from_str(&body) {
+            Ok(
+                Self::Flat { message }
+                | Self::Nested {
+                    error: ErrorDetail { message },
+                },
+            ) => message,
+            Err(_) => body,
+        }
+    }
 }
 
 // == API client ==
 
-/// A client for the Silicon Flow API.
+/// Metadata of an embedding request, as reported by the provider.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct EmbedMeta {
+    /// The id of the request, taken from the response body or the `x-request-id` header, if any.
+    pub id: Option<String>,
+    /// The number of tokens in the input text.
+    pub prompt_tokens: u32,
+    /// The total number of tokens billed for the request.
+    pub total_tokens: u32,
+    /// The name of the model reported by the provider, which may differ from the configured one, e.g. an alias or a versioned name.
+    pub model: String,
+}
+
+/// HTTP client shared by all API clients without an explicit proxy, so that connection pooling and TLS setup aren't repeated.
+static SHARED_CLIENT: OnceLock<Client> = OnceLock::new();
+
+/// Get the shared HTTP client, building it on first use.
+fn shared_client() -> Result<Client, SenseError> {
+    if let Some(client) = SHARED_CLIENT.get() {
+        return Ok(client.clone());
+    }
+    let client = ClientBuilder::new().build()?;
+    // Another thread may have won the race, in which case its client is used
+    Ok(SHARED_CLIENT.get_or_init(|| client).clone())
+}
+
+/// A client for the Silicon Flow API, or any OpenAI-compatible embedding API.
 #[derive(Clone)]
 pub struct ApiClient {
     /// The model to use.
     model: String,
+    /// The number of dimensions to request, if any.
+    dimensions: Option<u32>,
+    /// The number of dimensions of the embeddings returned by the model.
+    dimension: usize,
     /// API endpoint.
     endpoint: Url,
-    /// HTTP client.
+    /// Value of the `Authorization` header.
+    authorization: HeaderValue,
+    /// HTTP client, shared unless a proxy is given.
     client: Client,
 }
 
 impl ApiClient {
-    /// Create a new API client.
+    /// Create a new API client. Proxies are read from the `HTTP_PROXY`, `HTTPS_PROXY`, `ALL_PROXY` and `NO_PROXY` environment variables.
+    ///
+    /// The underlying HTTP client and its connection pool are shared by all API clients created without an explicit proxy.
     ///
     /// # Errors
     ///
     /// Returns an error if the API key is malformed or the HTTP client cannot be created.
-    #[allow(clippy::missing_panics_doc, reason = "URL is hardcoded")]
-    pub fn new(key: &str, model: Model) -> Result<Self, SenseError> {
-        validate_api_key(key)?;
-        let mut headers = HeaderMap::new();
-        headers.insert("Authorization", format!("Bearer {key}").parse()?);
-        let client = ClientBuilder::new().default_headers(headers).build()?;
+    pub fn new(key: &str, model: &Model) -> Result<Self, SenseError> {
+        Self::with_proxy(key, model, None)
+    }
+
+    /// Create a new API client, sending all requests through `proxy` if given. Otherwise, proxies are read from the environment, like [`ApiClient::new`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the API key is malformed, the proxy URL is invalid or the HTTP client cannot be created.
+    pub fn with_proxy(key: &str, model: &Model, proxy: Option<&str>) -> Result<Self, SenseError> {
+        Self::with_base_url(key, model, proxy, DEFAULT_BASE_URL)
+    }
+
+    /// Create a new API client for the OpenAI-compatible API at `base_url`, like `https://api.openai.com/v1`, to which `/embeddings` is appended. Requests go through `proxy` if given, like [`ApiClient::with_proxy`].
+    ///
+    /// The API key is only checked to look like a Silicon Flow key if `base_url` is that of Silicon Flow.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the base URL is invalid, the API key is malformed, the proxy URL is invalid or the HTTP client cannot be created.
+    pub fn with_base_url(
+        key: &str,
+        model: &Model,
+        proxy: Option<&str>,
+        base_url: &str,
+    ) -> Result<Self, SenseError> {
+        let endpoint = Url::parse(&format!("{}/embeddings", base_url.trim_end_matches('/')))
+            .ok()
+            .filter(|url| matches!(url.scheme(), "http" | "https"))
+            .ok_or_else(|| SenseError::InvalidBaseUrl {
+                url: base_url.to_string(),
+            })?;
+        validate_api_key(key, is_silicon_flow(&endpoint))?;
+        let mut authorization: HeaderValue = format!("Bearer {key}").parse()?;
+        authorization.set_sensitive(true);
+        let client = if let Some(proxy) = proxy {
+            // An explicit proxy disables the environment variables, so it needs its own client
+            ClientBuilder::new().proxy(Proxy::all(proxy)?).build()?
+        } else {
+            shared_client()?
+        };
 
         Ok(Self {
             model: model.to_string(),
-            endpoint: Url::parse("https://api.siliconflow.cn/v1/embeddings").unwrap(),
+            dimensions: model.requested_dimensions(),
+            dimension: model.dimension(),
+            endpoint,
+            authorization,
             client,
         })
     }
 
+    /// Number of dimensions of the embeddings returned, that of the [model](Model::dimension). Embeddings of another dimension are rejected.
+    #[must_use]
+    pub const fn dimension(&self) -> usize {
+        self.dimension
+    }
+
     /// Embed a text.
     ///
     /// # Errors
@@ -149,21 +358,74 @@ impl ApiClient {
     /// Returns:
     ///
     /// - [`SenseError::RequestFailed`] if the request fails
+    /// - [`SenseError::ApiError`] if the API responds with an error status
+    /// - [`SenseError::MalformedResponse`] if the response isn't shaped like an embedding response
     /// - [`SenseError::Base64DecodingFailed`] if base64 decoding fails
-    /// - [`SenseError::DimensionMismatch`] if the embedding is not 1024-dimensional.
+    /// - [`SenseError::DimensionMismatch`] if the embedding doesn't have the [dimension](Model::dimension) of the model, e.g. as the backend swapped models, or isn't 1024-dimensional.
     pub async fn embed(&self, text: &str) -> Result<EmbeddingBytes, SenseError> {
+        let (embedding, _) = self.request(text).await?;
+        Ok(embedding.into())
+    }
+
+    /// Embed a text, also returning the usage and request id reported by the provider.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`ApiClient::embed`].
+    pub async fn embed_with_meta(&self, text: &str) -> Result<(Embedding, EmbedMeta), SenseError> {
+        self.request(text).await
+    }
+
+    /// Send an embedding request, returning the embedding and its metadata. Embeddings are accepted in either encoding format, whichever the backend returns.
+    async fn request(&self, text: &str) -> Result<(Embedding, EmbedMeta), SenseError> {
         let request_body = RequestBody {
             model: &self.model,
             input: text,
             encoding_format: "base64",
+            dimensions: self.dimensions,
         };
-        let request = self.client.post(self.endpoint.clone()).json(&request_body);
+        let request = self
+            .client
+            .post(self.endpoint.clone())
+            .header(AUTHORIZATION, self.authorization.clone())
+            .json(&request_body);
 
-        let response: ResponseBody = request.send().await?.json().await?;
-        debug_assert_eq!(response.model, self.model);
+        let response = request.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            let message = ErrorBody::message(response.text().await?);
+            return Err(SenseError::ApiError { status, message });
+        }
 
-        let embedding = DECODER.decode(response.data[0].embedding.as_bytes())?;
-        Ok(embedding.try_into()?)
+        let header_id = response
+            .headers()
+            .get("x-request-id")
+            .and_then(|value| value.to_str().ok())
+            .map(ToString::to_string);
+        let body = response.text().await?;
+        let response: ResponseBody =
+            serde_json::from_str(&body).map_err(|e| SenseError::MalformedResponse {
+                message: e.to_string(),
+            })?;
+        let Some(data) = response.data.into_iter().next() else {
+            return Err(SenseError::MalformedResponse {
+                message: "no embedding in `data`".to_string(),
+            });
+        };
+        let meta = EmbedMeta {
+            id: response.id.or(header_id),
+            prompt_tokens: response.usage.prompt_tokens,
+            total_tokens: response.usage.total_tokens,
+            model: response.model,
+        };
+        let floats = data.embedding.into_floats()?;
+        if floats.len() != self.dimension {
+            return Err(SenseError::DimensionMismatch {
+                expected: self.dimension,
+                actual: floats.len(),
+            });
+        }
+        Ok((floats.try_into()?, meta))
     }
 }
 
@@ -175,20 +437,214 @@ mod tests {
 
     #[test]
     fn test_api_key_ok() {
-        validate_api_key(KEY).unwrap();
+        validate_api_key(KEY, true).unwrap();
     }
 
     #[test]
     fn test_api_key_malformed() {
         let malformed = &KEY[..KEY.len() - 1];
-        let err = validate_api_key(malformed).unwrap_err();
+        let err = validate_api_key(malformed, true).unwrap_err();
+        assert!(matches!(err, SenseError::MalformedApiKey));
+    }
+
+    #[test]
+    fn test_base_url() {
+        let openai_key = "sk-proj-abc_DEF-123";
+        let model = Model::TextEmbedding3Small;
+        let client =
+            ApiClient::with_base_url(openai_key, &model, None, "https://api.openai.com/v1/")
+                .unwrap();
+        assert_eq!(
+            client.endpoint.as_str(),
+            "https://api.openai.com/v1/embeddings"
+        );
+        // Only Silicon Flow keys are checked for their shape
+        let err = ApiClient::new(openai_key, &model).err().unwrap();
+        assert!(matches!(err, SenseError::MalformedApiKey));
+        let err = ApiClient::with_base_url(openai_key, &model, None, "https://siliconflow.com/v1")
+            .err()
+            .unwrap();
+        assert!(matches!(err, SenseError::MalformedApiKey));
+
+        for url in ["api.openai.com/v1", "ftp://example.com"] {
+            let err = ApiClient::with_base_url(KEY, &model, None, url)
+                .err()
+                .unwrap();
+            assert!(matches!(err, SenseError::InvalidBaseUrl { .. }));
+        }
+    }
+
+    #[test]
+    fn test_api_key_empty() {
+        let err = ApiClient::new("", &Model::default()).err().unwrap();
+        assert!(matches!(err, SenseError::MalformedApiKey));
+    }
+
+    #[test]
+    fn test_api_key_newline() {
+        // Would be an invalid header value, rejected before any request is made
+        let key = format!("{}\n", &KEY[..KEY.len() - 1]);
+        let err = ApiClient::new(&key, &Model::default()).err().unwrap();
         assert!(matches!(err, SenseError::MalformedApiKey));
     }
 
+    #[test]
+    fn test_proxy() {
+        ApiClient::with_proxy(KEY, &Model::default(), Some("http://127.0.0.1:7890")).unwrap();
+        let err = ApiClient::with_proxy(KEY, &Model::default(), Some("not a url"))
+            .err()
+            .unwrap();
+        assert!(matches!(err, SenseError::RequestFailed { .. }));
+    }
+
     #[test]
     fn test_model_string() {
         let model = Model::BgeLargeZhV1_5;
         assert_eq!(model.to_string(), "BAAI/bge-large-zh-v1.5");
+        let model = Model::Custom("my/model".to_string());
+        assert_eq!(model.to_string(), "my/model");
+    }
+
+    #[test]
+    fn test_model_dimension() {
+        assert_eq!(Model::BgeLargeZhV1_5.dimension(), 1024);
+        assert_eq!(Model::BceEmbeddingBaseV1.dimension(), 768);
+        assert_eq!(Model::TextEmbedding3Large.dimension(), 1024);
+        assert_eq!(Model::Custom("my/model".to_string()).dimension(), 1024);
+    }
+
+    #[test]
+    fn test_model_serde() {
+        let models = [
+            (Model::BgeM3, "\"BAAI/bge-m3\""),
+            (Model::TextEmbedding3Small, "\"text-embedding-3-small\""),
+            (Model::Custom("my/model".to_string()), "\"my/model\""),
+        ];
+        for (model, json) in models {
+            assert_eq!(serde_json::to_string(&model).unwrap(), json);
+            assert_eq!(serde_json::from_str::<Model>(json).unwrap(), model);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_api_error() {
+        use wiremock::{Mock, MockServer, ResponseTemplate, matchers::method};
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(
+                ResponseTemplate::new(400).set_body_string(
+                    r#"{"code":20012,"message":"Model does not exist.","data":null}"#,
+                ),
+            )
+            .mount(&server)
+            .await;
+        let client = ApiClient::with_base_url(KEY, &Model::default(), None, &server.uri()).unwrap();
+
+        let err = client.embed("Hello, world!").await.unwrap_err();
+        let SenseError::ApiError { status, message } = err else {
+            panic!("Unexpected error: {err}");
+        };
+        assert_eq!(status, 400);
+        assert_eq!(message, "Model does not exist.");
+    }
+
+    /// Mock server responding to every request with `body`, and a client using it. The server stops when dropped.
+    async fn mock_client(body: String) -> (wiremock::MockServer, ApiClient) {
+        use wiremock::{
+            Mock, MockServer, ResponseTemplate,
+            matchers::{header, method},
+        };
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(header("authorization", format!("Bearer {KEY}")))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(body, "application/json"))
+            .mount(&server)
+            .await;
+        let client = ApiClient::with_base_url(KEY, &Model::default(), None, &server.uri()).unwrap();
+        assert_eq!(client.dimension(), 1024);
+
+        (server, client)
+    }
+
+    /// Response body like those of Silicon Flow, with the given JSON as the embedding.
+    fn response_body(embedding: &str) -> String {
+        format!(
+            r#"{{"id":"req-1","object":"list","model":"BAAI/bge-large-zh-v1.5","data":[{{"object":"embedding","embedding":{embedding},"index":0}}],"usage":{{"prompt_tokens":4,"completion_tokens":0,"total_tokens":4}}}}"#
+        )
+    }
+
+    #[tokio::test]
+    async fn test_embed_with_meta() {
+        let embedding = Embedding::default().to_base64();
+        let (_server, client) = mock_client(response_body(&format!(r#""{embedding}""#))).await;
+
+        let (embedding, meta) = client.embed_with_meta("Hello, world!").await.unwrap();
+        assert_eq!(embedding, Embedding::default());
+        assert_eq!(meta.id.as_deref(), Some("req-1"));
+        assert_eq!(meta.prompt_tokens, 4);
+        assert_eq!(meta.total_tokens, 4);
+        assert_eq!(meta.model, "BAAI/bge-large-zh-v1.5");
+    }
+
+    #[tokio::test]
+    async fn test_embed_other_model() {
+        // E.g. a versioned name reported by an OpenAI-compatible backend
+        let embedding = Embedding::default().to_base64();
+        let body = response_body(&format!(r#""{embedding}""#))
+            .replace("BAAI/bge-large-zh-v1.5", "bge-large-zh-v1.5-2024");
+        let (_server, client) = mock_client(body).await;
+
+        let (_, meta) = client.embed_with_meta("Hello, world!").await.unwrap();
+        assert_eq!(meta.model, "bge-large-zh-v1.5-2024");
+    }
+
+    #[tokio::test]
+    async fn test_embed_float() {
+        // Returned by backends ignoring `encoding_format`
+        let floats = serde_json::to_string(&vec![0.5_f32; 1024]).unwrap();
+        let (_server, client) = mock_client(response_body(&floats)).await;
+
+        let embedding: Embedding = client.embed("Hello, world!").await.unwrap().into();
+        assert_eq!(embedding, Embedding::from([0.5; 1024]));
+    }
+
+    #[tokio::test]
+    async fn test_embed_malformed() {
+        let (_server, client) = mock_client(response_body("[0.5, 0.5]")).await;
+        let err = client.embed("Hello, world!").await.unwrap_err();
+        assert!(matches!(
+            err,
+            SenseError::DimensionMismatch {
+                expected: 1024,
+                actual: 2
+            }
+        ));
+        assert_eq!(
+            err.to_string(),
+            "Embedding must be 1024-dimensional, got 2 dimension(s)."
+        );
+
+        let (_server, client) = mock_client(response_body(r#"{"values": []}"#)).await;
+        let err = client.embed("Hello, world!").await.unwrap_err();
+        assert!(matches!(err, SenseError::MalformedResponse { .. }));
+
+        let body = r#"{"model":"BAAI/bge-large-zh-v1.5","data":[],"usage":{"prompt_tokens":0,"total_tokens":0}}"#;
+        let (_server, client) = mock_client(body.to_string()).await;
+        let err = client.embed("Hello, world!").await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Malformed API response: no embedding in `data`"
+        );
+    }
+
+    #[test]
+    fn test_error_body() {
+        let nested = r#"{"error":{"message":"Insufficient quota","type":"insufficient_quota"}}"#;
+        assert_eq!(ErrorBody::message(nested.to_string()), "Insufficient quota");
+        let raw = "Bad Gateway";
+        assert_eq!(ErrorBody::message(raw.to_string()), raw);
     }
 
     #[tokio::test]
@@ -196,7 +652,7 @@ mod tests {
     async fn test_embed() {
         // Read the API key from the environment
         let key = std::env::var("SILICONFLOW_API_KEY").unwrap();
-        let client = ApiClient::new(&key, Model::BgeLargeZhV1_5).unwrap();
+        let client = ApiClient::new(&key, &Model::BgeLargeZhV1_5).unwrap();
         let embedding = client.embed("Hello, world!").await;
         let _ = embedding.unwrap();
     }
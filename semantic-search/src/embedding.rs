@@ -15,42 +15,83 @@
 //! ## Conversion
 //!
 //! - [`Embedding`] can be converted from [`EmbeddingRaw`] and [`EmbeddingBytes`].
-//! - [`Embedding`] can be immutably dereferenced to [`EmbeddingRaw`] and converted to [`EmbeddingBytes`].
+//! - [`Embedding`] can be viewed as a slice with [`as_slice`](Embedding::as_slice), iterated with [`iter`](Embedding::iter) or by reference, or turned into [`EmbeddingRaw`] or `Vec<f32>` with [`into_raw`](Embedding::into_raw) and [`into_vec`](Embedding::into_vec). It can also be immutably dereferenced to [`EmbeddingRaw`], kept for backward compatibility.
+//! - [`Embedding`] can be converted to [`EmbeddingBytes`].
 //! - [`Embedding`] can be converted from `&[f32]`, `&[u8]`, `Vec<f32>` and `Vec<u8>`, but [`DimensionMismatch`](SenseError::DimensionMismatch) error is returned if the length mismatches.
+//! - [`Embedding`] can be encoded to and decoded from base64 of its [`EmbeddingBytes`], using [`to_base64`](Embedding::to_base64) and [`from_base64`](Embedding::from_base64).
 //!
 //! ## Calculation
 //!
-//! Cosine similarity between two embeddings can be calculated using [`cosine_similarity`](Embedding::cosine_similarity) method.
+//! Cosine similarity between two embeddings can be calculated using [`cosine_similarity`](Embedding::cosine_similarity) method. The centroid of several embeddings can be calculated using [`mean`](Embedding::mean). The [default](Embedding::default) embedding is the zero vector, whose cosine similarity with any embedding is `0.0`.
+//!
+//! ## Serialization
+//!
+//! With the `serde` feature enabled, [`Embedding`] implements `Serialize` and `Deserialize`, represented as an array of 1024 `f32`s. Deserializing an array of any other length fails.
 
 use super::SenseError;
-use std::{convert::TryFrom, ops::Deref};
+use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+use std::{convert::TryFrom, fmt, ops::Deref};
+
+/// Number of dimensions of an embedding.
+pub const DIMENSION: usize = 1024;
 
 /// Raw embedding representation.
-pub type EmbeddingRaw = [f32; 1024];
+pub type EmbeddingRaw = [f32; DIMENSION];
 
 /// Embedding represented in bytes (little-endian).
-pub type EmbeddingBytes = [u8; 1024 * 4];
+pub type EmbeddingBytes = [u8; DIMENSION * 4];
 
 /// Wrapped embedding representation.
 ///
+/// Two embeddings are equal if their values are equal. The [`Debug`] output only shows the dimension, the first few values and the norm, instead of all 1024 values.
+///
 /// See [module-level documentation](crate::embedding) for more details.
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Clone, PartialEq)]
 pub struct Embedding {
     inner: EmbeddingRaw,
     norm: f32,
 }
 
-// Cosine similarity calculation
+// Calculation
 
 impl Embedding {
     /// Calculate cosine similarity between two embeddings.
+    ///
+    /// Returns `0.0` if either embedding is the zero vector, for which cosine similarity is undefined. The result is clamped to `[-1.0, 1.0]`, as rounding errors may push it slightly out of range for (anti-)parallel embeddings.
     #[must_use]
     pub fn cosine_similarity(&self, other: &Self) -> f32 {
+        let norms = self.norm * other.norm;
+        if norms == 0.0 {
+            return 0.0;
+        }
         let dot_product: f32 = self.iter().zip(other.iter()).map(|(a, b)| a * b).sum();
-        dot_product / (self.norm * other.norm)
+        (dot_product / norms).clamp(-1.0, 1.0)
+    }
+
+    /// Calculate the component-wise mean of embeddings, e.g. the centroid of a cluster or the average of several queries.
+    ///
+    /// Returns `None` if `embeddings` is empty.
+    #[must_use]
+    pub fn mean(embeddings: &[Self]) -> Option<Self> {
+        if embeddings.is_empty() {
+            return None;
+        }
+        let mut sum = [0.0; 1024];
+        for embedding in embeddings {
+            for (sum, x) in sum.iter_mut().zip(embedding.iter()) {
+                *sum += x;
+            }
+        }
+        #[allow(clippy::cast_precision_loss, reason = "Number of embeddings is small")]
+        let count = embeddings.len() as f32;
+
+        Some(sum.map(|sum| sum / count).into())
     }
 }
 
+/// The zero embedding, with all values and the norm being `0.0`.
+///
+/// Cosine similarity involving the zero vector is undefined, so [`cosine_similarity`](Embedding::cosine_similarity) returns `0.0` for it instead of NaN. It is useful as a placeholder, but ranks below any embedding with a positive similarity.
 impl Default for Embedding {
     fn default() -> Self {
         Self {
@@ -62,6 +103,41 @@ impl Default for Embedding {
 
 // Convertion
 
+impl Embedding {
+    /// View the values as a slice.
+    #[must_use]
+    pub const fn as_slice(&self) -> &[f32] {
+        &self.inner
+    }
+
+    /// Iterate over the values.
+    pub fn iter(&self) -> std::slice::Iter<'_, f32> {
+        self.inner.iter()
+    }
+
+    /// Take the values as [`EmbeddingRaw`], dropping the cached norm.
+    #[must_use]
+    pub const fn into_raw(self) -> EmbeddingRaw {
+        self.inner
+    }
+
+    /// Take the values as a `Vec<f32>`, e.g. to hand them to APIs expecting one.
+    #[must_use]
+    pub fn into_vec(self) -> Vec<f32> {
+        self.inner.to_vec()
+    }
+}
+
+impl<'a> IntoIterator for &'a Embedding {
+    type Item = &'a f32;
+    type IntoIter = std::slice::Iter<'a, f32>;
+
+    /// Iterate over the values, same as [`iter`](Embedding::iter).
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
 impl From<EmbeddingRaw> for Embedding {
     /// Convert `[f32; 1024]` to `Embedding`.
     fn from(inner: EmbeddingRaw) -> Self {
@@ -97,6 +173,25 @@ impl From<Embedding> for EmbeddingBytes {
     }
 }
 
+impl Embedding {
+    /// Decode an embedding from base64-encoded bytes (little-endian), like those returned by the API.
+    ///
+    /// # Errors
+    ///
+    /// - [`SenseError::Base64DecodingFailed`] if `s` is not valid base64
+    /// - [`SenseError::DimensionMismatch`] if the decoded bytes are not 1024 * 4 bytes long
+    pub fn from_base64(s: &str) -> Result<Self, SenseError> {
+        BASE64.decode(s)?.try_into()
+    }
+
+    /// Encode the embedding as base64 of its bytes (little-endian), the inverse of [`from_base64`](Self::from_base64).
+    #[must_use]
+    pub fn to_base64(&self) -> String {
+        let bytes: EmbeddingBytes = self.clone().into();
+        BASE64.encode(bytes)
+    }
+}
+
 impl TryFrom<&[f32]> for Embedding {
     type Error = SenseError;
 
@@ -106,7 +201,13 @@ impl TryFrom<&[f32]> for Embedding {
     ///
     /// Returns [`DimensionMismatch`](SenseError::DimensionMismatch) if the length of the input slice is not 1024.
     fn try_from(value: &[f32]) -> Result<Self, Self::Error> {
-        let embedding: EmbeddingRaw = value.try_into()?;
+        let embedding: EmbeddingRaw =
+            value
+                .try_into()
+                .map_err(|_| SenseError::DimensionMismatch {
+                    expected: DIMENSION,
+                    actual: value.len(),
+                })?;
         Ok(Self::from(embedding))
     }
 }
@@ -120,7 +221,13 @@ impl TryFrom<&[u8]> for Embedding {
     ///
     /// Returns [`DimensionMismatch`](SenseError::DimensionMismatch) if the length of the input slice is not 1024 * 4.
     fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
-        let bytes: EmbeddingBytes = value.try_into()?;
+        let bytes: EmbeddingBytes =
+            value
+                .try_into()
+                .map_err(|_| SenseError::DimensionMismatch {
+                    expected: DIMENSION,
+                    actual: value.len() / 4,
+                })?;
         Ok(Self::from(bytes))
     }
 }
@@ -153,7 +260,7 @@ impl TryFrom<Vec<u8>> for Embedding {
     }
 }
 
-// Implement `Deref` for `Embedding`
+// Implement `Deref` for `Embedding`, kept for backward compatibility. Prefer `as_slice` or `into_raw`
 
 impl Deref for Embedding {
     type Target = EmbeddingRaw;
@@ -165,6 +272,42 @@ impl Deref for Embedding {
 
 // Should not mutate the inner representation, since `norm` is cached based on it
 
+// Debug output with a preview of the values
+
+impl fmt::Debug for Embedding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        /// Number of leading values shown.
+        const PREVIEW_LEN: usize = 4;
+
+        f.debug_struct("Embedding")
+            .field("dimension", &self.inner.len())
+            .field("preview", &&self.inner[..PREVIEW_LEN])
+            .field("norm", &self.norm)
+            .finish_non_exhaustive()
+    }
+}
+
+// Serialization as an array of `f32`
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Embedding {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Embedding {
+    /// Deserialize from an array of 1024 `f32`s, recomputing the norm.
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+
+        let value = Vec::<f32>::deserialize(deserializer)?;
+        let len = value.len();
+        Self::try_from(value).map_err(|_| D::Error::invalid_length(len, &"1024 elements"))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -172,6 +315,15 @@ mod tests {
     const EMBEDDING_FLOAT: f32 = 1.14; // 0x3F91EB85
     const EMBEDDING_CHUNK: [u8; 4] = [0x85, 0xEB, 0x91, 0x3F];
 
+    #[test]
+    fn embedding_debug() {
+        let embedding = Embedding::from([1.0; 1024]);
+        assert_eq!(
+            format!("{embedding:?}"),
+            "Embedding { dimension: 1024, preview: [1.0, 1.0, 1.0, 1.0], norm: 32.0, .. }"
+        );
+    }
+
     #[test]
     #[allow(clippy::float_cmp, reason = "They should be equal exactly")]
     fn embedding_from_bytes() {
@@ -196,6 +348,38 @@ mod tests {
         });
     }
 
+    #[test]
+    fn base64_round_trip() {
+        let embedding = Embedding::from([EMBEDDING_FLOAT; 1024]);
+        let encoded = embedding.to_base64();
+        assert!(encoded.starts_with("heuRP4XrkT"));
+        assert_eq!(Embedding::from_base64(&encoded).unwrap(), embedding);
+
+        assert!(matches!(
+            Embedding::from_base64("not base64!"),
+            Err(SenseError::Base64DecodingFailed)
+        ));
+        assert!(matches!(
+            Embedding::from_base64("heuRPw=="),
+            Err(SenseError::DimensionMismatch {
+                expected: 1024,
+                actual: 1
+            })
+        ));
+    }
+
+    #[test]
+    #[allow(clippy::cast_precision_loss, reason = "Indices are small")]
+    #[allow(clippy::float_cmp, reason = "They should be equal exactly")]
+    fn slice_and_raw() {
+        let raw: EmbeddingRaw = std::array::from_fn(|i| i as f32);
+        let embedding = Embedding::from(raw);
+        assert_eq!(embedding.as_slice(), &raw[..]);
+        assert!((&embedding).into_iter().eq(raw.iter()));
+        assert_eq!(embedding.clone().into_vec(), raw.to_vec());
+        assert_eq!(embedding.into_raw(), raw);
+    }
+
     #[test]
     fn similar_to_self() {
         let embedding = Embedding::from([EMBEDDING_FLOAT; 1024]);
@@ -204,4 +388,56 @@ mod tests {
         // Approximate equality
         assert!(delta <= f32::EPSILON);
     }
+
+    #[test]
+    #[allow(clippy::cast_precision_loss, reason = "Indices are small")]
+    fn similarity_in_range() {
+        for scale in [0.1, 0.3, 1.7, 3.0, 1e3] {
+            let raw: EmbeddingRaw = std::array::from_fn(|i| (i as f32 * 0.37).sin());
+            let a = Embedding::from(raw);
+            let b = Embedding::from(raw.map(|x| x * scale));
+            let c = Embedding::from(raw.map(|x| -x * scale));
+            for (x, y) in [(&a, &b), (&b, &a), (&a, &a), (&b, &b)] {
+                assert!(x.cosine_similarity(y) <= 1.0);
+            }
+            assert!(a.cosine_similarity(&c) >= -1.0);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let embedding = Embedding::from([EMBEDDING_FLOAT; 1024]);
+        let json = serde_json::to_string(&embedding).unwrap();
+        let deserialized: Embedding = serde_json::from_str(&json).unwrap();
+        assert_eq!(deserialized, embedding);
+
+        let err = serde_json::from_str::<Embedding>("[1.0, 2.0]").unwrap_err();
+        assert!(err.to_string().contains("invalid length 2"));
+    }
+
+    #[test]
+    fn mean() {
+        assert!(Embedding::mean(&[]).is_none());
+
+        let embedding = Embedding::from([EMBEDDING_FLOAT; 1024]);
+        let mean = Embedding::mean(&vec![embedding.clone(); 3]).unwrap();
+        assert_eq!(mean, embedding);
+
+        // Opposite embeddings cancel out
+        let opposite = Embedding::from([-EMBEDDING_FLOAT; 1024]);
+        let mean = Embedding::mean(&[embedding, opposite]).unwrap();
+        assert_eq!(mean, Embedding::default());
+    }
+
+    #[test]
+    #[allow(clippy::float_cmp, reason = "They should be equal exactly")]
+    fn zero_similarity() {
+        let zero = Embedding::from([0.0; 1024]);
+        let embedding = Embedding::from([EMBEDDING_FLOAT; 1024]);
+        assert_eq!(zero.cosine_similarity(&embedding), 0.0);
+        assert_eq!(embedding.cosine_similarity(&zero), 0.0);
+        assert_eq!(zero.cosine_similarity(&zero), 0.0);
+        assert_eq!(Embedding::default(), zero);
+    }
 }
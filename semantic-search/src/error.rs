@@ -4,27 +4,50 @@
 
 use base64::DecodeError;
 use doc_for::doc_impl;
-use reqwest::{Error as ReqwestError, header::InvalidHeaderValue};
-use std::array::TryFromSliceError;
+use reqwest::{Error as ReqwestError, StatusCode, header::InvalidHeaderValue};
 use thiserror::Error;
 
+use crate::embedding::DIMENSION;
+
 /// Possible errors.
 #[doc_impl(strip = 1, doc_for = false, gen_attr = "error({doc})")]
 #[derive(Debug, Error)]
 pub enum SenseError {
-    /// Embedding must be 1024-dimensional.
-    DimensionMismatch,
+    /// Embedding must be {expected}-dimensional, got {actual} dimension(s).
+    DimensionMismatch {
+        /// Number of dimensions expected.
+        expected: usize,
+        /// Number of dimensions got, counting whole 4-byte floats for bytes.
+        actual: usize,
+    },
     /// Malformed API key.
     MalformedApiKey,
+    /// Invalid base URL: {url}
+    InvalidBaseUrl {
+        /// The base URL given.
+        url: String,
+    },
     /// Request failed.
     RequestFailed {
         /// Source of the error.
         source: ReqwestError,
     },
+    /// API returned {status}: {message}
+    ApiError {
+        /// HTTP status code of the response.
+        status: StatusCode,
+        /// Error message from the API provider.
+        message: String,
+    },
     /// Invalid header value.
     InvalidHeaderValue,
     /// Base64 decoding failed.
     Base64DecodingFailed,
+    /// Malformed API response: {message}
+    MalformedResponse {
+        /// What is wrong with the response.
+        message: String,
+    },
 }
 
 impl From<ReqwestError> for SenseError {
@@ -34,24 +57,23 @@ impl From<ReqwestError> for SenseError {
     }
 }
 
-impl From<TryFromSliceError> for SenseError {
-    /// Error when casting slice to array (length mismatch).
-    fn from(_: TryFromSliceError) -> Self {
-        Self::DimensionMismatch
-    }
-}
-
 impl From<Vec<u8>> for SenseError {
     /// Error when casting `Vec<u8>` to array (length mismatch).
-    fn from(_: Vec<u8>) -> Self {
-        Self::DimensionMismatch
+    fn from(bytes: Vec<u8>) -> Self {
+        Self::DimensionMismatch {
+            expected: DIMENSION,
+            actual: bytes.len() / 4,
+        }
     }
 }
 
 impl From<Vec<f32>> for SenseError {
     /// Error when casting `Vec<f32>` to array (length mismatch).
-    fn from(_: Vec<f32>) -> Self {
-        Self::DimensionMismatch
+    fn from(floats: Vec<f32>) -> Self {
+        Self::DimensionMismatch {
+            expected: DIMENSION,
+            actual: floats.len(),
+        }
     }
 }
 
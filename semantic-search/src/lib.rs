@@ -12,6 +12,12 @@ mod api;
 pub mod embedding;
 mod error;
 
-pub use api::{ApiClient, Model};
+pub use api::{ApiClient, DEFAULT_BASE_URL, EmbedMeta, Model};
 pub use embedding::Embedding;
 pub use error::SenseError;
+
+/// Cargo features this library was compiled with.
+pub const FEATURES: &[&str] = &[
+    #[cfg(feature = "serde")]
+    "serde",
+];